@@ -1,32 +1,92 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::anyhow;
-use fxhash::FxHashMap as HashMap;
+use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
 use serde_json;
 use serde_json::json;
 use time::Duration;
 use zstd;
 
-use ppcalc_metric::{DestinationId, MessageId, SourceId, Trace, TraceBuilder};
+use ppcalc_metric::{
+    DestinationId, MessageId, RelayId, RelayObservations, SourceId, Trace, TraceBuilder,
+};
 
 use crate::cli::AnalyzeArgs;
 use crate::plot::deanonymized_users_over_time;
+use crate::provenance::{self, AnalysisMetadata, TraceMetadata};
+use crate::trace;
 
 pub fn run(args: AnalyzeArgs) -> anyhow::Result<()> {
+    let metadata = AnalysisMetadata {
+        tool: provenance::Tool::current(),
+        parameters: serde_json::to_value(&args)?,
+        input_sha256: provenance::hash_file(&args.input)?,
+        trace: TraceMetadata::read_for_trace(&args.input)?,
+    };
+
     // load trace
     let network_trace = TraceBuilder::from_csv(&args.input)
         .map_err(|e| anyhow!(e))?
         .build()?;
 
+    // load relay observations, if the adversary is placed at relays
+    let relay_observations = match args.relay_observations {
+        Some(ref path) => Some(RelayObservations::from_csv(path).map_err(|e| anyhow!(e))?),
+        None => None,
+    };
+    let adversary_relays: HashSet<RelayId> = args
+        .adversary_relays
+        .iter()
+        .map(|relay| RelayId::new(*relay))
+        .collect();
+
+    // load the class of each source, if known
+    let classes_path = match args.classes {
+        Some(ref path) => Some(path.clone()),
+        None => Some(trace::classes_path_for_trace(&args.input)).filter(|path| path.exists()),
+    };
+    let classes = match classes_path {
+        Some(path) => Some(
+            trace::read_classes(path)
+                .map_err(|e| anyhow!(e))?
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+        ),
+        None => None,
+    };
+
+    let min_window = Duration::milliseconds(args.min_window as i64);
+    let max_window = Duration::milliseconds(args.max_window as i64);
+
     if !args.sizes_only {
         let (source_relationship_anonymity_sets, _destination_relationship_anonymity_sets) =
-            ppcalc_metric::compute_relationship_anonymity(
-                &network_trace,
-                Duration::milliseconds(args.min_window as i64),
-                Duration::milliseconds(args.max_window as i64),
-            )
+            match relay_observations {
+                None if args.bidirectional => {
+                    ppcalc_metric::compute_bidirectional_relationship_anonymity(
+                        &network_trace,
+                        args.dummies,
+                        min_window,
+                        max_window,
+                    )
+                }
+                None => ppcalc_metric::compute_relationship_anonymity_with_dummies(
+                    &network_trace,
+                    args.dummies,
+                    min_window,
+                    max_window,
+                ),
+                Some(ref observations) => ppcalc_metric::compute_relay_relationship_anonymity(
+                    &network_trace,
+                    observations,
+                    &adversary_relays,
+                    args.dummies,
+                    min_window,
+                    max_window,
+                ),
+            }
             .map_err(|e| anyhow!(e))?;
 
         if let Some(path) = args.output_user_anonsets {
@@ -40,7 +100,13 @@ pub fn run(args: AnalyzeArgs) -> anyhow::Result<()> {
         }
 
         if let Some(path) = args.output {
-            output_anonymity_sets(path, &source_relationship_anonymity_sets, &network_trace)?;
+            output_anonymity_sets(
+                path,
+                &source_relationship_anonymity_sets,
+                &network_trace,
+                classes.as_ref(),
+                &metadata,
+            )?;
         }
 
         if let Some(path) = args.generate_testcase {
@@ -56,15 +122,42 @@ pub fn run(args: AnalyzeArgs) -> anyhow::Result<()> {
     } else {
         // sizes only
         let (source_relationship_anonymity_sets, _destination_relationship_anonymity_sets) =
-            ppcalc_metric::compute_relationship_anonymity_sizes(
-                &network_trace,
-                Duration::milliseconds(args.min_window as i64),
-                Duration::milliseconds(args.max_window as i64),
-            )
+            match relay_observations {
+                None if args.bidirectional => {
+                    ppcalc_metric::compute_bidirectional_relationship_anonymity_sizes(
+                        &network_trace,
+                        args.dummies,
+                        min_window,
+                        max_window,
+                    )
+                }
+                None => ppcalc_metric::compute_relationship_anonymity_sizes_with_dummies(
+                    &network_trace,
+                    args.dummies,
+                    min_window,
+                    max_window,
+                ),
+                Some(ref observations) => {
+                    ppcalc_metric::compute_relay_relationship_anonymity_sizes(
+                        &network_trace,
+                        observations,
+                        &adversary_relays,
+                        args.dummies,
+                        min_window,
+                        max_window,
+                    )
+                }
+            }
             .map_err(|e| anyhow!(e))?;
 
         if let Some(path) = args.output {
-            output_anonymity_sets(path, &source_relationship_anonymity_sets, &network_trace)?;
+            output_anonymity_sets(
+                path,
+                &source_relationship_anonymity_sets,
+                &network_trace,
+                classes.as_ref(),
+                &metadata,
+            )?;
         }
     }
 
@@ -101,10 +194,13 @@ fn output_anonymity_sets<T: JsonAnonymitySet>(
     path: impl AsRef<Path>,
     anonymity_sets: &HashMap<SourceId, Vec<(MessageId, T)>>,
     trace: &Trace,
+    classes: Option<&HashMap<SourceId, String>>,
+    metadata: &AnalysisMetadata,
 ) -> anyhow::Result<()> {
     use serde_json::{Map, Value};
     let path = path.as_ref();
 
+    let mut class_summaries: BTreeMap<&str, ClassSummary> = BTreeMap::new();
     let sets_per_user: Map<String, Value> = anonymity_sets
         .iter()
         .map(|(k, v)| {
@@ -135,9 +231,22 @@ fn output_anonymity_sets<T: JsonAnonymitySet>(
                 })
                 .map(|duration| duration.as_seconds_f32());
 
+            let class = classes.and_then(|classes| classes.get(k));
+            if let Some(class) = class {
+                let summary = class_summaries.entry(class).or_default();
+                summary.num_sources += 1;
+                if let Some(size) = last_anonset_size {
+                    summary.last_anonset_sizes.push(size);
+                }
+                if let Some(time) = time_to_deanonymization {
+                    summary.times_to_deanon.push(time);
+                }
+            }
+
             (
                 k.to_string(),
                 json!({
+                    "class": class,
                     "last_anonset_size": last_anonset_size,
                     "deanonymized_at_num": deanonymized_at_index,
                     "time_to_deanon": time_to_deanonymization,
@@ -147,6 +256,22 @@ fn output_anonymity_sets<T: JsonAnonymitySet>(
         })
         .collect();
 
+    for (class, summary) in class_summaries.iter() {
+        println!(
+            "Class {}: {} sources, {} de-anonymized, mean last anonymity set size {}",
+            class,
+            summary.num_sources,
+            summary.times_to_deanon.len(),
+            summary
+                .mean_last_anonset_size()
+                .map_or("-".to_string(), |x| format!("{:.2}", x)),
+        );
+    }
+    let classes: Map<String, Value> = class_summaries
+        .into_iter()
+        .map(|(class, summary)| (class.to_string(), summary.to_json()))
+        .collect();
+
     let mut file_writer: Box<dyn Write> = {
         let file = fs::File::create(path)?;
 
@@ -164,5 +289,138 @@ fn output_anonymity_sets<T: JsonAnonymitySet>(
 
     serde_json::to_writer_pretty(&mut file_writer, &sets_per_user)?;
 
+    // the provenance and the summary of each class are kept next to the output
+    metadata.write_for_output(path)?;
+    if !classes.is_empty() {
+        fs::write(
+            class_summaries_path(path),
+            serde_json::to_string_pretty(&classes)?,
+        )?;
+    }
+
     Ok(())
 }
+
+/// Get the path of the class summaries next to an analysis output, e.g.
+/// `sets.json.classes.json`
+fn class_summaries_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".classes.json");
+    path.into()
+}
+
+/// The anonymity of the sources of a class
+#[derive(Default)]
+struct ClassSummary {
+    num_sources: usize,
+    last_anonset_sizes: Vec<usize>,
+    /// Times to de-anonymization of the de-anonymized sources [s]
+    times_to_deanon: Vec<f32>,
+}
+
+impl ClassSummary {
+    fn mean_last_anonset_size(&self) -> Option<f64> {
+        if self.last_anonset_sizes.is_empty() {
+            return None;
+        }
+        let sum: usize = self.last_anonset_sizes.iter().sum();
+        Some(sum as f64 / self.last_anonset_sizes.len() as f64)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut times = self.times_to_deanon.clone();
+        times.sort_by(|a, b| a.total_cmp(b));
+        json!({
+            "sources": self.num_sources,
+            "deanonymized": times.len(),
+            "mean_last_anonset_size": self.mean_last_anonset_size(),
+            "median_time_to_deanon": times.get(times.len() / 2),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ppcalc_metric::{Direction, TraceEntry};
+    use time::macros::datetime;
+
+    #[test]
+    fn class_summaries() {
+        // four sources send a message every 10 s
+        let mut builder = TraceBuilder::new();
+        for m in 0..12 {
+            let timestamp = datetime!(1970-01-01 0:00) + Duration::seconds(10 * (m / 4) as i64);
+            builder.add_entry(TraceEntry {
+                m_id: MessageId::new(m),
+                source_id: SourceId::new(m % 4),
+                source_timestamp: timestamp,
+                destination_id: DestinationId::new(m % 4),
+                destination_timestamp: timestamp + Duration::milliseconds(50),
+                session: 0,
+                dummy: false,
+                dropped: false,
+                direction: Direction::Upstream,
+                retransmission: false,
+            });
+        }
+        let trace = builder.build().unwrap();
+        let sizes = |sizes: &[(u64, usize)]| {
+            sizes
+                .iter()
+                .map(|&(m, size)| (MessageId::new(m), size))
+                .collect::<Vec<_>>()
+        };
+        let anonymity_sets: HashMap<SourceId, Vec<(MessageId, usize)>> = [
+            (SourceId::new(0), sizes(&[(0, 4), (4, 1)])),
+            (SourceId::new(1), sizes(&[(1, 4), (5, 2)])),
+            (SourceId::new(2), sizes(&[(2, 4), (6, 2), (10, 1)])),
+            (SourceId::new(3), sizes(&[(3, 4), (7, 1)])),
+        ]
+        .into_iter()
+        .collect();
+        // source 3 has no class
+        let classes: HashMap<SourceId, String> = [(0, "a"), (1, "a"), (2, "b")]
+            .into_iter()
+            .map(|(source, class)| (SourceId::new(source), class.to_string()))
+            .collect();
+        let metadata = AnalysisMetadata {
+            tool: provenance::Tool::current(),
+            parameters: json!({}),
+            input_sha256: "00ff".to_string(),
+            trace: None,
+        };
+
+        let path = std::env::temp_dir().join("ppcalc_test_class_summaries.json");
+        output_anonymity_sets(&path, &anonymity_sets, &trace, Some(&classes), &metadata).unwrap();
+        let read = |path: &Path| -> serde_json::Value {
+            let value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+            fs::remove_file(path).unwrap();
+            value
+        };
+        let summaries = read(&class_summaries_path(&path));
+        let sets = read(&path);
+        fs::remove_file(provenance::metadata_path(&path)).unwrap();
+
+        assert_eq!(
+            summaries,
+            json!({
+                "a": {
+                    "sources": 2,
+                    "deanonymized": 1,
+                    "mean_last_anonset_size": 1.5,
+                    "median_time_to_deanon": 10.0,
+                },
+                "b": {
+                    "sources": 1,
+                    "deanonymized": 1,
+                    "mean_last_anonset_size": 1.0,
+                    "median_time_to_deanon": 20.0,
+                },
+            })
+        );
+        assert_eq!(sets["3"]["class"], serde_json::Value::Null);
+        assert_eq!(sets["2"]["class"], "b");
+        assert_eq!(sets["2"]["deanonymized_at_num"], 2);
+    }
+}
@@ -1,12 +1,22 @@
+use std::fmt;
+use std::fs;
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use clap::{Args, Parser, Subcommand};
-use rand::distributions::{uniform::SampleUniform, Distribution, Uniform};
-use rand_distr::Normal;
+use rand::distributions::{uniform::SampleUniform, Distribution, Uniform, WeightedIndex};
+use rand_distr::{Exp, Gamma, LogNormal, Normal, Pareto, Weibull};
+use serde::{Deserialize, Serialize};
 
+use ppcalc_metric::DummyVisibility;
+
+use crate::cover::CoverTraffic;
 use crate::destination::DestinationSelectionType;
+use crate::load::LoadProfile;
+use crate::mix::{self, MixModel};
+use crate::network::{QueueLocation, QueueModel};
+use crate::source::TrafficModel;
 
 /// Tool to analyze and generate network traces of anonymity networks.
 #[derive(Parser, Debug)]
@@ -19,21 +29,63 @@ pub struct Cli {
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Generate a new trace file, simulating ACN communication
-    Generate(GenerateArgs),
+    Generate(Box<GenerateArgs>),
     /// Analyze a network trace, estimating the achieved anonymity
     Analyze(AnalyzeArgs),
+    /// Print descriptive statistics of a network trace
+    Stats(StatsArgs),
+    /// Estimate the generator parameters that reproduce a trace, as a scenario file
+    Fit(FitArgs),
+    /// Build a trace from packet captures taken at the ingress and egress of an ACN
+    ImportPcap(ImportPcapArgs),
+    /// Build a trace from the tgen logs of a Shadow simulation
+    ImportShadow(ImportShadowArgs),
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Serialize)]
 pub struct AnalyzeArgs {
-    /// Minimum window for anonymity metric (milliseconds)
+    /// Minimum window for anonymity metric (milliseconds). Applies per hop if the adversary observes relays.
     #[arg(long)]
     pub min_window: u64,
 
-    /// Maximum window for anonymity metric (milliseconds)
+    /// Maximum window for anonymity metric (milliseconds). Applies per hop if the adversary observes relays.
     #[arg(long)]
     pub max_window: u64,
 
+    /// CSV file with the observations of the trace's messages at the relays of the ACN
+    #[arg(long, value_name = "OBSERVATIONS_FILE", requires = "adversary_relays")]
+    pub relay_observations: Option<PathBuf>,
+
+    /// Place the adversary at these relays instead of the sources and destinations.
+    /// Messages are observed at the source side if their first relay is compromised,
+    /// and at the destination side if their last relay is.
+    #[arg(
+        long,
+        value_name = "RELAY_IDS",
+        value_delimiter = ',',
+        requires = "relay_observations"
+    )]
+    pub adversary_relays: Vec<u64>,
+
+    /// Whether the adversary can tell dummy messages (cover traffic) apart from real ones
+    #[arg(
+        long,
+        value_name = "indistinguishable|distinguishable",
+        default_value = "indistinguishable",
+        value_parser = parse_dummy_visibility
+    )]
+    pub dummies: DummyVisibility,
+
+    /// Also use the downstream messages (responses) to narrow down the destinations
+    /// of the sources. The anonymity sets then include the responses.
+    #[arg(long, conflicts_with = "relay_observations")]
+    pub bidirectional: bool,
+
+    /// CSV file with the class of each source, to report the anonymity per class of users.
+    /// Defaults to TRACE_FILE.classes.csv, if it exists.
+    #[arg(long, value_name = "CLASSES_FILE")]
+    pub classes: Option<PathBuf>,
+
     /// Output the analysis data as a testcase
     #[arg(long, value_name = "TESTCASE_FOLDER")]
     pub generate_testcase: Option<String>,
@@ -43,7 +95,9 @@ pub struct AnalyzeArgs {
     pub output_user_anonsets: Option<PathBuf>,
 
     /// Output JSON file containing the computed anonymity sets (or their sizes) per source message.
-    /// If the file name ends in ".zst", it is compressed with zstandard.
+    /// If the file name ends in ".zst", it is compressed with zstandard. The provenance of the
+    /// analysis and its input is saved to OUT_FILE.meta.json, and the anonymity per class of
+    /// sources to OUT_FILE.classes.json.
     #[arg(long, short, value_name = "OUT_FILE")]
     pub output: Option<PathBuf>,
 
@@ -56,6 +110,120 @@ pub struct AnalyzeArgs {
     pub input: PathBuf,
 }
 
+#[derive(Args, Debug)]
+pub struct StatsArgs {
+    /// Width of the time buckets for counting concurrently active sources (milliseconds)
+    #[arg(long, value_name = "MILLISECONDS", default_value = "1000")]
+    pub interval: u64,
+
+    /// Output JSON file containing the computed statistics
+    #[arg(long, short, value_name = "OUT_FILE")]
+    pub output: Option<PathBuf>,
+
+    /// Input CSV trace file to compute statistics for
+    #[arg(value_name = "TRACE_FILE")]
+    pub input: PathBuf,
+}
+
+#[derive(Args, Debug)]
+#[clap(after_help = color_print::cstr!(r#"<bold><underline>FLOW_RULES file:</underline></bold>
+A JSON object with the lists "ingress" and "egress" of rules that select the packets of
+either capture. Each rule may contain any of the fields "protocol" ("tcp" or "udp"),
+"src_ip", "src_port", "dst_ip" and "dst_port", and an optional "label". Packets are
+assigned to the first rule they match. All packets of a labeled rule form a single flow;
+otherwise, each 5-tuple is its own flow, labeled like <bold>tcp:10.0.0.1:40000->10.0.0.2:443</bold>.
+
+<bold><underline>MAPPING file:</underline></bold>
+A CSV file with the columns <bold>ingress_flow</bold> and <bold>egress_flow</bold>, matching flow labels that carry
+the same traffic, and the optional columns <bold>source</bold> and <bold>destination</bold> to name the
+communicating entities (defaulting to the flow labels). Packets of matched flows are
+paired in order of appearance.
+"#))]
+pub struct ImportPcapArgs {
+    /// Packet capture (pcap or pcapng) taken at the ingress, i.e. the sources' side
+    #[arg(long, value_name = "CAPTURE_FILE")]
+    pub ingress: PathBuf,
+
+    /// Packet capture (pcap or pcapng) taken at the egress, i.e. the destinations' side
+    #[arg(long, value_name = "CAPTURE_FILE")]
+    pub egress: PathBuf,
+
+    /// JSON file with the rules for grouping packets into flows
+    #[arg(long, value_name = "FLOW_RULES")]
+    pub flows: PathBuf,
+
+    /// CSV file with the ground-truth mapping of ingress flows to egress flows
+    #[arg(long, value_name = "MAPPING")]
+    pub mapping: PathBuf,
+
+    /// Ignore packets carrying less than this number of payload bytes (e.g. pure TCP ACKs)
+    #[arg(long, value_name = "BYTES", default_value = "1")]
+    pub min_payload: usize,
+
+    /// Output CSV file to save the trace to
+    #[arg(value_name = "OUTPUT_FILE")]
+    pub output: PathBuf,
+}
+
+#[derive(Args, Debug)]
+#[clap(
+    after_help = "The scenario describes one population per class of sources, if the trace has a \
+classes file, and otherwise a single population. Each session is assumed to send at a constant \
+rate. The observed bandwidths, stream lengths, wait times, numbers of sessions and network \
+delays are saved as empirical distributions next to the scenario, e.g. to \
+SCENARIO_FILE.default.bandwidth.txt, and the popularity of the destinations as weights to \
+SCENARIO_FILE.destinations.txt. Cover traffic and responses are not fitted."
+)]
+pub struct FitArgs {
+    /// Size of each message [B]
+    #[arg(long, value_name = "BYTES", default_value = "514", value_parser = clap::value_parser!(u64).range(1..))]
+    pub message_size: u64,
+
+    /// CSV file with the class of each source, to fit a population per class.
+    /// Defaults to TRACE_FILE.classes.csv, if it exists.
+    #[arg(long, value_name = "CLASSES_FILE")]
+    pub classes: Option<PathBuf>,
+
+    /// Output scenario file to save the fitted parameters to
+    #[arg(long, short, value_name = "SCENARIO_FILE")]
+    pub output: PathBuf,
+
+    /// Input CSV trace file to fit the parameters to
+    #[arg(value_name = "TRACE_FILE")]
+    pub input: PathBuf,
+}
+
+#[derive(Args, Debug)]
+#[clap(
+    after_help = "Each successful tgen stream becomes a source, sending the bytes its client \
+wrote to the server (the destination). The payload is split into messages of MESSAGE_SIZE \
+bytes, spread evenly between the first and the last byte sent by the client and received by \
+the server, respectively. Client and server sides of a stream are matched by their tgen peer \
+names and stream IDs."
+)]
+pub struct ImportShadowArgs {
+    /// Treat hosts whose name starts with one of these prefixes as tgen servers
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        value_delimiter = ',',
+        default_value = "server"
+    )]
+    pub servers: Vec<String>,
+
+    /// Size of each message [B]
+    #[arg(long, value_name = "BYTES", default_value = "514", value_parser = clap::value_parser!(u64).range(1..))]
+    pub message_size: u64,
+
+    /// Shadow results directory (e.g. shadow.data) containing the tgen logs of each host
+    #[arg(value_name = "RESULTS_DIR")]
+    pub input: PathBuf,
+
+    /// Output CSV file to save the trace to
+    #[arg(value_name = "OUTPUT_FILE")]
+    pub output: PathBuf,
+}
+
 #[derive(Args, Debug)]
 #[clap(after_help = color_print::cstr!("<bold><underline>DISTRIBUTION values:</underline></bold>
 Some parameters require a probability distribution to be specified.
@@ -65,45 +233,290 @@ Currently, the following forms are accepted:
 - <bold>uniform:MIN:MAX</bold> (choose samples uniformly at random between MIN and MAX, inclusive)
 - <bold>normal:MEAN:DEV</bold> (draw samples from a normal distribution with mean value MEAN and standard deviation DEV)
 - <bold>normal:MEAN:DEV:MIN:MAX</bold> (draw samples from a normal distribution as before, but capped to the range [MIN..MAX], inclusive)
+- <bold>exponential:MEAN</bold> (draw samples from an exponential distribution with mean value MEAN)
+- <bold>lognormal:MU:SIGMA</bold> (draw samples whose logarithm is normally distributed with mean value MU and standard deviation SIGMA)
+- <bold>pareto:SCALE:SHAPE</bold> (draw samples from a Pareto distribution with minimum value SCALE and tail index SHAPE)
+- <bold>weibull:SCALE:SHAPE</bold> (draw samples from a Weibull distribution)
+- <bold>gamma:SHAPE:SCALE</bold> (draw samples from a gamma distribution)
+- <bold>empirical:FILE</bold> (draw samples from the observed values in FILE, one per line, optionally followed by a weight, or from a histogram with lines LOWER,UPPER,WEIGHT)
+- <bold>mixture:WEIGHT*DISTRIBUTION,...</bold> (draw samples from one of the given distributions, chosen by weight, e.g. mixture:0.9*lognormal:8:1,0.1*pareto:100000:1.5)
+
+Integer parameters are rounded up if a distribution yields real numbers.
+
+<bold><underline>MODEL values:</underline></bold>
+The traffic model determines how a source spreads its messages over time:
+
+- <bold>constant</bold> (constant rate given by the bandwidth, like a bulk transfer or streaming)
+- <bold>poisson</bold> (Poisson arrivals, with the bandwidth determining the average rate)
+- <bold>onoff:SHAPE:MEAN_ON:MEAN_OFF</bold> (on/off bursts with Pareto-distributed period lengths [ms] of shape SHAPE > 1, sending at the bandwidth while on)
+- <bold>web:PAGE_SIZE:MEAN_THINK_TIME</bold> (page loads of PAGE_SIZE bytes, each sent at the bandwidth, separated by exponentially distributed think times [ms])
+
+<bold><underline>MIX values:</underline></bold>
+A mix collects the messages and outputs them in batches or with individual delays:
+
+- <bold>threshold:N</bold> (flush all messages as soon as N have been collected)
+- <bold>timed:INTERVAL</bold> (flush all messages every INTERVAL [ms])
+- <bold>pool:N:POOL</bold> (as soon as N+POOL messages have been collected, flush N randomly chosen ones)
+- <bold>binomial:INTERVAL:P</bold> (every INTERVAL [ms], flush each message with probability P)
+- <bold>continuous:DISTRIBUTION</bold> (delay each message individually by a sample from DISTRIBUTION [ms], like Stop-and-Go mixes or Loopix)
+
+<bold><underline>COVER values:</underline></bold>
+Dummy messages are marked in the trace. Padding and loop messages never reach a destination. \
+Intervals drawn from a DISTRIBUTION are raised to at least 1µs:
+
+- <bold>constant:INTERVAL</bold> (pad each session to one message per INTERVAL [ms] while it is active)
+- <bold>adaptive:DISTRIBUTION</bold> (send padding whenever a session has been silent for a time drawn from DISTRIBUTION [ms], like WTF-PAD)
+- <bold>loop:DISTRIBUTION</bold> (send loop messages back to the source, with intervals drawn from DISTRIBUTION [ms])
+- <bold>drop:DISTRIBUTION</bold> (send messages to random destinations that discard them, with intervals drawn from DISTRIBUTION [ms])
+
+<bold><underline>SCENARIO FILES:</underline></bold>
+A scenario file is a TOML file that can describe several populations of sources.
+Its values use the same forms as the command line parameters:
+
+  sources = 100                      <dim># total number of sources</dim>
+  message_size = 514                 <dim># optional</dim>
+  seed = 1                           <dim># optional, overridden by --seed</dim>
+
+  [[population]]                     <dim># one or more</dim>
+  name = \"web\"
+  proportion = 0.8                   <dim># or: sources = 80</dim>
+  bandwidth = \"normal:10:2:1:20\"
+  stream_length = \"uniform:10000:1000000\"
+  source_wait = \"uniform:0:10000\"
+  traffic_model = \"web:50000:2000\"   <dim># optional, as are the following</dim>
+  sessions_per_source = \"constant:1\"
+  session_interval = \"constant:0\"
+  load = \"diurnal:86400000:0.5\"      <dim># see --load</dim>
+  destination_selection = \"uniform\"  <dim># overrides the one of [destinations]</dim>
+
+  [destinations]
+  count = 20
+  selection = \"zipf:1.2\"
+  stickiness = 0.0                   <dim># optional</dim>
+
+  [destinations.responses]           <dim># optional</dim>
+  size = \"uniform:1000:100000\"
+  think_time = \"constant:0\"        <dim># optional</dim>
+  bandwidth = \"constant:10\"
+
+  [network]
+  delay = \"uniform:10:50\"
+  <dim># optional: path_latency, destination_latencies, fifo, queue,</dim>
+  <dim># queue_location, cover (a list), mix, loss, retransmission_timeout and tor</dim>
+
+Relative file paths are relative to the scenario file. The populations are classes of users:
+the class of each source is saved to OUTPUT_FILE.classes.csv, which <bold>analyze</bold> uses to
+report the anonymity per class.
 "))]
+#[derive(Serialize)]
 pub struct GenerateArgs {
+    /// Read the sources, destinations and network from a scenario file
+    /// instead of the command line parameters. See SCENARIO FILES below.
+    #[arg(
+        long,
+        value_name = "SCENARIO_FILE",
+        conflicts_with_all = [
+            "num_sources", "num_destinations", "destination_selection", "bandwidth",
+            "stream_length", "message_size", "traffic_model", "source_wait",
+            "sessions_per_source", "session_interval", "load", "destination_stickiness",
+            "response_size", "think_time", "response_bandwidth",
+            "network_delay", "path_latency", "destination_latencies", "fifo", "queue",
+            "queue_location", "cover", "mix", "loss", "retransmission_timeout", "tor"
+        ]
+    )]
+    pub scenario: Option<PathBuf>,
+
     /// Number of sources to send from
-    #[arg(short = 's', long = "sources")]
-    pub num_sources: u64,
+    #[arg(short = 's', long = "sources", required_unless_present = "scenario")]
+    pub num_sources: Option<u64>,
 
     /// Number of destinations to send to
-    #[arg(short = 'd', long = "destinations")]
-    pub num_destinations: u64,
+    #[arg(
+        short = 'd',
+        long = "destinations",
+        required_unless_present = "scenario",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub num_destinations: Option<u64>,
 
-    /// Reuse the sources from the specified trace file
-    #[arg(long, value_name = "TRACE_FILE")]
+    /// Reuse the sources from the specified sources file (see --save-sources)
+    /// or trace file, instead of generating new ones
+    #[arg(long, value_name = "FILE")]
     pub reuse_sources: Option<PathBuf>,
 
-    /// Assignment strategy for connecting sources to destinations
-    #[arg(long, value_name = "uniform|roundrobin|normal", value_parser = parse_destination_selection_type)]
-    pub destination_selection: DestinationSelectionType,
+    /// Save the send times of the sources' messages to SOURCES_FILE, to reuse
+    /// them with --reuse-sources
+    #[arg(long, value_name = "SOURCES_FILE")]
+    pub save_sources: Option<PathBuf>,
+
+    /// Reuse the destination of each session of the sources from the specified
+    /// destinations file (see --save-destinations) or trace file, instead of
+    /// selecting new ones
+    #[arg(long, value_name = "FILE")]
+    pub reuse_destinations: Option<PathBuf>,
+
+    /// Save the destination of each session of the sources to DESTINATIONS_FILE,
+    /// to reuse them with --reuse-destinations
+    #[arg(long, value_name = "DESTINATIONS_FILE")]
+    pub save_destinations: Option<PathBuf>,
+
+    /// Reuse the network delays of the messages from the specified trace file,
+    /// instead of sampling delays and latencies. The n-th message of each
+    /// session gets the delay of the n-th message of that session in the trace,
+    /// so the sources should be reused from the same trace. Queues and mixes
+    /// still apply.
+    #[arg(
+        long,
+        value_name = "TRACE_FILE",
+        conflicts_with_all = ["path_latency", "destination_latencies", "tor"]
+    )]
+    pub reuse_delays: Option<PathBuf>,
+
+    /// Assignment strategy for connecting sources to destinations. A DISTRIBUTION is sampled
+    /// for each source to obtain its destination ID, drawing again if the ID is out of range.
+    /// zipf:EXPONENT makes destination popularity follow Zipf's law by destination ID, and
+    /// weights:FILE reads one popularity weight per destination and line from FILE.
+    #[arg(long, value_name = "uniform|roundrobin|normal|zipf:EXPONENT|weights:FILE|DISTRIBUTION", value_parser = parse_destination_selection_type, required_unless_present = "scenario")]
+    pub destination_selection: Option<DestinationSelectionType>,
 
     /// Probability distribution for the bandwidth per user [Mbit/s]
-    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<f64>)]
-    pub bandwidth: ParsedDistribution<f64>,
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<f64>, required_unless_present = "scenario")]
+    pub bandwidth: Option<ParsedDistribution<f64>>,
 
     /// Probability distribution for the length of a transfer [B]
-    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<u64>)]
-    pub stream_length: ParsedDistribution<u64>,
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<u64>, required_unless_present = "scenario")]
+    pub stream_length: Option<ParsedDistribution<u64>>,
 
     /// Size of each message [B]
-    #[arg(long, value_name = "BYTES", default_value = "514")]
+    #[arg(long, value_name = "BYTES", default_value = "514", value_parser = clap::value_parser!(u64).range(1..))]
     pub message_size: u64,
 
+    /// Traffic model that determines when a source sends its messages
+    #[arg(long, value_name = "MODEL", default_value = "constant", value_parser = parse_traffic_model)]
+    pub traffic_model: TrafficModel,
+
     /// Probability distribution for the time the source waits before sending [ms]
-    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<f64>)]
-    pub source_wait: ParsedDistribution<f64>,
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<f64>, required_unless_present = "scenario")]
+    pub source_wait: Option<ParsedDistribution<f64>>,
+
+    /// Probability distribution for the number of sessions per source. Each
+    /// session is a separate transfer, with its own stream length, bandwidth
+    /// and destination
+    #[arg(long, value_name = "DISTRIBUTION", default_value = "constant:1", value_parser = parse_distribution::<u64>)]
+    pub sessions_per_source: ParsedDistribution<u64>,
+
+    /// Probability distribution for the time between the end of a session and
+    /// the start of the next one of the same source [ms]
+    #[arg(long, value_name = "DISTRIBUTION", default_value = "constant:0", value_parser = parse_distribution::<f64>)]
+    pub session_interval: ParsedDistribution<f64>,
+
+    /// Relative rate over time at which sources and sessions start, where 1
+    /// is the rate of the source wait and session interval distributions:
+    /// diurnal:PERIOD:AMPLITUDE[:PEAK] [ms, 0-1, ms] varies sinusoidally,
+    /// steps:TIME=RATE,... [ms] changes at the given times, and file:PATH
+    /// interpolates the TIME,RATE lines of a CSV file
+    #[arg(long, value_name = "PROFILE", value_parser = parse_load_profile)]
+    pub load: Option<LoadProfile>,
+
+    /// Probability that a session goes to the same destination as the
+    /// previous session of its source, instead of choosing one independently
+    #[arg(long, value_name = "PROBABILITY", default_value = "0", value_parser = parse_probability)]
+    pub destination_stickiness: f64,
+
+    /// Probability distribution for the size of the response that the destination
+    /// sends back to the source after each session [B]. The responses are
+    /// downstream messages in the trace.
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<u64>, requires = "response_bandwidth", conflicts_with = "tor")]
+    pub response_size: Option<ParsedDistribution<u64>>,
 
-    /// Probability distribution for the network delay [ms]
+    /// Probability distribution for the time between the arrival of the last
+    /// message of a session and the destination's response [ms]
+    #[arg(long, value_name = "DISTRIBUTION", default_value = "constant:0", value_parser = parse_distribution::<f64>)]
+    pub think_time: ParsedDistribution<f64>,
+
+    /// Probability distribution for the bandwidth of each response [Mbit/s]
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<f64>, requires = "response_size")]
+    pub response_bandwidth: Option<ParsedDistribution<f64>>,
+
+    /// Probability distribution for the network delay of each message [ms]
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<u64>, required_unless_present = "scenario")]
+    pub network_delay: Option<ParsedDistribution<u64>>,
+
+    /// Probability distribution for a persistent base latency of each path
+    /// between a source and a destination [ms]. It is drawn once per path and
+    /// added to the network delay of each message.
     #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<u64>)]
-    pub network_delay: ParsedDistribution<u64>,
+    pub path_latency: Option<ParsedDistribution<u64>>,
 
-    /// Output CSV file to save the trace to
+    /// Read per-destination latency profiles from FILE, containing one
+    /// DISTRIBUTION per destination and line [ms]. For each message, a sample
+    /// of its destination's profile is added to the network delay.
+    #[arg(long, value_name = "FILE")]
+    pub destination_latencies: Option<PathBuf>,
+
+    /// Keep the order of messages within each stream, so that they cannot
+    /// overtake each other, like in circuit-based ACNs
+    #[arg(long)]
+    pub fifo: bool,
+
+    /// Let messages pass shared queues after their network delay, so that bursts
+    /// cause congestion: mm1:MEAN_SERVICE_TIME for exponential or
+    /// deterministic:SERVICE_TIME for constant service times [ms]
+    #[arg(long, value_name = "QUEUE", value_parser = parse_queue_model)]
+    pub queue: Option<QueueModel>,
+
+    /// Location of the queues: one per destination link, or relays:COUNT for
+    /// COUNT shared relays that each stream is randomly routed through
+    #[arg(long, value_name = "destination|relays:COUNT", default_value = "destination", value_parser = parse_queue_location, requires = "queue")]
+    pub queue_location: QueueLocation,
+
+    /// Let the sources send dummy messages as cover traffic. Can be given
+    /// multiple times to combine different kinds of cover traffic.
+    #[arg(long, value_name = "COVER", value_parser = parse_cover_traffic)]
+    pub cover: Vec<CoverTraffic>,
+
+    /// Route all messages through a mix after the network delay
+    #[arg(long, value_name = "MIX", value_parser = parse_mix_model)]
+    pub mix: Option<MixModel>,
+
+    /// Probability that a message is lost in the network. Lost messages are
+    /// marked as dropped and only appear at the source.
+    #[arg(long, value_name = "PROBABILITY", default_value = "0", value_parser = parse_probability, conflicts_with = "tor")]
+    pub loss: f64,
+
+    /// Probability distribution for the time after which a source sends a lost
+    /// message again [ms]. The retransmissions are marked in the trace and can
+    /// be lost as well. Without it, lost messages are not retransmitted.
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = parse_distribution::<f64>)]
+    pub retransmission_timeout: Option<ParsedDistribution<f64>>,
+
+    /// Simulate a Tor-like network with the relays from RELAYS_FILE, a Tor
+    /// consensus, server descriptors or a JSON list of relays. Each stream is
+    /// sent via a bandwidth-weighted three-hop circuit, using the position
+    /// weights of a consensus, and the network delay applies to each link of
+    /// the circuit.
+    #[arg(
+        long,
+        value_name = "RELAYS_FILE",
+        conflicts_with_all = ["path_latency", "destination_latencies", "fifo", "queue", "mix"]
+    )]
+    pub tor: Option<PathBuf>,
+
+    /// Save the observations of the messages at the relays to OBSERVATIONS_FILE,
+    /// if a Tor network is simulated. The relay IDs are the positions of the
+    /// relays in RELAYS_FILE.
+    #[arg(long, value_name = "OBSERVATIONS_FILE")]
+    pub relay_observations: Option<PathBuf>,
+
+    /// Seed for the random number generators. Generating with the same seed and
+    /// parameters yields the same trace, independent of the number of threads
+    /// (RAYON_NUM_THREADS). If not given, a random seed is chosen. It must fit
+    /// into 63 bits, so that the scenario file can store it.
+    #[arg(long, value_name = "SEED", value_parser = clap::value_parser!(u64).range(..=i64::MAX as u64))]
+    pub seed: Option<u64>,
+
+    /// Output CSV file to save the trace to. Its provenance is saved to
+    /// OUTPUT_FILE.meta.json, and its scenario to OUTPUT_FILE.scenario.toml.
     #[arg(value_name = "OUTPUT_FILE")]
     pub output: PathBuf,
 }
@@ -114,23 +527,252 @@ impl Cli {
     }
 }
 
-fn parse_destination_selection_type(s: &str) -> Result<DestinationSelectionType, String> {
+pub fn parse_destination_selection_type(s: &str) -> Result<DestinationSelectionType, String> {
     match s {
         "normal" => Ok(DestinationSelectionType::Normal),
         "uniform" => Ok(DestinationSelectionType::Uniform),
         "roundrobin" => Ok(DestinationSelectionType::RoundRobin),
-        _ => Err(format!("Invalid destination selection type \"{}\".", s)),
+        _ if s.starts_with("zipf:") => {
+            let exponent = s["zipf:".len()..]
+                .parse::<f64>()
+                .map_err(|_| format!("Invalid Zipf exponent in \"{}\".", s))?;
+            Ok(DestinationSelectionType::Zipf { exponent })
+        }
+        _ if s.starts_with("weights:") => Ok(DestinationSelectionType::Weights {
+            file: PathBuf::from(&s["weights:".len()..]),
+        }),
+        _ => match parse_distribution::<i64>(s) {
+            Ok(distribution) => Ok(DestinationSelectionType::Distribution(distribution)),
+            Err(e) => Err(format!(
+                "Invalid destination selection type \"{}\". Use uniform, roundrobin, normal, zipf:EXPONENT, weights:FILE, or a distribution.\n{}",
+                s, e
+            )),
+        },
+    }
+}
+
+pub fn parse_cover_traffic(s: &str) -> Result<CoverTraffic, String> {
+    let err = || {
+        format!(
+            "Invalid cover traffic \"{}\". Specify it using one of the following forms:
+    constant:INTERVAL
+    adaptive:DISTRIBUTION
+    loop:DISTRIBUTION
+    drop:DISTRIBUTION",
+            s
+        )
+    };
+
+    let (kind, parameter) = s.split_once(':').ok_or_else(err)?;
+    match kind {
+        "constant" => match parameter.parse::<f64>() {
+            Ok(interval) if interval > 0.0 => Ok(CoverTraffic::Constant { interval }),
+            _ => Err(err()),
+        },
+        "adaptive" => Ok(CoverTraffic::Adaptive(parse_distribution(parameter)?)),
+        "loop" => Ok(CoverTraffic::Loop(parse_distribution(parameter)?)),
+        "drop" => Ok(CoverTraffic::Drop(parse_distribution(parameter)?)),
+        _ => Err(err()),
+    }
+}
+
+fn parse_dummy_visibility(s: &str) -> Result<DummyVisibility, String> {
+    match s {
+        "indistinguishable" => Ok(DummyVisibility::Indistinguishable),
+        "distinguishable" => Ok(DummyVisibility::Distinguishable),
+        _ => Err(format!(
+            "Invalid dummy visibility \"{}\". Use indistinguishable or distinguishable.",
+            s
+        )),
+    }
+}
+
+pub fn parse_mix_model(s: &str) -> Result<MixModel, String> {
+    let err = || {
+        format!(
+            "Invalid mix \"{}\". Specify it using one of the following forms:
+    threshold:N
+    timed:INTERVAL
+    pool:N:POOL
+    binomial:INTERVAL:P
+    continuous:DISTRIBUTION",
+            s
+        )
+    };
+
+    if let Some(distribution) = s.strip_prefix("continuous:") {
+        return Ok(MixModel::Continuous(parse_distribution(distribution)?));
     }
+
+    let splitted: Vec<_> = s.split(':').collect();
+    let model = match splitted[..] {
+        ["threshold", n] => MixModel::Threshold {
+            n: n.parse().map_err(|_| err())?,
+        },
+        ["timed", interval] => MixModel::Timed {
+            interval: interval.parse().map_err(|_| err())?,
+        },
+        ["pool", n, pool] => MixModel::Pool {
+            n: n.parse().map_err(|_| err())?,
+            pool: pool.parse().map_err(|_| err())?,
+        },
+        ["binomial", interval, probability] => MixModel::Binomial {
+            interval: interval.parse().map_err(|_| err())?,
+            probability: parse_probability(probability)?,
+        },
+        _ => return Err(err()),
+    };
+
+    match model {
+        MixModel::Threshold { n: 0 } | MixModel::Pool { n: 0, .. } => {
+            Err("A mix must flush at least one message at a time.".to_string())
+        }
+        MixModel::Timed { interval } | MixModel::Binomial { interval, .. }
+            if interval.is_nan() || interval < mix::MIN_INTERVAL =>
+        {
+            Err(format!(
+                "The flushing interval of a mix must be at least {} ms.",
+                mix::MIN_INTERVAL
+            ))
+        }
+        MixModel::Binomial {
+            probability: 0.0, ..
+        } => Err("The flushing probability of a binomial mix must be positive.".to_string()),
+        _ => Ok(model),
+    }
+}
+
+pub fn parse_queue_model(s: &str) -> Result<QueueModel, String> {
+    let err = || {
+        format!(
+            "Invalid queue model \"{}\". Use mm1:MEAN_SERVICE_TIME or deterministic:SERVICE_TIME.",
+            s
+        )
+    };
+    let (model, time) = s.split_once(':').ok_or_else(err)?;
+    let time: f64 = time.parse().map_err(|_| err())?;
+    if time <= 0.0 {
+        return Err("The service time of a queue must be positive.".to_string());
+    }
+    match model {
+        "mm1" => Ok(QueueModel::MM1 {
+            mean_service_time: time,
+        }),
+        "deterministic" => Ok(QueueModel::Deterministic { service_time: time }),
+        _ => Err(err()),
+    }
+}
+
+pub fn parse_queue_location(s: &str) -> Result<QueueLocation, String> {
+    if s == "destination" {
+        return Ok(QueueLocation::Destination);
+    }
+    match s.strip_prefix("relays:").map(|count| count.parse::<u64>()) {
+        Some(Ok(count)) if count > 0 => Ok(QueueLocation::Relays { count }),
+        _ => Err(format!(
+            "Invalid queue location \"{}\". Use destination or relays:COUNT.",
+            s
+        )),
+    }
+}
+
+pub fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(format!("\"{}\" is not a probability between 0 and 1.", s)),
+    }
+}
+
+pub fn parse_traffic_model(s: &str) -> Result<TrafficModel, String> {
+    let err = || {
+        format!(
+            "Invalid traffic model \"{}\". Specify it using one of the following forms:
+    constant
+    poisson
+    onoff:SHAPE:MEAN_ON:MEAN_OFF
+    web:PAGE_SIZE:MEAN_THINK_TIME",
+            s
+        )
+    };
+
+    let splitted: Vec<_> = s.split(':').collect();
+    let model = match splitted[..] {
+        ["constant"] => TrafficModel::Constant,
+        ["poisson"] => TrafficModel::Poisson,
+        ["onoff", shape, mean_on, mean_off] => TrafficModel::OnOff {
+            shape: shape.parse().map_err(|_| err())?,
+            mean_on: mean_on.parse().map_err(|_| err())?,
+            mean_off: mean_off.parse().map_err(|_| err())?,
+        },
+        ["web", page_size, mean_think_time] => TrafficModel::Web {
+            page_size: page_size.parse().map_err(|_| err())?,
+            mean_think_time: mean_think_time.parse().map_err(|_| err())?,
+        },
+        _ => return Err(err()),
+    };
+    model.validate()?;
+    Ok(model)
+}
+
+pub fn parse_load_profile(s: &str) -> Result<LoadProfile, String> {
+    let err = || {
+        format!(
+            "Invalid load profile \"{}\". Specify it using one of the following forms:
+    diurnal:PERIOD:AMPLITUDE[:PEAK]
+    steps:TIME=RATE,TIME=RATE,...
+    file:PATH",
+            s
+        )
+    };
+
+    let profile = match s.split_once(':') {
+        Some(("diurnal", params)) => {
+            let params: Vec<f64> = params
+                .split(':')
+                .map(|x| x.parse().map_err(|_| err()))
+                .collect::<Result<_, _>>()?;
+            match params[..] {
+                [period, amplitude] => LoadProfile::Diurnal {
+                    period,
+                    amplitude,
+                    peak: period / 2.0,
+                },
+                [period, amplitude, peak] => LoadProfile::Diurnal {
+                    period,
+                    amplitude,
+                    peak,
+                },
+                _ => return Err(err()),
+            }
+        }
+        Some(("steps", steps)) => LoadProfile::Steps(
+            steps
+                .split(',')
+                .map(|step| {
+                    let (time, rate) = step.split_once('=').ok_or_else(err)?;
+                    Ok((
+                        time.parse().map_err(|_| err())?,
+                        rate.parse().map_err(|_| err())?,
+                    ))
+                })
+                .collect::<Result<_, String>>()?,
+        ),
+        Some(("file", path)) => return Ok(LoadProfile::File(PathBuf::from(path))),
+        _ => return Err(err()),
+    };
+    profile.make_load().map_err(|e| e.to_string())?;
+    Ok(profile)
 }
 
 /// A `Distribution` equivalent that is object-safe.
 ///
 /// See [https://stackoverflow.com/a/75007203] for source and explanation.
-pub trait ErasedDistribution<T> {
+/// It is `Send + Sync`, so that sources can be generated in parallel.
+pub trait ErasedDistribution<T>: Send + Sync {
     fn sample(&self, rng: &mut dyn rand::RngCore) -> T;
 }
 
-impl<T, D: Distribution<T> + ?Sized> ErasedDistribution<T> for D {
+impl<T, D: Distribution<T> + Send + Sync + ?Sized> ErasedDistribution<T> for D {
     fn sample(&self, rng: &mut dyn rand::RngCore) -> T {
         <Self as Distribution<T>>::sample(self, rng)
     }
@@ -221,7 +863,9 @@ impl<T: SampledValue> Distribution<T> for NormalAllowingIntegers<T> {
 /// A type that can be used as a result type from sampling any of our
 /// dynamically built distributions. It must allow to be (lossily) built from
 /// a sampled f64 value.
-pub trait SampledValue: FromStr + Clone + SampleUniform {
+pub trait SampledValue:
+    FromStr + fmt::Display + Clone + Send + Sync + SampleUniform<Sampler: Send + Sync>
+{
     fn from_f64(value: f64) -> Self;
 }
 
@@ -243,19 +887,49 @@ impl SampledValue for i64 {
     }
 }
 
-fn parse_distribution<T: SampledValue>(s: &str) -> Result<ParsedDistribution<T>, String> {
+pub fn parse_distribution<T: SampledValue>(s: &str) -> Result<ParsedDistribution<T>, String> {
     // common error
     let err = || {
         "Invalid distribution. Specify it using one of the following forms:
     constant:VALUE
     uniform:MIN:MAX
     normal:MEAN:DEV
-    normal:MEAN:DEV:MIN:MAX"
+    normal:MEAN:DEV:MIN:MAX
+    exponential:MEAN
+    lognormal:MU:SIGMA
+    pareto:SCALE:SHAPE
+    weibull:SCALE:SHAPE
+    gamma:SHAPE:SCALE
+    empirical:FILE
+    mixture:WEIGHT*DISTRIBUTION,WEIGHT*DISTRIBUTION,..."
             .to_string()
     };
 
+    if let Some(file) = s.strip_prefix("empirical:") {
+        return Ok(ParsedDistribution::Empirical {
+            file: PathBuf::from(file),
+        });
+    }
+    if let Some(components) = s.strip_prefix("mixture:") {
+        let components = components
+            .split(',')
+            .map(|component| {
+                let (weight, distribution) = component.split_once('*').ok_or_else(err)?;
+                let weight = weight.parse::<f64>().map_err(|_| err())?;
+                if distribution.starts_with("mixture:") {
+                    return Err("Mixtures cannot be nested.".to_string());
+                }
+                Ok((weight, parse_distribution::<T>(distribution)?))
+            })
+            .collect::<Result<_, String>>()?;
+        return Ok(ParsedDistribution::Mixture { components });
+    }
+
     let splitted: Vec<_> = s.split(':').collect();
 
+    // parameters of the distributions over real numbers
+    let float = |x: &str| x.parse::<f64>().map_err(|_| err());
+
     match splitted[..] {
         ["constant", value] => {
             let value: T = value.parse::<T>().map_err(|_| err())?;
@@ -293,12 +967,29 @@ fn parse_distribution<T: SampledValue>(s: &str) -> Result<ParsedDistribution<T>,
                 max: Some(max),
             })
         }
+        ["exponential", mean] => Ok(ParsedDistribution::Exponential { mean: float(mean)? }),
+        ["lognormal", mu, sigma] => Ok(ParsedDistribution::LogNormal {
+            mu: float(mu)?,
+            sigma: float(sigma)?,
+        }),
+        ["pareto", scale, shape] => Ok(ParsedDistribution::Pareto {
+            scale: float(scale)?,
+            shape: float(shape)?,
+        }),
+        ["weibull", scale, shape] => Ok(ParsedDistribution::Weibull {
+            scale: float(scale)?,
+            shape: float(shape)?,
+        }),
+        ["gamma", shape, scale] => Ok(ParsedDistribution::Gamma {
+            shape: float(shape)?,
+            scale: float(scale)?,
+        }),
         _ => return Err(err()),
     }
 }
 
 /// A set of parsed parameters for a probability distribution
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ParsedDistribution<T: SampledValue + 'static> {
     Constant {
         value: T,
@@ -313,6 +1004,69 @@ pub enum ParsedDistribution<T: SampledValue + 'static> {
         min: Option<f64>,
         max: Option<f64>,
     },
+    Exponential {
+        mean: f64,
+    },
+    /// The logarithm of the samples is normally distributed with mean `mu`
+    /// and standard deviation `sigma`
+    LogNormal {
+        mu: f64,
+        sigma: f64,
+    },
+    Pareto {
+        scale: f64,
+        shape: f64,
+    },
+    Weibull {
+        scale: f64,
+        shape: f64,
+    },
+    Gamma {
+        shape: f64,
+        scale: f64,
+    },
+    /// Samples from the observed values or histogram in a file, see
+    /// [`read_empirical`]
+    Empirical {
+        file: PathBuf,
+    },
+    /// Samples from one of the components, chosen by their weights
+    Mixture {
+        components: Vec<(f64, ParsedDistribution<T>)>,
+    },
+}
+
+/// Format the distribution like it is given on the command line
+impl<T: SampledValue> fmt::Display for ParsedDistribution<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Constant { value } => write!(f, "constant:{}", value),
+            Self::Uniform { min, max } => write!(f, "uniform:{}:{}", min, max),
+            Self::Normal {
+                mean,
+                dev,
+                min: Some(min),
+                max: Some(max),
+            } => write!(f, "normal:{}:{}:{}:{}", mean, dev, min, max),
+            Self::Normal { mean, dev, .. } => write!(f, "normal:{}:{}", mean, dev),
+            Self::Exponential { mean } => write!(f, "exponential:{}", mean),
+            Self::LogNormal { mu, sigma } => write!(f, "lognormal:{}:{}", mu, sigma),
+            Self::Pareto { scale, shape } => write!(f, "pareto:{}:{}", scale, shape),
+            Self::Weibull { scale, shape } => write!(f, "weibull:{}:{}", scale, shape),
+            Self::Gamma { shape, scale } => write!(f, "gamma:{}:{}", shape, scale),
+            Self::Empirical { file } => write!(f, "empirical:{}", file.display()),
+            Self::Mixture { components } => {
+                write!(f, "mixture:")?;
+                for (i, (weight, distribution)) in components.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}*{}", weight, distribution)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 impl<T: SampledValue + Copy + 'static> ParsedDistribution<T> {
@@ -331,6 +1085,226 @@ impl<T: SampledValue + Copy + 'static> ParsedDistribution<T> {
                 NormalAllowingIntegers::new(*mean, *dev, *min, *max)
                     .map_err(|e| format!("Error building normal distribution: {}", e))?,
             )),
+            Self::Exponential { mean } => {
+                if *mean <= 0.0 {
+                    return Err("The mean of an exponential distribution must be positive.".into());
+                }
+                Ok(Box::new(FromFloat::new(Exp::new(1.0 / *mean)?)))
+            }
+            Self::LogNormal { mu, sigma } => Ok(Box::new(FromFloat::new(
+                LogNormal::new(*mu, *sigma)
+                    .map_err(|e| format!("Error building log-normal distribution: {}", e))?,
+            ))),
+            Self::Pareto { scale, shape } => Ok(Box::new(FromFloat::new(
+                Pareto::new(*scale, *shape)
+                    .map_err(|e| format!("Error building Pareto distribution: {}", e))?,
+            ))),
+            Self::Weibull { scale, shape } => Ok(Box::new(FromFloat::new(
+                Weibull::new(*scale, *shape)
+                    .map_err(|e| format!("Error building Weibull distribution: {}", e))?,
+            ))),
+            Self::Gamma { shape, scale } => Ok(Box::new(FromFloat::new(
+                Gamma::new(*shape, *scale)
+                    .map_err(|e| format!("Error building gamma distribution: {}", e))?,
+            ))),
+            Self::Empirical { file } => Ok(Box::new(FromFloat::new(read_empirical(file)?))),
+            Self::Mixture { components } => {
+                let weights = WeightedIndex::new(components.iter().map(|(weight, _)| *weight))
+                    .map_err(|e| format!("Invalid weights of the mixture: {}", e))?;
+                let components = components
+                    .iter()
+                    .map(|(_, distribution)| distribution.make_distr())
+                    .collect::<Result<_, _>>()?;
+                Ok(Box::new(Mixture {
+                    weights,
+                    components,
+                }))
+            }
+        }
+    }
+
+    /// Make the paths of empirical distributions relative to the given directory
+    pub fn relative_to(self, base: &Path) -> ParsedDistribution<T> {
+        match self {
+            Self::Empirical { file } => Self::Empirical {
+                file: base.join(file),
+            },
+            Self::Mixture { components } => Self::Mixture {
+                components: components
+                    .into_iter()
+                    .map(|(weight, distribution)| (weight, distribution.relative_to(base)))
+                    .collect(),
+            },
+            distribution => distribution,
+        }
+    }
+}
+
+/// A distribution over real numbers fit into a distribution of any sampled
+/// value type
+pub struct FromFloat<T, D> {
+    distribution: D,
+    phantom: PhantomData<T>,
+}
+
+impl<T, D: Distribution<f64>> FromFloat<T, D> {
+    fn new(distribution: D) -> FromFloat<T, D> {
+        FromFloat {
+            distribution,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: SampledValue, D: Distribution<f64>> Distribution<T> for FromFloat<T, D> {
+    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> T {
+        T::from_f64(Distribution::sample(&self.distribution, rng))
+    }
+}
+
+/// An empirical distribution: a histogram of bins with weights. A sample is
+/// drawn uniformly from a bin chosen by weight.
+pub struct Empirical {
+    /// Lower and upper bound of each bin, which are equal for single values
+    bins: Vec<(f64, f64)>,
+    weights: WeightedIndex<f64>,
+}
+
+impl Distribution<f64> for Empirical {
+    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> f64 {
+        let (lower, upper) = self.bins[Distribution::sample(&self.weights, rng)];
+        if lower < upper {
+            rng.gen_range(lower..upper)
+        } else {
+            lower
+        }
+    }
+}
+
+/// Read an empirical distribution from a file. Each line contains either an
+/// observed value, a value and its weight, or the lower and upper bound of a
+/// histogram bin and its weight, separated by commas or whitespace. Empty
+/// lines and lines starting with `#` are ignored.
+pub fn read_empirical(
+    path: impl AsRef<Path>,
+) -> Result<Empirical, Box<dyn std::error::Error + Send + Sync>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Cannot read empirical distribution {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+
+    let mut bins = Vec::new();
+    let mut weights = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values: Vec<f64> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Invalid number in {}, line {}", path.display(), i + 1))?;
+        let (bin, weight) = match values[..] {
+            [value] => ((value, value), 1.0),
+            [value, weight] => ((value, value), weight),
+            [lower, upper, weight] if lower <= upper => ((lower, upper), weight),
+            _ => {
+                return Err(format!(
+                    "Invalid histogram bin in {}, line {}",
+                    path.display(),
+                    i + 1
+                )
+                .into())
+            }
+        };
+        bins.push(bin);
+        weights.push(weight);
+    }
+
+    let weights = WeightedIndex::new(weights)
+        .map_err(|e| format!("Invalid empirical distribution {}: {}", path.display(), e))?;
+    Ok(Empirical { bins, weights })
+}
+
+/// A weighted mixture of distributions
+pub struct Mixture<T> {
+    weights: WeightedIndex<f64>,
+    components: Vec<Box<dyn ErasedDistribution<T>>>,
+}
+
+impl<T> Distribution<T> for Mixture<T> {
+    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> T {
+        let component = &self.components[Distribution::sample(&self.weights, rng)];
+        Distribution::sample(&**component, rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distribution_roundtrip() {
+        for s in [
+            "constant:3",
+            "uniform:1:2.5",
+            "normal:10:2",
+            "normal:10:2:1:20",
+            "exponential:20",
+            "lognormal:9:1.5",
+            "pareto:5000:1.5",
+            "weibull:20:0.8",
+            "gamma:2:10",
+            "empirical:sizes.txt",
+            "mixture:0.9*lognormal:8:1,0.1*pareto:100000:1.5",
+        ] {
+            let distribution = parse_distribution::<f64>(s).unwrap();
+            assert_eq!(distribution.to_string(), s);
+        }
+        assert!(parse_distribution::<f64>("mixture:1*mixture:1*constant:1").is_err());
+        assert!(parse_distribution::<f64>("pareto:1").is_err());
+    }
+
+    #[test]
+    fn zero_counts() {
+        let generate = |params: &[&str]| {
+            let mut arguments = vec![
+                "ppcalc",
+                "generate",
+                "--sources=1",
+                "--destination-selection=uniform",
+                "--bandwidth=constant:1",
+                "--stream-length=constant:1000",
+                "--source-wait=constant:0",
+                "--network-delay=constant:10",
+                "trace.csv",
+            ];
+            arguments.extend_from_slice(params);
+            Cli::try_parse_from(arguments)
+        };
+        assert!(generate(&["--destinations=1", "--message-size=1"]).is_ok());
+        assert!(generate(&["--destinations=0"]).is_err());
+        assert!(generate(&["--destinations=1", "--message-size=0"]).is_err());
+    }
+
+    #[test]
+    fn mix_intervals() {
+        assert!(parse_mix_model("timed:0.000001").is_ok());
+        assert!(parse_mix_model("binomial:0.5:0.1").is_ok());
+        for s in [
+            "timed:0",
+            "timed:0.0000001",
+            "timed:-1",
+            "timed:NaN",
+            "binomial:1e-9:0.5",
+        ] {
+            assert!(parse_mix_model(s).is_err(), "{}", s);
         }
     }
 }
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use anyhow::anyhow;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use time::{Duration, PrimitiveDateTime};
+
+use ppcalc_metric::{DestinationId, Direction, SourceId};
+
+use crate::cli::{ErasedDistribution, ParsedDistribution};
+use crate::trace::{PreNetworkTraceEntry, SourceTrace};
+
+/// A kind of dummy messages that the sources send in addition to their real ones
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CoverTraffic {
+    /// Padding to a constant rate: while a session is active, a dummy is sent
+    /// at the end of each interval [ms] without a real message. The network
+    /// drops the padding.
+    Constant { interval: f64 },
+    /// Adaptive padding like WTF-PAD: whenever a session has been silent for
+    /// a time drawn from the distribution [ms], a dummy is sent. The network
+    /// drops the padding.
+    Adaptive(ParsedDistribution<f64>),
+    /// Loop cover messages, sent throughout the lifetime of each source with
+    /// intervals drawn from the distribution [ms]. They return to their source
+    /// and thus never arrive at a destination.
+    Loop(ParsedDistribution<f64>),
+    /// Drop cover messages, sent like loop cover messages, but to uniformly
+    /// chosen destinations that discard them
+    Drop(ParsedDistribution<f64>),
+}
+
+/// Format the cover traffic like it is given on the command line
+impl fmt::Display for CoverTraffic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverTraffic::Constant { interval } => write!(f, "constant:{}", interval),
+            CoverTraffic::Adaptive(distribution) => write!(f, "adaptive:{}", distribution),
+            CoverTraffic::Loop(distribution) => write!(f, "loop:{}", distribution),
+            CoverTraffic::Drop(distribution) => write!(f, "drop:{}", distribution),
+        }
+    }
+}
+
+/// The real messages of a session, together with its destination
+struct Session {
+    destination_id: DestinationId,
+    timestamps: Vec<PrimitiveDateTime>,
+}
+
+/// Generate the dummy messages of the given cover traffic for the source
+/// traces, ordered by time.
+///
+/// Dummies are associated with a session of their source. Those that never
+/// arrive at a destination keep the destination of their session.
+pub fn cover_traffic(
+    source_traces: &[SourceTrace],
+    source_destination_map: &HashMap<(SourceId, u64), DestinationId>,
+    cover: &[CoverTraffic],
+    number_of_destinations: u64,
+    rng: &mut impl Rng,
+) -> anyhow::Result<Vec<PreNetworkTraceEntry>> {
+    let mut sessions: BTreeMap<(SourceId, u64), Session> = BTreeMap::new();
+    for trace in source_traces.iter().filter(|x| !x.timestamps.is_empty()) {
+        let key = (trace.source_id, trace.session);
+        let mut timestamps = trace.timestamps.clone();
+        timestamps.sort_unstable();
+        sessions.insert(
+            key,
+            Session {
+                destination_id: source_destination_map[&key],
+                timestamps,
+            },
+        );
+    }
+
+    let mut dummies = Vec::new();
+    let mut add_dummy =
+        |(source_id, session): (SourceId, u64), source_timestamp, destination_id, dropped| {
+            dummies.push(PreNetworkTraceEntry {
+                source_id,
+                session,
+                source_timestamp,
+                destination_id,
+                dummy: true,
+                dropped,
+                direction: Direction::Upstream,
+            })
+        };
+
+    for kind in cover {
+        match kind {
+            CoverTraffic::Constant { interval } => {
+                let interval = Duration::seconds_f64(interval / 1000.0);
+                for (key, session) in sessions.iter() {
+                    let timestamps = &session.timestamps;
+                    let mut next_real = 0;
+                    let mut slot = timestamps[0] + interval;
+                    while slot <= *timestamps.last().unwrap() {
+                        // was there a real message in this interval?
+                        let before = next_real;
+                        while next_real < timestamps.len() && timestamps[next_real] <= slot {
+                            next_real += 1;
+                        }
+                        if next_real == before {
+                            add_dummy(*key, slot, session.destination_id, true);
+                        }
+                        slot += interval;
+                    }
+                }
+            }
+            CoverTraffic::Adaptive(distribution) => {
+                let distr = make_distr(distribution)?;
+                for (key, session) in sessions.iter() {
+                    for gap in session.timestamps.windows(2) {
+                        let mut time = gap[0] + sample_interval(&*distr, rng);
+                        while time < gap[1] {
+                            add_dummy(*key, time, session.destination_id, true);
+                            time += sample_interval(&*distr, rng);
+                        }
+                    }
+                }
+            }
+            CoverTraffic::Loop(distribution) | CoverTraffic::Drop(distribution) => {
+                let is_loop = matches!(kind, CoverTraffic::Loop(_));
+                let distr = make_distr(distribution)?;
+
+                let mut sources: BTreeMap<SourceId, Vec<(u64, &Session)>> = BTreeMap::new();
+                for ((source_id, session_id), session) in sessions.iter() {
+                    sources
+                        .entry(*source_id)
+                        .or_default()
+                        .push((*session_id, session));
+                }
+
+                for (source_id, source_sessions) in sources {
+                    let first = source_sessions
+                        .iter()
+                        .map(|(_, session)| session.timestamps[0])
+                        .min()
+                        .unwrap();
+                    let last = source_sessions
+                        .iter()
+                        .map(|(_, session)| *session.timestamps.last().unwrap())
+                        .max()
+                        .unwrap();
+
+                    let mut time = first + sample_interval(&*distr, rng);
+                    while time <= last {
+                        // the latest session that started before the dummy
+                        let (session_id, session) = source_sessions
+                            .iter()
+                            .filter(|(_, session)| session.timestamps[0] <= time)
+                            .max_by_key(|(_, session)| session.timestamps[0])
+                            .unwrap();
+                        let destination_id = match is_loop {
+                            true => session.destination_id,
+                            false => DestinationId::new(rng.gen_range(0..number_of_destinations)),
+                        };
+                        add_dummy((source_id, *session_id), time, destination_id, is_loop);
+                        time += sample_interval(&*distr, rng);
+                    }
+                }
+            }
+        }
+    }
+
+    dummies.sort_by_key(|entry| entry.source_timestamp);
+    Ok(dummies)
+}
+
+fn make_distr(
+    distribution: &ParsedDistribution<f64>,
+) -> anyhow::Result<Box<dyn ErasedDistribution<f64>>> {
+    distribution.make_distr().map_err(|e| anyhow!(e))
+}
+
+/// The shortest interval between dummies of a source, which intervals drawn
+/// from a distribution are raised to [ms]
+const MIN_INTERVAL: f64 = 0.001;
+
+/// Draw the time until the next dummy, which is at least [`MIN_INTERVAL`]
+fn sample_interval(distr: &dyn ErasedDistribution<f64>, rng: &mut impl Rng) -> Duration {
+    let interval = distr.sample(rng).max(MIN_INTERVAL);
+    Duration::seconds_f64(interval / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_distribution;
+    use crate::rng::{self, Stage};
+    use time::macros::datetime;
+
+    #[test]
+    fn non_positive_intervals() {
+        // half of the intervals drawn are negative, which are raised to the minimum
+        let epoch = datetime!(1970-01-01 0:00);
+        let source_traces = vec![SourceTrace {
+            source_id: SourceId::new(0),
+            session: 0,
+            timestamps: vec![epoch, epoch + Duration::milliseconds(100)],
+        }];
+        let map = [((SourceId::new(0), 0), DestinationId::new(0))]
+            .into_iter()
+            .collect();
+        let cover = [CoverTraffic::Loop(
+            parse_distribution("normal:1:1").unwrap(),
+        )];
+        let dummies = cover_traffic(
+            &source_traces,
+            &map,
+            &cover,
+            1,
+            &mut rng::stage_rng(1, Stage::Cover),
+        )
+        .unwrap();
+        assert!(dummies.len() > 50);
+        assert!(dummies.windows(2).all(|pair| {
+            pair[1].source_timestamp - pair[0].source_timestamp >= Duration::microseconds(1)
+        }));
+    }
+}
@@ -1,52 +1,129 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
 
-use rand::{distributions::Uniform, prelude::Distribution};
+use anyhow::Context;
+use rand::distributions::{Uniform, WeightedIndex};
+use rand::{prelude::Distribution, Rng};
+use rand_distr::Zipf;
 use serde::{Deserialize, Serialize};
 
 use ppcalc_metric::{DestinationId, SourceId};
 
+use crate::cli::ParsedDistribution;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum DestinationSelectionType {
     Uniform,
     RoundRobin,
+    /// A normal distribution centered on the middle of the destination IDs,
+    /// covering all of them within three standard deviations
     Normal,
+    /// Destination IDs drawn from an arbitrary distribution
+    Distribution(ParsedDistribution<i64>),
+    /// Zipf-distributed popularity, with destination 0 being the most popular one
+    Zipf {
+        exponent: f64,
+    },
+    /// Popularity weights read from a file, one weight per line for each destination
+    Weights {
+        file: PathBuf,
+    },
 }
 
-pub fn destination_selection(
+/// Format the selection type like it is given on the command line
+impl fmt::Display for DestinationSelectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DestinationSelectionType::Uniform => write!(f, "uniform"),
+            DestinationSelectionType::RoundRobin => write!(f, "roundrobin"),
+            DestinationSelectionType::Normal => write!(f, "normal"),
+            DestinationSelectionType::Distribution(distribution) => write!(f, "{}", distribution),
+            DestinationSelectionType::Zipf { exponent } => write!(f, "zipf:{}", exponent),
+            DestinationSelectionType::Weights { file } => write!(f, "weights:{}", file.display()),
+        }
+    }
+}
+
+/// Choose a destination for each of the given keys, e.g. sources or their sessions
+pub fn destination_selection<K: Eq + Hash>(
     selection_type: &DestinationSelectionType,
     number_of_destinations: u64,
-    source_id_list: Vec<SourceId>,
-) -> HashMap<SourceId, DestinationId> {
-    match selection_type {
+    source_id_list: Vec<K>,
+    rng: &mut impl Rng,
+) -> anyhow::Result<HashMap<K, DestinationId>> {
+    Ok(match selection_type {
         DestinationSelectionType::Uniform => {
-            uniform_destination_selection(number_of_destinations, source_id_list)
+            uniform_destination_selection(number_of_destinations, source_id_list, rng)
         }
         DestinationSelectionType::RoundRobin => {
             round_robin_destination_selection(number_of_destinations, source_id_list)
         }
         DestinationSelectionType::Normal => {
-            normal_destination_selection(number_of_destinations, source_id_list)
+            normal_destination_selection(number_of_destinations, source_id_list, rng)?
+        }
+        DestinationSelectionType::Distribution(distribution) => distribution_destination_selection(
+            distribution,
+            number_of_destinations,
+            source_id_list,
+            rng,
+        )?,
+        DestinationSelectionType::Zipf { exponent } => {
+            zipf_destination_selection(*exponent, number_of_destinations, source_id_list, rng)?
+        }
+        DestinationSelectionType::Weights { file } => {
+            let weights = read_popularity_weights(file, number_of_destinations)?;
+            weighted_destination_selection(&weights, source_id_list, rng)?
+        }
+    })
+}
+
+/// Let later sessions of a source return to the destination of the previous
+/// session with the given probability, replacing their own choice.
+pub fn apply_stickiness(
+    session_destination_map: &mut HashMap<(SourceId, u64), DestinationId>,
+    stickiness: f64,
+    rng: &mut impl Rng,
+) {
+    if stickiness <= 0.0 {
+        return;
+    }
+    // go through the sessions in order, so the result is reproducible
+    let mut sessions: Vec<_> = session_destination_map.keys().copied().collect();
+    sessions.sort();
+    for (source_id, session) in sessions {
+        let Some(previous) = session
+            .checked_sub(1)
+            .and_then(|previous| session_destination_map.get(&(source_id, previous)))
+            .copied()
+        else {
+            continue;
+        };
+        if rng.gen_bool(stickiness) {
+            session_destination_map.insert((source_id, session), previous);
         }
     }
 }
 
-pub fn uniform_destination_selection(
+pub fn uniform_destination_selection<K: Eq + Hash>(
     number_of_destinations: u64,
-    source_id_list: Vec<SourceId>,
-) -> HashMap<SourceId, DestinationId> {
+    source_id_list: Vec<K>,
+    rng: &mut impl Rng,
+) -> HashMap<K, DestinationId> {
     let mut map = HashMap::new();
     let distr = Uniform::from(0..number_of_destinations);
-    let mut rng = rand::thread_rng();
     for source_id in source_id_list {
-        map.insert(source_id, DestinationId::new(distr.sample(&mut rng)));
+        map.insert(source_id, DestinationId::new(distr.sample(rng)));
     }
     map
 }
 
-pub fn round_robin_destination_selection(
+pub fn round_robin_destination_selection<K: Eq + Hash>(
     number_of_destinations: u64,
-    source_id_list: Vec<SourceId>,
-) -> HashMap<SourceId, DestinationId> {
+    source_id_list: Vec<K>,
+) -> HashMap<K, DestinationId> {
     let mut map = HashMap::new();
     for (i, source_id) in source_id_list.into_iter().enumerate() {
         map.insert(
@@ -57,10 +134,171 @@ pub fn round_robin_destination_selection(
     map
 }
 
-// TODO
-pub fn normal_destination_selection(
-    _number_of_destinations: u64,
-    _source_id_list: Vec<SourceId>,
-) -> HashMap<SourceId, DestinationId> {
-    unimplemented!("Choosing destinations based on a normal distribution isn't implemented yet.")
+pub fn normal_destination_selection<K: Eq + Hash>(
+    number_of_destinations: u64,
+    source_id_list: Vec<K>,
+    rng: &mut impl Rng,
+) -> anyhow::Result<HashMap<K, DestinationId>> {
+    // samples are rounded up to integer IDs, so shift the mean by half a destination
+    let distribution = ParsedDistribution::Normal {
+        mean: number_of_destinations as f64 / 2.0 - 1.0,
+        dev: (number_of_destinations as f64 / 6.0).max(f64::MIN_POSITIVE),
+        min: None,
+        max: None,
+    };
+    distribution_destination_selection(&distribution, number_of_destinations, source_id_list, rng)
+}
+
+/// Choose the destination ID of each source from a distribution.
+///
+/// Samples outside the range of destination IDs are drawn again.
+pub fn distribution_destination_selection<K: Eq + Hash>(
+    distribution: &ParsedDistribution<i64>,
+    number_of_destinations: u64,
+    source_id_list: Vec<K>,
+    rng: &mut impl Rng,
+) -> anyhow::Result<HashMap<K, DestinationId>> {
+    const MAX_ATTEMPTS: usize = 1000;
+
+    let distr = distribution.make_distr().map_err(|e| anyhow::anyhow!(e))?;
+    let mut map = HashMap::new();
+    for source_id in source_id_list {
+        let destination = (0..MAX_ATTEMPTS)
+            .map(|_| distr.sample(rng))
+            .find(|x| (0..number_of_destinations as i64).contains(x))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "The destination selection distribution does not yield destination IDs between 0 and {}.",
+                    number_of_destinations as i64 - 1
+                )
+            })?;
+        map.insert(source_id, DestinationId::new(destination as u64));
+    }
+    Ok(map)
+}
+
+/// Choose destinations with Zipf-distributed popularity.
+///
+/// The destination with ID `k` has rank `k + 1`, so it is chosen with a
+/// probability proportional to `(k + 1)^(-exponent)`.
+pub fn zipf_destination_selection<K: Eq + Hash>(
+    exponent: f64,
+    number_of_destinations: u64,
+    source_id_list: Vec<K>,
+    rng: &mut impl Rng,
+) -> anyhow::Result<HashMap<K, DestinationId>> {
+    let distr = Zipf::new(number_of_destinations, exponent)
+        .map_err(|e| anyhow::anyhow!("Invalid Zipf distribution: {}", e))?;
+    let mut map = HashMap::new();
+    for source_id in source_id_list {
+        let rank: f64 = distr.sample(rng);
+        map.insert(source_id, DestinationId::new(rank as u64 - 1));
+    }
+    Ok(map)
+}
+
+/// Choose destinations with probabilities proportional to the given weights,
+/// one for each destination ID.
+pub fn weighted_destination_selection<K: Eq + Hash>(
+    weights: &[f64],
+    source_id_list: Vec<K>,
+    rng: &mut impl Rng,
+) -> anyhow::Result<HashMap<K, DestinationId>> {
+    let distr = WeightedIndex::new(weights)
+        .map_err(|e| anyhow::anyhow!("Invalid destination weights: {}", e))?;
+    let mut map = HashMap::new();
+    for source_id in source_id_list {
+        map.insert(source_id, DestinationId::new(distr.sample(rng) as u64));
+    }
+    Ok(map)
+}
+
+/// Read the popularity weights of the first `number_of_destinations`
+/// destinations from a file containing one weight per line.
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn read_popularity_weights(
+    path: impl AsRef<Path>,
+    number_of_destinations: u64,
+) -> anyhow::Result<Vec<f64>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read destination weights from {}", path.display()))?;
+
+    let weights = content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .take(number_of_destinations as usize)
+        .map(|line| {
+            line.parse::<f64>()
+                .with_context(|| format!("Invalid weight \"{}\" in {}", line, path.display()))
+        })
+        .collect::<anyhow::Result<Vec<f64>>>()?;
+
+    if (weights.len() as u64) < number_of_destinations {
+        anyhow::bail!(
+            "{} contains only {} weights, but there are {} destinations.",
+            path.display(),
+            weights.len(),
+            number_of_destinations
+        );
+    }
+    Ok(weights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{self, Stage};
+
+    /// How often each destination is chosen for 10000 keys
+    fn counts(selection: &DestinationSelectionType, number_of_destinations: u64) -> Vec<usize> {
+        let mut rng = rng::stage_rng(7, Stage::Destinations);
+        let map = destination_selection(
+            selection,
+            number_of_destinations,
+            (0..10000).collect(),
+            &mut rng,
+        )
+        .unwrap();
+        let mut counts = vec![0; number_of_destinations as usize];
+        for destination in map.values() {
+            counts[destination.to_num() as usize] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn zipf() {
+        let counts = counts(&DestinationSelectionType::Zipf { exponent: 1.0 }, 5);
+        // destination k is chosen with a probability proportional to 1 / (k + 1)
+        assert!(counts.windows(2).all(|pair| pair[0] > pair[1]));
+        let ratio = counts[0] as f64 / counts[1] as f64;
+        assert!((1.8..2.2).contains(&ratio));
+    }
+
+    #[test]
+    fn weights_file() {
+        let path = std::env::temp_dir().join("ppcalc_test_weights.txt");
+        fs::write(&path, "# popularity\n1\n\n0\n3\n5\n").unwrap();
+
+        // only the weights of the existing destinations are used
+        let file = DestinationSelectionType::Weights { file: path.clone() };
+        let counts = counts(&file, 3);
+        assert_eq!(counts[1], 0);
+        let ratio = counts[2] as f64 / counts[0] as f64;
+        assert!((2.7..3.3).contains(&ratio));
+
+        assert!(read_popularity_weights(&path, 5).is_err());
+    }
+
+    #[test]
+    fn normal() {
+        let counts = counts(&DestinationSelectionType::Normal, 9);
+        // the middle destination is the most popular one
+        assert!(counts.iter().all(|count| *count > 0));
+        assert_eq!(counts.iter().max(), Some(&counts[4]));
+        assert!(counts[0] < counts[4] / 4 && counts[8] < counts[4] / 4);
+    }
 }
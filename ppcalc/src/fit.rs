@@ -0,0 +1,481 @@
+//! Fit the parameters of `generate` to an existing trace, so that a synthetic
+//! look-alike of the trace can be generated and shared instead.
+//!
+//! The sources are reconstructed like for reusing them from a trace. Each
+//! session is taken to send at a constant rate between its first and last
+//! message, which gives its bandwidth and start time. The observed values
+//! are saved as empirical distributions next to the scenario file.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use time::PrimitiveDateTime;
+
+use ppcalc_metric::SourceId;
+
+use crate::cli::{self, FitArgs, ParsedDistribution, SampledValue};
+use crate::destination::DestinationSelectionType;
+use crate::network::QueueLocation;
+use crate::scenario::{Destinations, Network, Population, Scenario};
+use crate::source::TrafficModel;
+use crate::trace;
+
+/// Maximum number of lines of an empirical distribution file. More distinct
+/// values are grouped into histogram bins of equal counts.
+const MAX_BINS: usize = 1000;
+
+pub fn run(args: FitArgs) -> anyhow::Result<()> {
+    let source_traces = trace::read_sources_from_trace(&args.input).map_err(|e| anyhow!(e))?;
+    let source_destination_map = trace::read_destinations(&args.input).map_err(|e| anyhow!(e))?;
+    let delays = trace::read_delays_from_trace(&args.input).map_err(|e| anyhow!(e))?;
+    if source_traces.is_empty() {
+        bail!("The trace contains no messages of sources.");
+    }
+
+    let classes_path = match args.classes {
+        Some(ref path) => Some(path.clone()),
+        None => Some(trace::classes_path_for_trace(&args.input)).filter(|path| path.exists()),
+    };
+    let classes = match classes_path {
+        Some(path) => trace::read_classes(path).map_err(|e| anyhow!(e))?,
+        None => HashMap::new(),
+    };
+
+    // the sessions of each source in the order they were sent
+    let mut sources: BTreeMap<SourceId, Vec<Session>> = BTreeMap::new();
+    for source_trace in source_traces.iter() {
+        sources
+            .entry(source_trace.source_id)
+            .or_default()
+            .push(Session::new(&source_trace.timestamps));
+    }
+    for sessions in sources.values_mut() {
+        sessions.sort_by_key(|x| x.start);
+    }
+
+    // the trace starts when the first session does
+    let origin = sources
+        .values()
+        .map(|sessions| sessions[0].start)
+        .min()
+        .unwrap();
+
+    let mut observations: BTreeMap<&str, Observations> = BTreeMap::new();
+    for (source_id, sessions) in sources.iter() {
+        let class = classes
+            .get(source_id)
+            .map(|x| x.as_str())
+            .unwrap_or("default");
+        observations
+            .entry(class)
+            .or_default()
+            .add(sessions, origin, args.message_size);
+    }
+
+    let mut populations = Vec::with_capacity(observations.len());
+    for (name, observations) in observations {
+        if observations.bandwidths.is_empty() {
+            bail!(
+                "No session of population \"{}\" sends more than one message, so its bandwidth is unknown.",
+                name
+            );
+        }
+        let file = |field: &str| format!("{}.{}", name, field);
+        populations.push(Population {
+            name: name.to_string(),
+            num_sources: observations.num_sources,
+            bandwidth: write_distribution_file(
+                &args.output,
+                &file("bandwidth"),
+                &observations.bandwidths,
+            )?,
+            stream_length: write_distribution_file(
+                &args.output,
+                &file("stream_length"),
+                &observations.stream_lengths,
+            )?,
+            source_wait: write_distribution_file(
+                &args.output,
+                &file("source_wait"),
+                &observations.source_waits,
+            )?,
+            traffic_model: TrafficModel::Constant,
+            sessions_per_source: write_distribution_file(
+                &args.output,
+                &file("sessions_per_source"),
+                &observations.sessions,
+            )?,
+            session_interval: write_distribution_file(
+                &args.output,
+                &file("session_interval"),
+                &observations.session_intervals,
+            )?,
+            destination_selection: None,
+            load: None,
+        });
+    }
+
+    // the popularity of the destinations, by the sessions they received
+    let num_destinations = source_destination_map
+        .values()
+        .map(|x| x.to_num() + 1)
+        .max()
+        .unwrap();
+    let mut popularity = vec![0u64; num_destinations as usize];
+    for destination_id in source_destination_map.values() {
+        popularity[destination_id.to_num() as usize] += 1;
+    }
+    let weights_path = sidecar_path(&args.output, "destinations");
+    let mut weights = String::new();
+    for count in popularity.iter() {
+        writeln!(weights, "{}", count)?;
+    }
+    fs::write(&weights_path, weights)?;
+
+    let stickiness = estimate_stickiness(&sources, &source_destination_map, &popularity);
+
+    let delays: Vec<f64> = delays
+        .values()
+        .flatten()
+        .map(|x| x.as_seconds_f64() * 1000.0)
+        .collect();
+
+    let scenario = Scenario {
+        message_size: args.message_size,
+        seed: None,
+        populations,
+        destinations: Destinations {
+            count: num_destinations,
+            selection: DestinationSelectionType::Weights {
+                file: file_name(&weights_path),
+            },
+            stickiness,
+            responses: None,
+        },
+        network: Network {
+            delay: write_distribution_file(&args.output, "delay", &delays)?,
+            path_latency: None,
+            destination_latencies: None,
+            fifo: false,
+            queue: None,
+            queue_location: QueueLocation::Destination,
+            cover: Vec::new(),
+            mix: None,
+            loss: 0.0,
+            retransmission_timeout: None,
+            tor: None,
+        },
+    };
+    scenario.write(&args.output)?;
+
+    println!(
+        "Fitted {} sources in {} population(s), {} sessions and {} destinations.",
+        scenario.num_sources(),
+        scenario.populations.len(),
+        source_traces.len(),
+        num_destinations
+    );
+    println!("Saved the scenario to {}.", args.output.display());
+
+    Ok(())
+}
+
+/// A session of a source, assuming that it sends at a constant rate
+struct Session {
+    /// When the session started, one inter-message delay before its first message
+    start: PrimitiveDateTime,
+    /// When the session sent its last message
+    end: PrimitiveDateTime,
+    num_messages: u64,
+    /// Time between two messages [ms], if the session sent more than one
+    inter_message_delay: Option<f64>,
+}
+
+impl Session {
+    fn new(timestamps: &[PrimitiveDateTime]) -> Session {
+        let first = timestamps[0];
+        let end = *timestamps.last().unwrap();
+        let num_messages = timestamps.len() as u64;
+        let inter_message_delay = (num_messages > 1)
+            .then(|| (end - first).as_seconds_f64() * 1000.0 / (num_messages - 1) as f64);
+        Session {
+            start: first - time::Duration::seconds_f64(inter_message_delay.unwrap_or(0.0) / 1000.0),
+            end,
+            num_messages,
+            inter_message_delay,
+        }
+    }
+}
+
+/// The observed values of the parameters of a population
+#[derive(Default)]
+struct Observations {
+    num_sources: u64,
+    /// Mbit/s
+    bandwidths: Vec<f64>,
+    /// B
+    stream_lengths: Vec<f64>,
+    /// ms
+    source_waits: Vec<f64>,
+    sessions: Vec<f64>,
+    /// ms
+    session_intervals: Vec<f64>,
+}
+
+impl Observations {
+    fn add(&mut self, sessions: &[Session], origin: PrimitiveDateTime, message_size: u64) {
+        self.num_sources += 1;
+        self.sessions.push(sessions.len() as f64);
+        self.source_waits
+            .push((sessions[0].start - origin).as_seconds_f64() * 1000.0);
+
+        for (i, session) in sessions.iter().enumerate() {
+            self.stream_lengths
+                .push((session.num_messages * message_size) as f64);
+            if let Some(imd) = session.inter_message_delay.filter(|x| *x > 0.0) {
+                // the inverse of the conversion in `generate`
+                let bandwidth = message_size as f64 / (imd * 1000.0); // B/µs
+                self.bandwidths
+                    .push(bandwidth * (8.0 * 1000.0 * 1000.0) / (1024.0 * 1024.0));
+            }
+            if i > 0 {
+                let interval = session.start - sessions[i - 1].end;
+                self.session_intervals
+                    .push(interval.as_seconds_f64().max(0.0) * 1000.0);
+            }
+        }
+    }
+}
+
+/// Estimate the probability that a session goes to the destination of the
+/// previous one, beyond what the popularity of the destinations explains
+fn estimate_stickiness(
+    sources: &BTreeMap<SourceId, Vec<Session>>,
+    source_destination_map: &HashMap<(SourceId, u64), ppcalc_metric::DestinationId>,
+    popularity: &[u64],
+) -> f64 {
+    // the sessions are numbered in the order they were sent
+    let mut pairs = 0;
+    let mut repeated = 0;
+    for (&source_id, sessions) in sources.iter() {
+        for session in 1..sessions.len() as u64 {
+            let previous = source_destination_map.get(&(source_id, session - 1));
+            let current = source_destination_map.get(&(source_id, session));
+            if let (Some(previous), Some(current)) = (previous, current) {
+                pairs += 1;
+                if previous == current {
+                    repeated += 1;
+                }
+            }
+        }
+    }
+
+    // probability that two independently chosen destinations are the same
+    let total: u64 = popularity.iter().sum();
+    let coincidence: f64 = popularity
+        .iter()
+        .map(|&x| (x as f64 / total as f64).powi(2))
+        .sum();
+    if pairs == 0 || coincidence >= 1.0 {
+        return 0.0;
+    }
+    let observed = repeated as f64 / pairs as f64;
+    ((observed - coincidence) / (1.0 - coincidence)).clamp(0.0, 1.0)
+}
+
+/// Get the path of a file next to the scenario, e.g. `scenario.toml.delay.txt`
+fn sidecar_path(scenario_path: &Path, name: &str) -> PathBuf {
+    let mut path = scenario_path.as_os_str().to_owned();
+    path.push(format!(".{}.txt", name));
+    path.into()
+}
+
+/// The name of a file next to the scenario, which it refers to relatively
+fn file_name(path: &Path) -> PathBuf {
+    PathBuf::from(path.file_name().unwrap())
+}
+
+/// Describe the observed values by a distribution: a constant if they are
+/// all the same, or else an empirical distribution saved next to the scenario
+fn write_distribution_file<T: SampledValue + Copy>(
+    scenario_path: &Path,
+    name: &str,
+    values: &[f64],
+) -> anyhow::Result<ParsedDistribution<T>> {
+    let first = values.first().copied().unwrap_or(0.0);
+    if values.iter().all(|x| *x == first) {
+        // integer parameters cannot be fractional constants
+        if let Ok(distribution) = cli::parse_distribution::<T>(&format!("constant:{}", first)) {
+            return Ok(distribution);
+        }
+    }
+
+    let path = sidecar_path(scenario_path, name);
+    let mut content = String::new();
+    for (lower, upper, weight) in histogram(values) {
+        if lower == upper {
+            writeln!(content, "{},{}", lower, weight)?;
+        } else {
+            writeln!(content, "{},{},{}", lower, upper, weight)?;
+        }
+    }
+    fs::write(&path, content)?;
+
+    Ok(ParsedDistribution::Empirical {
+        file: file_name(&path),
+    })
+}
+
+/// Group values into at most [`MAX_BINS`] bins of (lower, upper, weight).
+/// Bins of a single value have equal bounds.
+fn histogram(values: &[f64]) -> Vec<(f64, f64, usize)> {
+    let mut values = values.to_vec();
+    values.sort_unstable_by(|a, b| a.total_cmp(b));
+
+    let mut distinct: Vec<(f64, usize)> = Vec::new();
+    for value in values.iter() {
+        match distinct.last_mut() {
+            Some((last, count)) if last == value => *count += 1,
+            _ => distinct.push((*value, 1)),
+        }
+    }
+    if distinct.len() <= MAX_BINS {
+        return distinct
+            .into_iter()
+            .map(|(value, count)| (value, value, count))
+            .collect();
+    }
+
+    // bins of (about) equal counts, each reaching up to the next one
+    let chunk_size = values.len().div_ceil(MAX_BINS);
+    let chunks: Vec<&[f64]> = values.chunks(chunk_size).collect();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let upper = match chunks.get(i + 1) {
+                Some(next) => next[0],
+                None => *chunk.last().unwrap(),
+            };
+            (chunk[0], upper, chunk.len())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Cli, Commands};
+    use crate::rng;
+    use clap::Parser;
+
+    /// Read the values of an empirical distribution file written by `fit`
+    fn read_sidecar(path: &Path) -> Vec<f64> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = std::env::temp_dir().join("ppcalc_test_fit");
+        fs::create_dir_all(&dir).unwrap();
+        let trace_path = dir.join("trace.csv");
+        let scenario_path = dir.join("scenario.toml");
+
+        // sessions at 1 B/µs, so that the messages are sent exactly 514 µs apart
+        let arguments = [
+            "ppcalc",
+            "generate",
+            "--seed",
+            "42",
+            "--sources",
+            "20",
+            "--destinations",
+            "5",
+            "--destination-selection",
+            "uniform",
+            "--destination-stickiness",
+            "1",
+            "--bandwidth",
+            "constant:7.62939453125",
+            "--stream-length",
+            "constant:5140",
+            "--source-wait",
+            "uniform:0:10000",
+            "--sessions-per-source",
+            "constant:3",
+            "--session-interval",
+            "constant:1000",
+            "--network-delay",
+            "uniform:10:100",
+            trace_path.to_str().unwrap(),
+        ];
+        let Commands::Generate(args) = Cli::try_parse_from(arguments).unwrap().command else {
+            unreachable!()
+        };
+        crate::generate::run(*args).unwrap();
+        let arguments = [
+            "ppcalc",
+            "fit",
+            "--output",
+            scenario_path.to_str().unwrap(),
+            trace_path.to_str().unwrap(),
+        ];
+        let Commands::Fit(args) = Cli::try_parse_from(arguments).unwrap().command else {
+            unreachable!()
+        };
+        run(args).unwrap();
+
+        // the session parameters are recovered
+        let scenario = Scenario::read(&scenario_path).unwrap();
+        assert_eq!(scenario.num_sources(), 20);
+        assert_eq!(scenario.populations.len(), 1);
+        let population = &scenario.populations[0];
+        assert_eq!(population.name, "default");
+        assert_eq!(population.stream_length.to_string(), "constant:5140");
+        assert_eq!(population.sessions_per_source.to_string(), "constant:3");
+        assert_eq!(population.session_interval.to_string(), "constant:1000");
+        let bandwidth = population.bandwidth.make_distr().unwrap();
+        let mut rng = rng::stage_rng(42, rng::Stage::Sources);
+        for _ in 0..100 {
+            assert!((bandwidth.sample(&mut rng) - 7.62939453125).abs() < 1e-6);
+        }
+
+        // the values that vary are saved next to the scenario
+        let source_waits = read_sidecar(&sidecar_path(&scenario_path, "default.source_wait"));
+        assert_eq!(source_waits.len(), 20);
+        assert!(source_waits.iter().all(|x| (0.0..=10000.0).contains(x)));
+        let delays = read_sidecar(&sidecar_path(&scenario_path, "delay"));
+        assert!(delays.iter().all(|x| (10.0..=100.0).contains(x)));
+        let popularity = read_sidecar(&sidecar_path(&scenario_path, "destinations"));
+        assert_eq!(popularity.len(), 5);
+        assert_eq!(popularity.iter().sum::<f64>(), 60.0);
+
+        // every session goes to the destination of the previous one
+        assert_eq!(scenario.destinations.count, 5);
+        assert_eq!(scenario.destinations.stickiness, 1.0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn histogram_bins() {
+        assert_eq!(
+            histogram(&[3.0, 1.0, 3.0, 2.0]),
+            vec![(1.0, 1.0, 1), (2.0, 2.0, 1), (3.0, 3.0, 2)]
+        );
+
+        let values: Vec<f64> = (0..3000).map(|x| x as f64).collect();
+        let bins = histogram(&values);
+        assert_eq!(bins.len(), MAX_BINS);
+        assert_eq!(bins[0], (0.0, 3.0, 3));
+        assert_eq!(bins[MAX_BINS - 1], (2997.0, 2999.0, 3));
+        assert_eq!(bins.iter().map(|x| x.2).sum::<usize>(), 3000);
+    }
+}
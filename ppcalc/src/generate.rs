@@ -1,82 +1,723 @@
-use ppcalc_metric::SourceId;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::rc::Rc;
 
-use crate::cli::GenerateArgs;
-use crate::{bench, destination, network, source, trace};
+use ppcalc_metric::{Direction, SourceId, TraceWriter};
+use rayon::prelude::*;
+use time::macros::datetime;
+use time::PrimitiveDateTime;
+
+use crate::cli::{ErasedDistribution, GenerateArgs};
+use crate::load::Load;
+use crate::provenance::{self, TraceMetadata};
+use crate::scenario::{Population, Scenario};
+use crate::{bench, cover, destination, network, rng, source, tor, trace};
+
+/// Number of messages that are generated at once per source
+const SOURCE_CHUNK_SIZE: usize = 1024;
+
+/// Number of sources that are generated at once when they are collected
+const SOURCE_BATCH_SIZE: usize = 1024;
 
 pub fn run(args: GenerateArgs) -> anyhow::Result<()> {
     let mut bench = bench::Bench::new();
     let BENCH_ENABLED = true;
 
-    let stream_length_distr = args
-        .stream_length
-        .make_distr()
-        .map_err(|e| anyhow::anyhow!(e))?;
-    let bandwidth_distr = args
-        .bandwidth
-        .make_distr()
-        .map_err(|e| anyhow::anyhow!(e))?;
-    let source_wait_distr = args
-        .source_wait
-        .make_distr()
-        .map_err(|e| anyhow::anyhow!(e))?;
-
-    let mut rng = rand::thread_rng();
+    let mut scenario = Scenario::from_args(&args)?;
+    if args.relay_observations.is_some() && scenario.network.tor.is_none() {
+        anyhow::bail!("Relay observations are only available for a simulated Tor network.");
+    }
+    if args.reuse_delays.is_some() && scenario.network.tor.is_some() {
+        anyhow::bail!("Delays cannot be reused for a simulated Tor network.");
+    }
 
-    // traces = trace::read_source_trace_from_file(&source_path).unwrap();
+    // TOML integers are signed, so keep the seed that the scenario file stores
+    // in their range
+    let seed = scenario.seed.unwrap_or_else(|| rand::random::<u64>() >> 1);
+    println!("Using seed {}.", seed);
+    // record the seed in the scenario, so that it can be reproduced
+    scenario.seed = Some(seed);
+    let message_size = scenario.message_size;
+    let destinations = &scenario.destinations;
+    let network = &scenario.network;
 
-    let source_traces = if let Some(source_path) = args.reuse_sources {
+    let reused_traces = if let Some(ref source_path) = args.reuse_sources {
         println!("Reusing sources from {}...", source_path.display());
         bench.measure("read sources", BENCH_ENABLED);
-        trace::read_sources_from_trace(&source_path).map_err(|e| anyhow::anyhow!(e))?
+        Some(trace::read_sources(source_path).map_err(|e| anyhow::anyhow!(e))?)
     } else {
         println!("Generating new sources...");
-        bench.measure("generate sources", BENCH_ENABLED);
+        None
+    };
+    // Every source draws from its own random number stream, so its messages
+    // can be generated lazily, when they are sent, and in parallel to those of
+    // the other sources without depending on the number of threads.
+    let generators = scenario
+        .populations
+        .iter()
+        .map(|population| SourceGenerator::new(population, message_size, seed))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let generator = |source_id| &generators[scenario.population_index(source_id).unwrap()];
 
-        let mut source_traces = vec![];
-        for i in 0..args.num_sources {
-            let source_id = SourceId::new(i);
+    // the sessions of all sources, in the order of the sources
+    bench.measure("generate sessions", BENCH_ENABLED);
+    let sessions: Vec<(SourceId, u64)> = match reused_traces {
+        Some(ref traces) => traces
+            .iter()
+            .map(|trace| (trace.source_id, trace.session))
+            .collect(),
+        None => {
+            let num_sessions: Vec<u64> = (0..scenario.num_sources())
+                .into_par_iter()
+                .map(|i| generator(SourceId::new(i)).num_sessions(SourceId::new(i)))
+                .collect();
+            num_sessions
+                .into_iter()
+                .enumerate()
+                .flat_map(|(i, num_sessions)| {
+                    (0..num_sessions).map(move |session| (SourceId::new(i as u64), session))
+                })
+                .collect()
+        }
+    };
+    // the class of each source is the name of its population
+    let classes: BTreeMap<SourceId, String> = sessions
+        .iter()
+        .filter_map(|&(source_id, _)| {
+            let i = scenario.population_index(source_id)?;
+            Some((source_id, scenario.populations[i].name.clone()))
+        })
+        .collect();
 
-            let length = stream_length_distr.sample(&mut rng);
-            let bandwidth = bandwidth_distr.sample(&mut rng); // Mbit/s
-            let bandwidth = (bandwidth * 1024.0 * 1024.0) / (8.0 * 1000.0 * 1000.0); // B/µs
+    let source_destination_map = if let Some(ref destinations_path) = args.reuse_destinations {
+        println!(
+            "Reusing destinations from {}...",
+            destinations_path.display()
+        );
+        bench.measure("read destinations", BENCH_ENABLED);
+        let source_destination_map =
+            trace::read_destinations(destinations_path).map_err(|e| anyhow::anyhow!(e))?;
+        for &(source_id, session) in sessions.iter() {
+            match source_destination_map.get(&(source_id, session)) {
+                None => anyhow::bail!(
+                    "{} has no destination for session {} of source {}.",
+                    destinations_path.display(),
+                    session,
+                    source_id.to_num()
+                ),
+                Some(destination_id) if destination_id.to_num() >= destinations.count => {
+                    anyhow::bail!(
+                        "{} contains destination {}, but there are only {} destinations.",
+                        destinations_path.display(),
+                        destination_id.to_num(),
+                        destinations.count
+                    )
+                }
+                Some(_) => {}
+            }
+        }
+        source_destination_map
+    } else {
+        bench.measure("generating source-destination map ", BENCH_ENABLED);
+        // The sessions of populations with their own destination selection
+        // choose their destinations separately, after all the others.
+        let mut session_lists = vec![(&destinations.selection, Vec::new())];
+        let population_lists: Vec<usize> = scenario
+            .populations
+            .iter()
+            .map(|population| match population.destination_selection {
+                Some(ref selection) => {
+                    session_lists.push((selection, Vec::new()));
+                    session_lists.len() - 1
+                }
+                None => 0,
+            })
+            .collect();
+        for &(source_id, session) in sessions.iter() {
+            let list = match scenario.population_index(source_id) {
+                Some(i) => population_lists[i],
+                None => 0,
+            };
+            session_lists[list].1.push((source_id, session));
+        }
 
-            let num_messages = (length + args.message_size - 1) / args.message_size; // ceiling division
-            let imd = args.message_size as f64 / bandwidth; // µs
+        let mut destination_rng = rng::stage_rng(seed, rng::Stage::Destinations);
+        let mut source_destination_map = HashMap::new();
+        for (selection, session_list) in session_lists {
+            source_destination_map.extend(destination::destination_selection(
+                selection,
+                destinations.count,
+                session_list,
+                &mut destination_rng,
+            )?);
+        }
+        destination::apply_stickiness(
+            &mut source_destination_map,
+            destinations.stickiness,
+            &mut destination_rng,
+        );
+        source_destination_map
+    };
+    if let Some(ref destinations_path) = args.save_destinations {
+        bench.measure("write destinations", BENCH_ENABLED);
+        trace::write_destinations(destinations_path, &source_destination_map)
+            .map_err(|e| anyhow::anyhow!(e))?;
+    }
 
-            let mut source = source::Source::new(
-                num_messages,
-                time::Duration::microseconds(imd as i64),
-                time::Duration::microseconds(
-                    ((source_wait_distr.sample(&mut rng) * 1000.0) as u64) as i64,
-                ),
-            );
-            source_traces.push(source.gen_source_trace(source_id));
+    // Cover traffic depends on all messages of each source, so the sources
+    // are collected for it, as far as they are not read from a file anyway.
+    let source_traces = match reused_traces {
+        None if !network.cover.is_empty() => {
+            bench.measure("generate sources", BENCH_ENABLED);
+            // the sources are generated in parallel a batch at a time, so that
+            // too many messages are noticed early
+            let source_ids: Vec<SourceId> =
+                (0..scenario.num_sources()).map(SourceId::new).collect();
+            let mut source_traces = Vec::new();
+            let mut num_messages = 0;
+            for batch in source_ids.chunks(SOURCE_BATCH_SIZE) {
+                let batch_traces: Vec<Vec<trace::SourceTrace>> = batch
+                    .par_iter()
+                    .map(|&source_id| generator(source_id).source_traces(source_id))
+                    .collect();
+                for trace in batch_traces.into_iter().flatten() {
+                    num_messages += trace.timestamps.len();
+                    source_traces.push(trace);
+                }
+                network::check_collected(num_messages, "Cover traffic")?;
+            }
+            Some(source_traces)
         }
-        // write_sources(&source_path, &source_traces).unwrap();
-        source_traces
+        reused_traces => reused_traces,
+    };
+    let dummies = match source_traces {
+        Some(ref source_traces) if !network.cover.is_empty() => {
+            bench.measure("generate cover traffic", BENCH_ENABLED);
+            cover::cover_traffic(
+                source_traces,
+                &source_destination_map,
+                &network.cover,
+                destinations.count,
+                &mut rng::stage_rng(seed, rng::Stage::Cover),
+            )?
+        }
+        _ => Vec::new(),
     };
 
-    bench.measure("generating source-destination map ", BENCH_ENABLED);
-    let source_name_list = source_traces.iter().map(|x| x.source_id.clone()).collect();
-    let source_destination_map = destination::destination_selection(
-        &args.destination_selection,
-        args.num_destinations,
-        source_name_list,
-    );
-
-    bench.measure("merge traces", BENCH_ENABLED);
-    let pre_network_trace = network::merge_traces(source_traces, &source_destination_map);
-    let network_trace = network::generate_network_delay(&args.network_delay, pre_network_trace);
-
-    bench.measure("write to file", BENCH_ENABLED);
-    network_trace
-        .write_to_file(&args.output)
-        .map_err(|e| anyhow::anyhow!(e))?;
+    // The messages are streamed from the sources through the network to the
+    // output file, with one stream per source, or per session of collected
+    // sources. The dummies come last.
+    bench.measure("generate network trace", BENCH_ENABLED);
+    let message = |source_id, session, source_timestamp| trace::PreNetworkTraceEntry {
+        source_id,
+        session,
+        source_timestamp,
+        destination_id: source_destination_map[&(source_id, session)],
+        dummy: false,
+        dropped: false,
+        direction: Direction::Upstream,
+    };
+    let mut streams: Vec<network::MessageStream> = Vec::new();
+    match source_traces {
+        Some(source_traces) => {
+            for trace in source_traces {
+                streams.push(Box::new(trace.timestamps.into_iter().map(
+                    move |source_timestamp| {
+                        message(trace.source_id, trace.session, source_timestamp)
+                    },
+                )));
+            }
+        }
+        None => {
+            let buffers = Rc::new(RefCell::new(SourceBuffers::new(
+                (0..scenario.num_sources())
+                    .map(|i| generator(SourceId::new(i)).messages(SourceId::new(i)))
+                    .collect(),
+            )));
+            for i in 0..scenario.num_sources() {
+                let buffers = Rc::clone(&buffers);
+                let source_id = SourceId::new(i);
+                streams.push(Box::new(
+                    std::iter::from_fn(move || buffers.borrow_mut().next(i as usize)).map(
+                        move |(session, source_timestamp)| {
+                            message(source_id, session, source_timestamp)
+                        },
+                    ),
+                ));
+            }
+        }
+    }
+    streams.push(Box::new(dummies.into_iter()));
+
+    // the sources file is written while the messages pass
+    let mut sources_writer = match args.save_sources {
+        Some(ref sources_path) => {
+            Some(trace::SourcesWriter::create(sources_path).map_err(|e| anyhow::anyhow!(e))?)
+        }
+        None => None,
+    };
+    let mut sources_error = None;
+    let pre_network_trace = network::merge_traces(streams).inspect(|entry| {
+        if let Some(ref mut writer) = sources_writer {
+            if let Err(e) = writer.write(entry) {
+                sources_error.get_or_insert(e);
+            }
+        }
+    });
+    let mut output = TraceWriter::create(&args.output).map_err(|e| anyhow::anyhow!(e))?;
+    if let Some(ref relays_path) = network.tor {
+        let tor_network = tor::read_relays(relays_path)?;
+        println!("Simulating {} relays...", tor_network.relays.len());
+        let (entries, observations) = tor::simulate(
+            &tor_network,
+            network::collect_messages(pre_network_trace, "The Tor simulation")?,
+            message_size,
+            &network.delay,
+            &mut rng::stage_rng(seed, rng::Stage::Circuits),
+            &mut rng::stage_rng(seed, rng::Stage::Delays),
+        )?;
+        for entry in entries {
+            output.write_entry(entry).map_err(|e| anyhow::anyhow!(e))?;
+        }
+        if let Some(ref observations_path) = args.relay_observations {
+            observations
+                .write_to_file(observations_path)
+                .map_err(|e| anyhow::anyhow!(e))?;
+        }
+    } else {
+        let delay_model = network::DelayModel {
+            message_delay: network.delay.clone(),
+            path_latency: network.path_latency.clone(),
+            destination_latencies: match network.destination_latencies {
+                Some(ref path) => Some(network::read_destination_latencies(
+                    path,
+                    destinations.count,
+                )?),
+                None => None,
+            },
+            fifo: network.fifo,
+            queue: network.queue.clone().map(|model| network::Queueing {
+                model,
+                location: network.queue_location.clone(),
+            }),
+            mix: network.mix.clone(),
+            responses: destinations
+                .responses
+                .clone()
+                .map(|model| network::Responses {
+                    model,
+                    message_size,
+                }),
+            reused_delays: match args.reuse_delays {
+                Some(ref path) => {
+                    println!("Reusing delays from {}...", path.display());
+                    Some(trace::read_delays_from_trace(path).map_err(|e| anyhow::anyhow!(e))?)
+                }
+                None => None,
+            },
+            loss: match network.loss {
+                0.0 => None,
+                probability => {
+                    if probability == 1.0 && network.retransmission_timeout.is_some() {
+                        anyhow::bail!(
+                            "Lost messages cannot be retransmitted if all messages are lost."
+                        );
+                    }
+                    Some(network::Loss {
+                        probability,
+                        retransmission_timeout: network.retransmission_timeout.clone(),
+                    })
+                }
+            },
+        };
+        network::generate_network_delay(&delay_model, pre_network_trace, seed, &mut output)?;
+    }
+    if let Some(e) = sources_error {
+        return Err(anyhow::anyhow!(e));
+    }
+    if let Some(writer) = sources_writer {
+        writer.finish().map_err(|e| anyhow::anyhow!(e))?;
+    }
+    let num_messages = output.finish().map_err(|e| anyhow::anyhow!(e))?;
+    println!("Generated {} messages.", num_messages);
 
-    // TODO
-    // bench.measure("parameters", BENCH_ENABLED);
-    // let parameter_path = String::from(&working_dir) + "parameters.json";
-    // std::fs::write(parameter_path, serde_json::to_string_pretty(&args).unwrap()).unwrap();
+    bench.measure("write metadata", BENCH_ENABLED);
+    let metadata = TraceMetadata {
+        tool: provenance::Tool::current(),
+        parameters: serde_json::to_value(&args)?,
+        seed: Some(seed),
+        sha256: provenance::hash_file(&args.output)?,
+    };
+    metadata.write_for_trace(&args.output)?;
+    scenario.write(Scenario::path_for_trace(&args.output))?;
+    trace::write_classes(trace::classes_path_for_trace(&args.output), &classes)
+        .map_err(|e| anyhow::anyhow!(e))?;
 
     Ok(())
 }
+
+/// Generates the sessions of the sources of a population
+struct SourceGenerator<'a> {
+    population: &'a Population,
+    message_size: u64,
+    seed: u64,
+    stream_length_distr: Box<dyn ErasedDistribution<u64>>,
+    bandwidth_distr: Box<dyn ErasedDistribution<f64>>,
+    source_wait_distr: Box<dyn ErasedDistribution<f64>>,
+    sessions_distr: Box<dyn ErasedDistribution<u64>>,
+    session_interval_distr: Box<dyn ErasedDistribution<f64>>,
+    load: Option<Load>,
+}
+
+impl<'a> SourceGenerator<'a> {
+    fn new(
+        population: &'a Population,
+        message_size: u64,
+        seed: u64,
+    ) -> anyhow::Result<SourceGenerator<'a>> {
+        Ok(SourceGenerator {
+            population,
+            message_size,
+            seed,
+            stream_length_distr: population
+                .stream_length
+                .make_distr()
+                .map_err(|e| anyhow::anyhow!(e))?,
+            bandwidth_distr: population
+                .bandwidth
+                .make_distr()
+                .map_err(|e| anyhow::anyhow!(e))?,
+            source_wait_distr: population
+                .source_wait
+                .make_distr()
+                .map_err(|e| anyhow::anyhow!(e))?,
+            sessions_distr: population
+                .sessions_per_source
+                .make_distr()
+                .map_err(|e| anyhow::anyhow!(e))?,
+            session_interval_distr: population
+                .session_interval
+                .make_distr()
+                .map_err(|e| anyhow::anyhow!(e))?,
+            load: match population.load {
+                Some(ref load) => Some(load.make_load()?),
+                None => None,
+            },
+        })
+    }
+
+    /// Draw the number of sessions of a source, which is the first value of
+    /// its random number stream
+    fn num_sessions(&self, source_id: SourceId) -> u64 {
+        let mut rng = rng::source_rng(self.seed, rng::Stage::Sources, source_id);
+        self.sessions_distr.sample(&mut rng).max(1)
+    }
+
+    /// Generate the messages of a source from its own random number stream,
+    /// session by session as they are needed
+    fn messages(&self, source_id: SourceId) -> SourceMessages<'_> {
+        let mut rng = rng::source_rng(self.seed, rng::Stage::Sources, source_id);
+        let num_sessions = self.sessions_distr.sample(&mut rng).max(1);
+        SourceMessages {
+            generator: self,
+            rng,
+            num_sessions,
+            next_session: 0,
+            session: None,
+            start_offset: time::Duration::ZERO,
+        }
+    }
+
+    /// Generate all messages of a source at once, as one trace per session
+    /// that sends messages
+    fn source_traces(&self, source_id: SourceId) -> Vec<trace::SourceTrace> {
+        let mut source_traces: Vec<trace::SourceTrace> = Vec::new();
+        for (session, timestamp) in self.messages(source_id) {
+            match source_traces.last_mut() {
+                Some(trace) if trace.session == session => trace.timestamps.push(timestamp),
+                _ => source_traces.push(trace::SourceTrace {
+                    source_id,
+                    session,
+                    timestamps: vec![timestamp],
+                }),
+            }
+        }
+        source_traces
+    }
+
+    /// Start a session at the given offset, drawing its length, bandwidth and
+    /// the wait before it
+    fn start_session(
+        &self,
+        session: u64,
+        start_offset: time::Duration,
+        rng: &mut rng::StageRng,
+    ) -> source::Source {
+        let message_size = self.message_size;
+        let length = self.stream_length_distr.sample(rng);
+        let bandwidth = self.bandwidth_distr.sample(rng); // Mbit/s
+        let bandwidth = (bandwidth * 1024.0 * 1024.0) / (8.0 * 1000.0 * 1000.0); // B/µs
+
+        let num_messages = (length + message_size - 1) / message_size; // ceiling division
+        let imd = message_size as f64 / bandwidth; // µs
+
+        // the first session starts after the source's wait time, the
+        // following ones after an interval since the previous one ended
+        let wait = match session {
+            0 => self.source_wait_distr.sample(rng),
+            _ => self.session_interval_distr.sample(rng),
+        };
+        // with a load profile, the wait passes faster at a higher load
+        let wait = match self.load {
+            Some(ref load) => {
+                let start = start_offset.as_seconds_f64() * 1000.0;
+                load.advance(start, wait) - start
+            }
+            None => wait,
+        };
+        let start_offset =
+            start_offset + time::Duration::microseconds(((wait * 1000.0) as u64) as i64);
+
+        source::Source::new(
+            num_messages,
+            time::Duration::microseconds(imd as i64),
+            start_offset,
+            self.population.traffic_model.clone(),
+            message_size,
+        )
+    }
+}
+
+/// The messages of a source as (session, send time), ordered by time
+struct SourceMessages<'a> {
+    generator: &'a SourceGenerator<'a>,
+    rng: rng::StageRng,
+    num_sessions: u64,
+    next_session: u64,
+    /// The current session and its number
+    session: Option<(u64, source::Source)>,
+    /// The end of the previous session
+    start_offset: time::Duration,
+}
+
+impl Iterator for SourceMessages<'_> {
+    type Item = (u64, PrimitiveDateTime);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((session, ref mut source)) = self.session {
+                if let Some(timestamp) = source.next_timestamp(&mut self.rng) {
+                    self.start_offset = timestamp - datetime!(1970-01-01 0:00);
+                    return Some((session, timestamp));
+                }
+            }
+            if self.next_session == self.num_sessions {
+                self.session = None;
+                return None;
+            }
+            let source =
+                self.generator
+                    .start_session(self.next_session, self.start_offset, &mut self.rng);
+            self.session = Some((self.next_session, source));
+            self.next_session += 1;
+        }
+    }
+}
+
+/// The messages of the generated sources, which are drawn in parallel in
+/// chunks of up to [`SOURCE_CHUNK_SIZE`] messages per source
+struct SourceBuffers<'a> {
+    sources: Vec<SourceBuffer<'a>>,
+}
+
+/// The messages of a source that are drawn, but not sent yet
+struct SourceBuffer<'a> {
+    messages: SourceMessages<'a>,
+    buffer: VecDeque<(u64, PrimitiveDateTime)>,
+    exhausted: bool,
+}
+
+impl<'a> SourceBuffers<'a> {
+    fn new(sources: Vec<SourceMessages<'a>>) -> SourceBuffers<'a> {
+        SourceBuffers {
+            sources: sources
+                .into_iter()
+                .map(|messages| SourceBuffer {
+                    messages,
+                    buffer: VecDeque::new(),
+                    exhausted: false,
+                })
+                .collect(),
+        }
+    }
+
+    /// Take the next message of the `i`-th source. Once it has none left, the
+    /// buffers of all sources that run low are refilled in parallel.
+    fn next(&mut self, i: usize) -> Option<(u64, PrimitiveDateTime)> {
+        if self.sources[i].buffer.is_empty() && !self.sources[i].exhausted {
+            self.sources
+                .par_iter_mut()
+                .filter(|source| !source.exhausted && source.buffer.len() < SOURCE_CHUNK_SIZE / 4)
+                .for_each(|source| source.refill());
+        }
+        self.sources[i].buffer.pop_front()
+    }
+}
+
+impl SourceBuffer<'_> {
+    fn refill(&mut self) {
+        while self.buffer.len() < SOURCE_CHUNK_SIZE {
+            match self.messages.next() {
+                Some(message) => self.buffer.push_back(message),
+                None => {
+                    self.exhausted = true;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Cli, Commands};
+    use clap::Parser;
+
+    /// Generate a trace into a temporary file with the given parameters, and
+    /// return the file's content
+    fn generate_trace(name: &str, params: &[&str]) -> String {
+        let path = std::env::temp_dir().join(format!("ppcalc_test_{}.csv", name));
+        let mut arguments = vec!["ppcalc", "generate", "--seed", "42"];
+        arguments.extend_from_slice(params);
+        arguments.push(path.to_str().unwrap());
+        let Commands::Generate(args) = Cli::try_parse_from(arguments).unwrap().command else {
+            unreachable!()
+        };
+        run(*args).unwrap();
+        std::fs::read_to_string(&path).unwrap()
+    }
+
+    const PARAMS: [&str; 14] = [
+        "--sources",
+        "20",
+        "--destinations",
+        "5",
+        "--bandwidth",
+        "uniform:1:2",
+        "--stream-length",
+        "uniform:1000:20000",
+        "--source-wait",
+        "exponential:100",
+        "--network-delay",
+        "uniform:10:100",
+        "--sessions-per-source",
+        "uniform:1:3",
+    ];
+
+    #[test]
+    fn same_seed() {
+        let first = generate_trace(
+            "same_seed_1",
+            &[&PARAMS[..], &["--destination-selection", "uniform"]].concat(),
+        );
+        let second = generate_trace(
+            "same_seed_2",
+            &[&PARAMS[..], &["--destination-selection", "uniform"]].concat(),
+        );
+        assert!(first.lines().count() > 20);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn independent_stages() {
+        // the send and arrival times of the messages of each source
+        let times = |trace: &str| {
+            let mut times: Vec<(String, String, String)> = trace
+                .lines()
+                .skip(1)
+                .map(|line| {
+                    let fields: Vec<&str> = line.split(',').collect();
+                    (fields[1].into(), fields[2].into(), fields[4].into())
+                })
+                .collect();
+            times.sort();
+            times
+        };
+        let destinations = |trace: &str| {
+            let mut destinations: Vec<String> = trace
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').nth(3).unwrap().into())
+                .collect();
+            destinations.sort();
+            destinations
+        };
+
+        // choosing the destinations differently does not change the sources
+        // and their delays
+        let uniform = generate_trace(
+            "stages_uniform",
+            &[&PARAMS[..], &["--destination-selection", "uniform"]].concat(),
+        );
+        let zipf = generate_trace(
+            "stages_zipf",
+            &[
+                &PARAMS[..],
+                &[
+                    "--destination-selection",
+                    "zipf:2",
+                    "--destination-stickiness",
+                    "0.5",
+                ],
+            ]
+            .concat(),
+        );
+        assert_ne!(destinations(&uniform), destinations(&zipf));
+        assert_eq!(times(&uniform), times(&zipf));
+    }
+
+    #[test]
+    fn thread_count() {
+        // the sources and the delays of their messages are generated in parallel
+        for (name, cover) in [
+            ("plain", &[][..]),
+            ("cover", &["--cover", "loop:exponential:500"][..]),
+        ] {
+            let with_threads = |num_threads| {
+                let sources_path = std::env::temp_dir().join(format!(
+                    "ppcalc_test_threads_{}_{}_sources.csv",
+                    name, num_threads
+                ));
+                let params = [
+                    &PARAMS[..],
+                    &[
+                        "--destination-selection",
+                        "uniform",
+                        "--loss",
+                        "0.1",
+                        "--retransmission-timeout",
+                        "uniform:100:200",
+                        "--save-sources",
+                        sources_path.to_str().unwrap(),
+                    ],
+                    cover,
+                ]
+                .concat();
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(num_threads)
+                    .build()
+                    .unwrap()
+                    .install(|| {
+                        let trace =
+                            generate_trace(&format!("threads_{}_{}", name, num_threads), &params);
+                        (trace, std::fs::read_to_string(&sources_path).unwrap())
+                    })
+            };
+            let (trace, sources) = with_threads(1);
+            assert!(trace.lines().count() > 20);
+            assert!(sources.lines().count() > 20);
+            assert_eq!((trace, sources), with_threads(8));
+        }
+    }
+}
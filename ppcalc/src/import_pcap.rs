@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+
+use ppcalc_metric::{DestinationId, Direction, MessageId, SourceId, TraceBuilder, TraceEntry};
+
+use crate::cli::ImportPcapArgs;
+use crate::pcap::{self, FiveTuple, Packet, Protocol};
+
+pub fn run(args: ImportPcapArgs) -> anyhow::Result<()> {
+    let rules: FlowRules = serde_json::from_str(
+        &fs::read_to_string(&args.flows)
+            .with_context(|| format!("Cannot read {}", args.flows.display()))?,
+    )
+    .with_context(|| format!("Invalid flow rules in {}", args.flows.display()))?;
+    let mapping = read_mapping(&args.mapping)?;
+
+    println!("Reading ingress capture {}...", args.ingress.display());
+    let ingress_flows = group_flows(
+        pcap::read_packets(&args.ingress)?,
+        &rules.ingress,
+        args.min_payload,
+    );
+    println!("Reading egress capture {}...", args.egress.display());
+    let egress_flows = group_flows(
+        pcap::read_packets(&args.egress)?,
+        &rules.egress,
+        args.min_payload,
+    );
+    println!(
+        "Found {} ingress and {} egress flows.",
+        ingress_flows.len(),
+        egress_flows.len()
+    );
+
+    let (mut trace, num_sources, num_destinations) =
+        pair_flows(&mapping, &ingress_flows, &egress_flows);
+    trace.fix();
+    let trace = trace.build()?;
+    trace.write_to_file(&args.output).map_err(|e| anyhow!(e))?;
+
+    println!(
+        "Wrote {} messages from {} sources to {} destinations.",
+        trace.max_message_id().to_num() + 1,
+        num_sources,
+        num_destinations
+    );
+
+    Ok(())
+}
+
+/// The rules that select and label the flows on either side of the network
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FlowRules {
+    ingress: Vec<FlowRule>,
+    egress: Vec<FlowRule>,
+}
+
+/// A pattern on the 5-tuple of a packet. Fields that are not given match any value.
+///
+/// Packets matching a labeled rule all belong to the flow of that label.
+/// Otherwise, each distinct 5-tuple forms its own flow, labeled like
+/// `tcp:10.0.0.1:40000->10.0.0.2:443`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FlowRule {
+    label: Option<String>,
+    protocol: Option<Protocol>,
+    src_ip: Option<IpAddr>,
+    src_port: Option<u16>,
+    dst_ip: Option<IpAddr>,
+    dst_port: Option<u16>,
+}
+
+impl FlowRule {
+    fn matches(&self, tuple: &FiveTuple) -> bool {
+        self.protocol.is_none_or(|x| x == tuple.protocol)
+            && self.src_ip.is_none_or(|x| x == tuple.src_ip)
+            && self.src_port.is_none_or(|x| x == tuple.src_port)
+            && self.dst_ip.is_none_or(|x| x == tuple.dst_ip)
+            && self.dst_port.is_none_or(|x| x == tuple.dst_port)
+    }
+}
+
+/// Group packets into labeled flows, according to the first rule each packet matches.
+///
+/// Packets matching no rule, or with less than `min_payload` bytes of
+/// payload, are dropped.
+fn group_flows(
+    packets: Vec<Packet>,
+    rules: &[FlowRule],
+    min_payload: usize,
+) -> BTreeMap<String, Vec<Packet>> {
+    let mut flows: BTreeMap<String, Vec<Packet>> = BTreeMap::new();
+    for packet in packets {
+        if packet.payload_len < min_payload {
+            continue;
+        }
+        let Some(rule) = rules.iter().find(|r| r.matches(&packet.five_tuple)) else {
+            continue;
+        };
+        let label = match rule.label {
+            Some(ref label) => label.clone(),
+            None => packet.five_tuple.to_string(),
+        };
+        flows.entry(label).or_default().push(packet);
+    }
+
+    for packets in flows.values_mut() {
+        packets.sort_by_key(|p| p.timestamp);
+    }
+    flows
+}
+
+/// Pair the ingress and egress flows of each row of the mapping into messages,
+/// returning them with the number of sources and destinations.
+///
+/// Rows whose flows have no packets are skipped.
+fn pair_flows(
+    mapping: &[MappingEntry],
+    ingress_flows: &BTreeMap<String, Vec<Packet>>,
+    egress_flows: &BTreeMap<String, Vec<Packet>>,
+) -> (TraceBuilder, usize, usize) {
+    // Sources and destinations are numbered in order of their first appearance
+    // in the mapping file.
+    let mut source_ids: BTreeMap<&str, SourceId> = BTreeMap::new();
+    let mut destination_ids: BTreeMap<&str, DestinationId> = BTreeMap::new();
+
+    let mut trace = TraceBuilder::new();
+    for row in mapping.iter() {
+        let (Some(ingress), Some(egress)) = (
+            ingress_flows.get(&row.ingress_flow),
+            egress_flows.get(&row.egress_flow),
+        ) else {
+            println!(
+                "Warning: flows \"{}\" and \"{}\" have no matching packets, skipping.",
+                row.ingress_flow, row.egress_flow
+            );
+            continue;
+        };
+        if ingress.len() != egress.len() {
+            println!(
+                "Warning: flows \"{}\" ({} packets) and \"{}\" ({} packets) differ in length, ignoring the surplus.",
+                row.ingress_flow,
+                ingress.len(),
+                row.egress_flow,
+                egress.len()
+            );
+        }
+
+        let next_source = SourceId::new(source_ids.len() as u64);
+        let source_id = *source_ids.entry(row.source()).or_insert(next_source);
+        let next_destination = DestinationId::new(destination_ids.len() as u64);
+        let destination_id = *destination_ids
+            .entry(row.destination())
+            .or_insert(next_destination);
+
+        // Packets of matched flows correspond to each other in order of appearance
+        for (sent, received) in ingress.iter().zip(egress.iter()) {
+            trace.add_entry(TraceEntry {
+                m_id: MessageId::new(0),
+                source_id,
+                source_timestamp: sent.timestamp,
+                destination_id,
+                destination_timestamp: received.timestamp,
+                session: 0,
+                dummy: false,
+                dropped: false,
+                direction: Direction::Upstream,
+                retransmission: false,
+            });
+        }
+    }
+
+    (trace, source_ids.len(), destination_ids.len())
+}
+
+/// A row of the ground-truth mapping file
+#[derive(Deserialize, Debug)]
+struct MappingEntry {
+    ingress_flow: String,
+    egress_flow: String,
+    /// Label of the source entity (defaults to the ingress flow label)
+    #[serde(default)]
+    source: Option<String>,
+    /// Label of the destination entity (defaults to the egress flow label)
+    #[serde(default)]
+    destination: Option<String>,
+}
+
+impl MappingEntry {
+    fn source(&self) -> &str {
+        self.source.as_deref().unwrap_or(&self.ingress_flow)
+    }
+
+    fn destination(&self) -> &str {
+        self.destination.as_deref().unwrap_or(&self.egress_flow)
+    }
+}
+
+fn read_mapping(path: &Path) -> anyhow::Result<Vec<MappingEntry>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .from_path(path)
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+    let mut mapping = Vec::new();
+    for row in rdr.deserialize() {
+        let row: MappingEntry =
+            row.with_context(|| format!("Invalid flow mapping in {}", path.display()))?;
+        mapping.push(row);
+    }
+    if mapping.is_empty() {
+        bail!("The flow mapping {} is empty", path.display());
+    }
+    Ok(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn packet(millisecond: i64, src_port: u16, dst_port: u16, payload_len: usize) -> Packet {
+        Packet {
+            timestamp: datetime!(1970-01-01 0:00) + time::Duration::milliseconds(millisecond),
+            five_tuple: FiveTuple {
+                protocol: Protocol::Tcp,
+                src_ip: "10.0.0.1".parse().unwrap(),
+                src_port,
+                dst_ip: "10.0.0.2".parse().unwrap(),
+                dst_port,
+            },
+            payload_len,
+        }
+    }
+
+    fn rules(json: &str) -> Vec<FlowRule> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn grouping() {
+        let packets = vec![
+            packet(3, 1000, 443, 100),
+            packet(1, 1000, 443, 100),
+            packet(2, 1001, 443, 100),
+            packet(4, 1002, 80, 100),
+            // too small
+            packet(5, 1000, 443, 10),
+        ];
+        let rules = rules(r#"[{"label": "first", "src_port": 1000}, {"dst_port": 443}]"#);
+        let flows = group_flows(packets, &rules, 50);
+
+        // the first matching rule decides, and unlabeled rules label by 5-tuple
+        let labels: Vec<&str> = flows.keys().map(|x| x.as_str()).collect();
+        assert_eq!(labels, ["first", "tcp:10.0.0.1:1001->10.0.0.2:443"]);
+        // the packets of a flow are ordered by time
+        let times: Vec<_> = flows["first"].iter().map(|p| p.timestamp).collect();
+        assert_eq!(
+            times,
+            [
+                datetime!(1970-01-01 0:00:00.001),
+                datetime!(1970-01-01 0:00:00.003)
+            ]
+        );
+    }
+
+    #[test]
+    fn rule_matching() {
+        let rule = &rules(r#"[{"protocol": "udp", "dst_ip": "10.0.0.2"}]"#)[0];
+        let mut tuple = packet(0, 1000, 443, 0).five_tuple;
+        assert!(!rule.matches(&tuple));
+        tuple.protocol = Protocol::Udp;
+        assert!(rule.matches(&tuple));
+        tuple.dst_ip = "10.0.0.3".parse().unwrap();
+        assert!(!rule.matches(&tuple));
+    }
+
+    #[test]
+    fn pairing() {
+        let flows = |entries: &[(&str, Vec<i64>)]| -> BTreeMap<String, Vec<Packet>> {
+            entries
+                .iter()
+                .map(|(label, times)| {
+                    let packets = times.iter().map(|&t| packet(t, 1000, 443, 100)).collect();
+                    (label.to_string(), packets)
+                })
+                .collect()
+        };
+        let ingress = flows(&[("a", vec![0, 10, 20]), ("b", vec![5])]);
+        let egress = flows(&[("x", vec![3, 12]), ("y", vec![8])]);
+        let mapping_path = std::env::temp_dir().join("ppcalc_test_mapping.csv");
+        fs::write(
+            &mapping_path,
+            "ingress_flow,egress_flow,source,destination\n\
+             a,x,alice,\n\
+             b,y,alice,server\n\
+             c,x,,\n",
+        )
+        .unwrap();
+        let mapping = read_mapping(&mapping_path).unwrap();
+        fs::remove_file(&mapping_path).unwrap();
+
+        // the flow without packets is skipped, and the surplus packet of "a" ignored
+        let (mut trace, num_sources, num_destinations) = pair_flows(&mapping, &ingress, &egress);
+        assert_eq!((num_sources, num_destinations), (1, 2));
+        trace.fix();
+        let trace = trace.build().unwrap();
+        let messages: Vec<_> = trace
+            .entries()
+            .map(|entry| {
+                (
+                    entry.source_id.to_num(),
+                    entry.destination_id.to_num(),
+                    (entry.destination_timestamp - entry.source_timestamp).whole_milliseconds(),
+                )
+            })
+            .collect();
+        assert_eq!(messages, [(0, 0, 3), (0, 1, 3), (0, 0, 2)]);
+    }
+}
@@ -0,0 +1,375 @@
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use fxhash::FxHashMap as HashMap;
+use time::macros::datetime;
+use time::PrimitiveDateTime;
+
+use ppcalc_metric::{DestinationId, Direction, MessageId, SourceId, TraceBuilder, TraceEntry};
+
+use crate::cli::ImportShadowArgs;
+
+pub fn run(args: ImportShadowArgs) -> anyhow::Result<()> {
+    let log_files = find_tgen_logs(&args.input)?;
+    if log_files.is_empty() {
+        bail!("No tgen log files found in {}", args.input.display());
+    }
+
+    let mut client_streams = Vec::new();
+    let mut server_streams: HashMap<(String, String, String), TgenStream> = HashMap::default();
+    for (host, path) in log_files {
+        let is_server = args.servers.iter().any(|prefix| host.starts_with(prefix));
+        for stream in read_tgen_streams(&path)? {
+            if is_server {
+                server_streams.insert(
+                    (stream.peer.clone(), stream.name.clone(), stream.id.clone()),
+                    stream,
+                );
+            } else {
+                client_streams.push(stream);
+            }
+        }
+    }
+    println!(
+        "Found {} client and {} server streams.",
+        client_streams.len(),
+        server_streams.len()
+    );
+
+    // each client stream becomes a source, each server a destination
+    client_streams.sort_by(|a, b| (&a.name, a.created).cmp(&(&b.name, b.created)));
+    let mut destination_ids: HashMap<String, DestinationId> = HashMap::default();
+    let mut num_sources = 0;
+    let mut trace = TraceBuilder::new();
+    for stream in client_streams {
+        let Some(server_stream) =
+            server_streams.get(&(stream.name.clone(), stream.peer.clone(), stream.id.clone()))
+        else {
+            println!(
+                "Warning: no server side found for stream {} of {} to {}, skipping.",
+                stream.id, stream.name, stream.peer
+            );
+            continue;
+        };
+
+        let num_messages = stream.bytes_sent.div_ceil(args.message_size);
+        let (Some(send), Some(recv)) = (stream.send_window(), server_stream.recv_window()) else {
+            continue;
+        };
+        if num_messages == 0 {
+            continue;
+        }
+
+        let source_id = SourceId::new(num_sources);
+        num_sources += 1;
+        let next_destination = DestinationId::new(destination_ids.len() as u64);
+        let destination_id = *destination_ids
+            .entry(stream.peer.clone())
+            .or_insert(next_destination);
+
+        // spread the messages evenly over the transfer on both sides
+        for i in 0..num_messages {
+            trace.add_entry(TraceEntry {
+                m_id: MessageId::new(0),
+                source_id,
+                source_timestamp: interpolate(send, i, num_messages),
+                destination_id,
+                destination_timestamp: interpolate(recv, i, num_messages),
+                session: 0,
+                dummy: false,
+                dropped: false,
+                direction: Direction::Upstream,
+                retransmission: false,
+            });
+        }
+    }
+
+    trace.fix();
+    let trace = trace.build()?;
+    trace.write_to_file(&args.output).map_err(|e| anyhow!(e))?;
+
+    println!(
+        "Wrote {} messages from {} sources to {} destinations.",
+        trace.max_message_id().to_num() + 1,
+        num_sources,
+        destination_ids.len()
+    );
+
+    Ok(())
+}
+
+/// Timestamp of the `i`-th of `n` messages that are evenly spread over `window`
+fn interpolate(
+    window: (PrimitiveDateTime, PrimitiveDateTime),
+    i: u64,
+    n: u64,
+) -> PrimitiveDateTime {
+    let (start, end) = window;
+    if n <= 1 {
+        return start;
+    }
+    start + (end - start) * (i as f64 / (n - 1) as f64)
+}
+
+/// Find all tgen log files below a Shadow results directory, together with
+/// the name of the host they belong to (their parent directory).
+fn find_tgen_logs(dir: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut result = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).with_context(|| format!("Cannot read {}", dir.display()))? {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            if file_name.contains("tgen")
+                && (file_name.ends_with(".stdout") || file_name.ends_with(".log"))
+            {
+                let host = dir
+                    .file_name()
+                    .map(|x| x.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                result.push((host, path));
+            }
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// A successfully completed tgen stream, as logged by one of its endpoints
+#[derive(Debug)]
+struct TgenStream {
+    id: String,
+    name: String,
+    peer: String,
+    bytes_sent: u64,
+    /// creation time [µs]
+    created: u64,
+    /// offsets from the creation time [µs]
+    first_byte_send: Option<u64>,
+    last_byte_send: Option<u64>,
+    first_byte_recv: Option<u64>,
+    last_byte_recv: Option<u64>,
+}
+
+impl TgenStream {
+    fn window(
+        &self,
+        first: Option<u64>,
+        last: Option<u64>,
+    ) -> Option<(PrimitiveDateTime, PrimitiveDateTime)> {
+        let at = |offset: u64| {
+            datetime!(1970-01-01 0:00)
+                + time::Duration::microseconds((self.created + offset) as i64)
+        };
+        Some((at(first?), at(last?)))
+    }
+
+    fn send_window(&self) -> Option<(PrimitiveDateTime, PrimitiveDateTime)> {
+        self.window(self.first_byte_send, self.last_byte_send)
+    }
+
+    fn recv_window(&self) -> Option<(PrimitiveDateTime, PrimitiveDateTime)> {
+        self.window(self.first_byte_recv, self.last_byte_recv)
+    }
+
+    /// Parse a `[stream-success]` log line
+    fn parse(line: &str) -> Option<TgenStream> {
+        let stream = parse_section(line, "stream")?;
+        let bytes = parse_section(line, "bytes")?;
+        let times = parse_section(line, "times")?;
+
+        // values may be given as "done/expected"
+        let number = |map: &HashMap<&str, &str>, key: &str| -> Option<u64> {
+            map.get(key)?.split('/').next()?.parse().ok()
+        };
+        // unset times are logged as -1
+        let offset = |key: &str| -> Option<u64> { times.get(key)?.parse().ok() };
+
+        Some(TgenStream {
+            id: stream.get("id")?.to_string(),
+            name: stream.get("name")?.to_string(),
+            peer: stream.get("peername")?.to_string(),
+            bytes_sent: number(&bytes, "payload-bytes-send")?,
+            created: number(&times, "created-ts")?,
+            first_byte_send: offset("usecs-to-first-byte-send"),
+            last_byte_send: offset("usecs-to-last-byte-send"),
+            first_byte_recv: offset("usecs-to-first-byte-recv"),
+            last_byte_recv: offset("usecs-to-last-byte-recv"),
+        })
+    }
+}
+
+/// Parse a `NAME [key=value,...]` section of a tgen log line
+fn parse_section<'a>(line: &'a str, name: &str) -> Option<HashMap<&'a str, &'a str>> {
+    let start = line.find(&format!(" {} [", name))? + name.len() + 3;
+    let end = start + line[start..].find(']')?;
+    Some(
+        line[start..end]
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .collect(),
+    )
+}
+
+fn read_tgen_streams(path: &Path) -> anyhow::Result<Vec<TgenStream>> {
+    let file = fs::File::open(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let mut streams = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.contains("[stream-success]") {
+            continue;
+        }
+        match TgenStream::parse(&line) {
+            Some(stream) => streams.push(stream),
+            None => println!(
+                "Warning: cannot parse stream in {}: {}",
+                path.display(),
+                line
+            ),
+        }
+    }
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `[stream-success]` line as logged by tgen, with the given times [µs]
+    fn stream_line(id: u64, name: &str, peer: &str, bytes_sent: u64, times: &str) -> String {
+        format!(
+            "2024-01-01 00:00:01 946684801.000000 [message] [shd-tgen-stream.c:1616] \
+             [_tgenstream_log] [stream-success] stream [id={},vertexid=stream,name={},\
+             peername={},sendsize={},recvsize=0,sendstate=SEND_SUCCESS,\
+             recvstate=RECV_SUCCESS,error=NONE] transport [fd=17,local=localhost:127.0.0.1:4000,\
+             proxy=NULL,remote={}:11.0.0.1:80,state=SUCCESS,error=NONE] bytes \
+             [total-bytes-recv=0,total-bytes-send={},payload-bytes-recv=0,\
+             payload-bytes-send={}/{},payload-progress-recv=100.0%,\
+             payload-progress-send=100.0%] times [{}]",
+            id, name, peer, bytes_sent, peer, bytes_sent, bytes_sent, bytes_sent, times
+        )
+    }
+
+    const CLIENT_TIMES: &str = "created-ts=1000000,usecs-to-socket-create=2,\
+        usecs-to-first-byte-send=100,usecs-to-last-byte-send=1100,\
+        usecs-to-first-byte-recv=-1,usecs-to-last-byte-recv=-1";
+    const SERVER_TIMES: &str = "created-ts=1000050,usecs-to-socket-create=2,\
+        usecs-to-first-byte-send=-1,usecs-to-last-byte-send=-1,\
+        usecs-to-first-byte-recv=250,usecs-to-last-byte-recv=2250";
+
+    #[test]
+    fn parse_stream() {
+        let stream =
+            TgenStream::parse(&stream_line(3, "client1", "server1", 1500, CLIENT_TIMES)).unwrap();
+        assert_eq!(
+            (
+                stream.id.as_str(),
+                stream.name.as_str(),
+                stream.peer.as_str()
+            ),
+            ("3", "client1", "server1")
+        );
+        assert_eq!(stream.bytes_sent, 1500);
+        assert_eq!(stream.created, 1_000_000);
+        let epoch = datetime!(1970-01-01 0:00);
+        assert_eq!(
+            stream.send_window(),
+            Some((
+                epoch + time::Duration::microseconds(1_000_100),
+                epoch + time::Duration::microseconds(1_001_100)
+            ))
+        );
+        // times that are logged as -1 are unset
+        assert_eq!(stream.recv_window(), None);
+    }
+
+    #[test]
+    fn malformed_lines() {
+        // a section is missing
+        let line = stream_line(1, "client1", "server1", 1000, CLIENT_TIMES);
+        let without_times = &line[..line.find(" times [").unwrap()];
+        assert!(TgenStream::parse(without_times).is_none());
+        // a section is not closed
+        assert!(parse_section("stream-success] stream [id=1,name=a", "stream").is_none());
+        // a required value is not a number
+        let line = stream_line(1, "client1", "server1", 1000, "created-ts=now");
+        assert!(TgenStream::parse(&line).is_none());
+    }
+
+    #[test]
+    fn interpolation() {
+        let start = datetime!(1970-01-01 0:00);
+        let window = (start, start + time::Duration::milliseconds(10));
+        assert_eq!(interpolate(window, 0, 1), start);
+        let times: Vec<_> = (0..3).map(|i| interpolate(window, i, 3)).collect();
+        assert_eq!(
+            times,
+            [start, start + time::Duration::milliseconds(5), window.1]
+        );
+    }
+
+    #[test]
+    fn import() {
+        let input = std::env::temp_dir().join("ppcalc_test_shadow");
+        let _ = fs::remove_dir_all(&input);
+        let write_log = |host: &str, lines: &[String]| {
+            fs::create_dir_all(input.join(host)).unwrap();
+            fs::write(
+                input.join(host).join("tgen.1000.stdout"),
+                lines.join("\n") + "\n",
+            )
+            .unwrap();
+        };
+        write_log(
+            "client1",
+            &[
+                "2024-01-01 00:00:00 946684800.000000 [message] tgen is starting".to_string(),
+                stream_line(1, "client1", "server1", 1000, CLIENT_TIMES),
+                // the server did not log this stream
+                stream_line(2, "client1", "server1", 1000, CLIENT_TIMES),
+                // malformed, so skipped
+                "[stream-success] stream [id=3,name=client1".to_string(),
+            ],
+        );
+        write_log(
+            "server1",
+            &[stream_line(1, "server1", "client1", 0, SERVER_TIMES)],
+        );
+
+        let output = std::env::temp_dir().join("ppcalc_test_shadow.csv");
+        run(ImportShadowArgs {
+            servers: vec!["server".to_string()],
+            message_size: 500,
+            input: input.clone(),
+            output: output.clone(),
+        })
+        .unwrap();
+        let trace = TraceBuilder::from_csv(&output).unwrap().build().unwrap();
+        fs::remove_dir_all(&input).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        // the 1000 bytes of the matched stream are spread over both windows as
+        // two messages
+        let epoch = datetime!(1970-01-01 0:00);
+        let times: Vec<_> = trace
+            .entries()
+            .map(|entry| {
+                (
+                    entry.source_id.to_num(),
+                    (entry.source_timestamp - epoch).whole_microseconds(),
+                    (entry.destination_timestamp - epoch).whole_microseconds(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            times,
+            [(0, 1_000_100, 1_000_300), (0, 1_001_100, 1_002_300)]
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Time-varying load: the rate at which sources and sessions start changes
+//! over time, e.g. following a daily pattern.
+//!
+//! A load profile gives the relative rate over time, where 1 is the rate of
+//! the unchanged wait and interval distributions. The start times are drawn
+//! from these distributions in an operational time that runs faster at
+//! higher load, and then transformed to real time. Starts that form a
+//! Poisson process in operational time thus follow a non-homogeneous Poisson
+//! process with the profile's rate in real time.
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+
+/// The relative load over time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LoadProfile {
+    /// A sinusoidal pattern with a mean of 1, e.g. over a day
+    Diurnal {
+        /// Length of a cycle [ms]
+        period: f64,
+        /// Relative deviation from the mean at the peak, between 0 and 1
+        amplitude: f64,
+        /// Time of the first peak [ms]
+        peak: f64,
+    },
+    /// Constant rates, each from the given time [ms] on. The first rate also
+    /// applies before its time.
+    Steps(Vec<(f64, f64)>),
+    /// A CSV file of times [ms] and rates, interpolated linearly. The first
+    /// and the last rate continue before and after the given times.
+    File(PathBuf),
+}
+
+/// Format the profile like it is given on the command line
+impl fmt::Display for LoadProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadProfile::Diurnal {
+                period,
+                amplitude,
+                peak,
+            } => write!(f, "diurnal:{}:{}:{}", period, amplitude, peak),
+            LoadProfile::Steps(steps) => {
+                write!(f, "steps:")?;
+                for (i, (time, rate)) in steps.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}={}", time, rate)?;
+                }
+                Ok(())
+            }
+            LoadProfile::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+impl LoadProfile {
+    /// Make a file path relative to `base`, like for a scenario file
+    pub fn relative_to(self, base: &Path) -> LoadProfile {
+        match self {
+            LoadProfile::File(path) => LoadProfile::File(base.join(path)),
+            profile => profile,
+        }
+    }
+
+    /// Prepare the transformation of start times, reading the profile's file
+    pub fn make_load(&self) -> anyhow::Result<Load> {
+        let load = match self {
+            LoadProfile::Diurnal {
+                period,
+                amplitude,
+                peak,
+            } => {
+                if *period <= 0.0 || !(0.0..=1.0).contains(amplitude) {
+                    bail!("The period of a diurnal load must be positive, and its amplitude between 0 and 1.");
+                }
+                Load::Diurnal {
+                    period: *period,
+                    amplitude: *amplitude,
+                    peak: *peak,
+                }
+            }
+            LoadProfile::Steps(steps) => Load::piecewise(steps.clone(), false)?,
+            LoadProfile::File(path) => Load::piecewise(read_load_points(path)?, true)?,
+        };
+        Ok(load)
+    }
+}
+
+/// Read the (time, rate) points of a load profile from a file. Empty lines
+/// and lines starting with `#` are ignored.
+fn read_load_points(path: &Path) -> anyhow::Result<Vec<(f64, f64)>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read load profile {}", path.display()))?;
+
+    let mut points = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let point = line
+            .split_once(',')
+            .and_then(|(time, rate)| Some((time.trim().parse().ok()?, rate.trim().parse().ok()?)))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid time and rate in {}, line {}",
+                    path.display(),
+                    i + 1
+                )
+            })?;
+        points.push(point);
+    }
+    Ok(points)
+}
+
+/// A load profile that transforms start times
+#[derive(Debug)]
+pub enum Load {
+    Diurnal {
+        period: f64,
+        amplitude: f64,
+        peak: f64,
+    },
+    Piecewise {
+        /// (time, rate) points with increasing times
+        points: Vec<(f64, f64)>,
+        /// Whether the rate changes linearly between the points, or stays
+        /// constant until the next one
+        linear: bool,
+    },
+}
+
+impl Load {
+    fn piecewise(points: Vec<(f64, f64)>, linear: bool) -> anyhow::Result<Load> {
+        if points.is_empty() {
+            bail!("A load profile needs at least one rate.");
+        }
+        if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            bail!("The times of a load profile must be increasing.");
+        }
+        if points
+            .iter()
+            .any(|(_, rate)| *rate < 0.0 || !rate.is_finite())
+        {
+            bail!("The rates of a load profile cannot be negative.");
+        }
+        if points.last().unwrap().1 == 0.0 {
+            bail!("A load profile cannot end with a rate of 0.");
+        }
+        Ok(Load::Piecewise { points, linear })
+    }
+
+    /// The operational time at real time `t` [ms], i.e. the integral of the
+    /// rate from 0 to `t`
+    fn operational_time(&self, t: f64) -> f64 {
+        match self {
+            Load::Diurnal {
+                period,
+                amplitude,
+                peak,
+            } => {
+                let phase = |t: f64| (2.0 * PI * (t - peak) / period).sin();
+                t + amplitude * period / (2.0 * PI) * (phase(t) - phase(0.0))
+            }
+            Load::Piecewise { .. } => self.integral(t) - self.integral(0.0),
+        }
+    }
+
+    /// The integral of a piecewise rate from the first point to `t`, which
+    /// is negative before the first point
+    fn integral(&self, t: f64) -> f64 {
+        let Load::Piecewise { points, linear } = self else {
+            unreachable!()
+        };
+
+        let (first_time, first_rate) = points[0];
+        if t <= first_time {
+            return first_rate * (t - first_time);
+        }
+        let mut integral = 0.0;
+        for pair in points.windows(2) {
+            let ((from, from_rate), (to, to_rate)) = (pair[0], pair[1]);
+            let end = t.min(to);
+            let end_rate = match linear {
+                true => from_rate + (to_rate - from_rate) * (end - from) / (to - from),
+                false => from_rate,
+            };
+            integral += (from_rate + end_rate) / 2.0 * (end - from);
+            if t <= to {
+                return integral;
+            }
+        }
+        let (last_time, last_rate) = points[points.len() - 1];
+        integral + last_rate * (t - last_time)
+    }
+
+    /// The real time [ms] at which `wait` [ms] of operational time have
+    /// passed since real time `start` [ms]
+    pub fn advance(&self, start: f64, wait: f64) -> f64 {
+        let target = self.operational_time(start) + wait;
+
+        // the operational time grows without bound, so find an upper limit
+        // and bisect
+        let mut low = start;
+        let mut high = start + wait.max(1.0);
+        while self.operational_time(high) < target {
+            low = high;
+            high += (high - start) * 2.0;
+        }
+        while high - low > 1e-3 {
+            let middle = (low + high) / 2.0;
+            if self.operational_time(middle) < target {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        high
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance() {
+        // twice the rate from 1s on
+        let load = Load::piecewise(vec![(0.0, 1.0), (1000.0, 2.0)], false).unwrap();
+        assert!((load.advance(0.0, 500.0) - 500.0).abs() < 0.01);
+        assert!((load.advance(500.0, 1000.0) - 1250.0).abs() < 0.01);
+
+        // the same amount of operational time passes in each period
+        let load = LoadProfile::Diurnal {
+            period: 1000.0,
+            amplitude: 0.9,
+            peak: 250.0,
+        }
+        .make_load()
+        .unwrap();
+        assert!((load.advance(0.0, 2000.0) - 2000.0).abs() < 0.01);
+        assert!(load.advance(0.0, 250.0) < 250.0);
+    }
+}
@@ -1,11 +1,24 @@
 mod analyze;
 mod bench;
 mod cli;
+mod cover;
 mod destination;
+mod fit;
 mod generate;
+mod import_pcap;
+mod import_shadow;
+mod load;
+mod mix;
 mod network;
+mod pcap;
 mod plot;
+mod provenance;
+mod response;
+mod rng;
+mod scenario;
 mod source;
+mod stats;
+mod tor;
 mod trace;
 
 use cli::Cli;
@@ -15,11 +28,23 @@ fn main() -> anyhow::Result<()> {
 
     match cli.command {
         cli::Commands::Generate(args) => {
-            generate::run(args)?;
+            generate::run(*args)?;
         }
         cli::Commands::Analyze(args) => {
             analyze::run(args)?;
         }
+        cli::Commands::Stats(args) => {
+            stats::run(args)?;
+        }
+        cli::Commands::Fit(args) => {
+            fit::run(args)?;
+        }
+        cli::Commands::ImportPcap(args) => {
+            import_pcap::run(args)?;
+        }
+        cli::Commands::ImportShadow(args) => {
+            import_shadow::run(args)?;
+        }
     }
 
     Ok(())
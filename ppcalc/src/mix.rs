@@ -0,0 +1,277 @@
+use std::fmt;
+
+use rand::seq::index;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use time::macros::datetime;
+use time::{Duration, PrimitiveDateTime};
+
+use ppcalc_metric::TraceEntry;
+
+use crate::cli::ParsedDistribution;
+
+/// The batching strategy of a mix that all messages pass on their way to
+/// their destinations
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum MixModel {
+    /// Collect messages until there are `n` of them, then flush them all
+    Threshold { n: usize },
+    /// Flush all collected messages every `interval` [ms]
+    Timed { interval: f64 },
+    /// Collect messages until there are `n + pool` of them, then flush `n`
+    /// randomly chosen ones and keep the rest
+    Pool { n: usize, pool: usize },
+    /// Every `interval` [ms], let each collected message leave with the given
+    /// probability
+    Binomial { interval: f64, probability: f64 },
+    /// Delay each message individually by a sample from the distribution [ms],
+    /// like Stop-and-Go mixes or Loopix
+    Continuous(ParsedDistribution<f64>),
+}
+
+/// Format the model like it is given on the command line
+impl fmt::Display for MixModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MixModel::Threshold { n } => write!(f, "threshold:{}", n),
+            MixModel::Timed { interval } => write!(f, "timed:{}", interval),
+            MixModel::Pool { n, pool } => write!(f, "pool:{}:{}", n, pool),
+            MixModel::Binomial {
+                interval,
+                probability,
+            } => write!(f, "binomial:{}:{}", interval, probability),
+            MixModel::Continuous(distribution) => write!(f, "continuous:{}", distribution),
+        }
+    }
+}
+
+/// Send the messages through a single mix, starting at their current
+/// destination timestamps, and delay them until the mix outputs them.
+///
+/// Messages that are still in a threshold or pool mix after the last message
+/// arrived are flushed at that time.
+pub fn apply_mix(
+    entries: &mut [TraceEntry],
+    mix: &MixModel,
+    rng: &mut impl Rng,
+) -> anyhow::Result<()> {
+    // process the messages in the order they arrive at the mix
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| entries[i].destination_timestamp);
+    let Some(&last) = order.last() else {
+        return Ok(());
+    };
+    let last_arrival = entries[last].destination_timestamp;
+
+    match *mix {
+        MixModel::Threshold { n } => {
+            for batch in order.chunks(n) {
+                let flush = match batch.len() {
+                    len if len == n => entries[batch[n - 1]].destination_timestamp,
+                    _ => last_arrival,
+                };
+                for &i in batch {
+                    entries[i].destination_timestamp = flush;
+                }
+            }
+        }
+        MixModel::Timed { interval } => {
+            let interval = Duration::seconds_f64(interval / 1000.0);
+            for i in order {
+                entries[i].destination_timestamp =
+                    next_round(entries[i].destination_timestamp, interval);
+            }
+        }
+        MixModel::Pool { n, pool } => {
+            let mut collected = Vec::new();
+            for i in order {
+                collected.push(i);
+                if collected.len() < n + pool {
+                    continue;
+                }
+                let flush = entries[i].destination_timestamp;
+                let mut chosen = index::sample(rng, collected.len(), n).into_vec();
+                // remove from the back, so the remaining indices stay valid
+                chosen.sort_unstable();
+                for position in chosen.into_iter().rev() {
+                    entries[collected.swap_remove(position)].destination_timestamp = flush;
+                }
+            }
+            for i in collected {
+                entries[i].destination_timestamp = last_arrival;
+            }
+        }
+        MixModel::Binomial {
+            interval,
+            probability,
+        } => {
+            let interval = Duration::seconds_f64(interval / 1000.0);
+            let mut round = next_round(entries[order[0]].destination_timestamp, interval);
+            let mut collected: Vec<usize> = Vec::new();
+            let mut arrivals = order.into_iter().peekable();
+            while arrivals.peek().is_some() || !collected.is_empty() {
+                while let Some(&i) = arrivals.peek() {
+                    if entries[i].destination_timestamp >= round {
+                        break;
+                    }
+                    collected.push(i);
+                    arrivals.next();
+                }
+                collected.retain(|&i| {
+                    if rng.gen_bool(probability) {
+                        entries[i].destination_timestamp = round;
+                        false
+                    } else {
+                        true
+                    }
+                });
+                round = match arrivals.peek() {
+                    // skip the rounds in which the mix is empty anyway
+                    Some(&i) if collected.is_empty() => {
+                        next_round(entries[i].destination_timestamp, interval)
+                    }
+                    _ => round + interval,
+                };
+            }
+        }
+        MixModel::Continuous(ref distribution) => {
+            let distr = distribution.make_distr().map_err(|e| anyhow::anyhow!(e))?;
+            for entry in entries.iter_mut() {
+                let delay = distr.sample(rng).max(0.0);
+                entry.destination_timestamp += Duration::seconds_f64(delay / 1000.0);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The shortest flushing interval of a mix [ms], as its rounds are counted
+/// in whole nanoseconds
+pub const MIN_INTERVAL: f64 = 0.000001;
+
+/// The first point in time after `time` that is a multiple of `interval`
+fn next_round(time: PrimitiveDateTime, interval: Duration) -> PrimitiveDateTime {
+    let epoch = datetime!(1970-01-01 0:00);
+    let interval = interval.whole_nanoseconds().max(1);
+    let rounds = (time - epoch).whole_nanoseconds() / interval + 1;
+    epoch + Duration::nanoseconds((rounds * interval) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_distribution;
+    use crate::rng::{self, Stage};
+    use ppcalc_metric::{DestinationId, Direction, MessageId, SourceId};
+
+    /// Messages arriving at the mix at the given times [ms]
+    fn arrivals(times: &[i64]) -> Vec<TraceEntry> {
+        let epoch = datetime!(1970-01-01 0:00);
+        times
+            .iter()
+            .enumerate()
+            .map(|(i, time)| TraceEntry {
+                m_id: MessageId::new(i as u64),
+                source_id: SourceId::new(0),
+                source_timestamp: epoch,
+                destination_id: DestinationId::new(0),
+                destination_timestamp: epoch + Duration::milliseconds(*time),
+                session: 0,
+                dummy: false,
+                dropped: false,
+                direction: Direction::Upstream,
+                retransmission: false,
+            })
+            .collect()
+    }
+
+    /// The times [ms] at which the mix outputs the messages of `times`
+    fn mix_times(mix: MixModel, times: &[i64]) -> Vec<i64> {
+        let mut entries = arrivals(times);
+        apply_mix(&mut entries, &mix, &mut rng::stage_rng(1, Stage::Delays)).unwrap();
+        entries
+            .iter()
+            .map(|entry| {
+                (entry.destination_timestamp - datetime!(1970-01-01 0:00)).whole_milliseconds()
+                    as i64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rounds() {
+        let epoch = datetime!(1970-01-01 0:00);
+        let interval = Duration::milliseconds(10);
+        assert_eq!(next_round(epoch, interval), epoch + interval);
+        assert_eq!(
+            next_round(epoch + Duration::milliseconds(5), interval),
+            epoch + interval
+        );
+        assert_eq!(next_round(epoch + interval, interval), epoch + interval * 2);
+    }
+
+    #[test]
+    fn threshold() {
+        // the incomplete last batch is flushed with the last arrival
+        assert_eq!(
+            mix_times(MixModel::Threshold { n: 3 }, &[4, 1, 2, 3, 5]),
+            vec![5, 3, 3, 3, 5]
+        );
+    }
+
+    #[test]
+    fn timed() {
+        assert_eq!(
+            mix_times(MixModel::Timed { interval: 10.0 }, &[1, 9, 10, 15]),
+            vec![10, 10, 20, 20]
+        );
+    }
+
+    #[test]
+    fn pool() {
+        // two of the three collected messages leave with the third and fifth
+        // arrival, the one left in the pool is flushed at the end
+        let times = [1, 2, 3, 4, 5];
+        let output = mix_times(MixModel::Pool { n: 2, pool: 1 }, &times);
+        assert!(output
+            .iter()
+            .zip(times)
+            .all(|(out, arrival)| *out >= arrival));
+        let mut sorted = output.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, vec![3, 3, 5, 5, 5]);
+        assert_eq!(&output[3..], &[5, 5]);
+    }
+
+    #[test]
+    fn binomial() {
+        // everything leaves in the next round if the probability is 1
+        let all = MixModel::Binomial {
+            interval: 10.0,
+            probability: 1.0,
+        };
+        assert_eq!(mix_times(all, &[1, 9, 10, 15]), vec![10, 10, 20, 20]);
+
+        // otherwise, messages stay for a number of rounds, but all of them leave
+        let half = MixModel::Binomial {
+            interval: 10.0,
+            probability: 0.5,
+        };
+        let times: Vec<i64> = (0..100).collect();
+        let output = mix_times(half, &times);
+        assert!(output
+            .iter()
+            .zip(&times)
+            .all(|(out, arrival)| out > arrival && out % 10 == 0));
+        assert!(output
+            .iter()
+            .zip(&times)
+            .any(|(out, arrival)| out - arrival > 10));
+    }
+
+    #[test]
+    fn continuous() {
+        let delay = MixModel::Continuous(parse_distribution("constant:5").unwrap());
+        assert_eq!(mix_times(delay, &[3, 1, 2]), vec![8, 6, 7]);
+    }
+}
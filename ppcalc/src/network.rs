@@ -1,58 +1,883 @@
 use crate::trace;
-use std::collections::HashMap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
 
-use crate::cli::ParsedDistribution;
+use anyhow::{anyhow, bail, Context};
+use rand::Rng;
+use rand_distr::{Distribution, Exp};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use time::PrimitiveDateTime;
 
-use ppcalc_metric::{DestinationId, MessageId, SourceId, Trace, TraceBuilder, TraceEntry};
+use crate::cli::{self, parse_distribution, ParsedDistribution};
+use crate::mix::{self, MixModel};
+use crate::response::{self, ResponseModel};
+use crate::rng::{self, StageRng};
+
+use ppcalc_metric::{DestinationId, Direction, SourceId, TraceEntry, TraceWriter};
+
+/// How messages are delayed on their way through the network
+pub struct DelayModel {
+    /// Delay of each individual message [ms]
+    pub message_delay: ParsedDistribution<u64>,
+    /// Persistent base latency of each source-destination path [ms]
+    pub path_latency: Option<ParsedDistribution<u64>>,
+    /// Additional delay of the messages to each destination [ms]
+    pub destination_latencies: Option<Vec<ParsedDistribution<u64>>>,
+    /// Whether messages of the same stream keep their order
+    pub fifo: bool,
+    /// Shared queues that the messages pass before reaching their destination
+    pub queue: Option<Queueing>,
+    /// A mix that the messages pass last
+    pub mix: Option<MixModel>,
+    /// Delays of the real messages of each stream, in the order they are sent,
+    /// that are used instead of sampling delays and latencies
+    pub reused_delays: Option<trace::SessionDelays>,
+    /// Responses that the destinations send back to the sources
+    pub responses: Option<Responses>,
+    /// Loss of messages, and their retransmission
+    pub loss: Option<Loss>,
+}
+
+/// Loss of messages on their way through the network
+pub struct Loss {
+    /// Probability that a message is lost
+    pub probability: f64,
+    /// Time after which the source sends a lost message again [ms]. Without
+    /// it, lost messages are dropped silently.
+    pub retransmission_timeout: Option<ParsedDistribution<f64>>,
+}
+
+/// Responses of the destinations, split into messages of the given size
+pub struct Responses {
+    pub model: ResponseModel,
+    pub message_size: u64,
+}
+
+/// The service times of a queue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum QueueModel {
+    /// Exponentially distributed service times, i.e. an M/M/1 queue for Poisson arrivals
+    MM1 {
+        /// Mean service time [ms]
+        mean_service_time: f64,
+    },
+    /// A constant service time for every message
+    Deterministic {
+        /// Service time [ms]
+        service_time: f64,
+    },
+}
+
+/// Format the model like it is given on the command line
+impl fmt::Display for QueueModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueModel::MM1 { mean_service_time } => write!(f, "mm1:{}", mean_service_time),
+            QueueModel::Deterministic { service_time } => {
+                write!(f, "deterministic:{}", service_time)
+            }
+        }
+    }
+}
+
+impl QueueModel {
+    /// Draw the service time of a message [ms]
+    fn service_time(&self, rng: &mut impl Rng) -> f64 {
+        match *self {
+            QueueModel::MM1 { mean_service_time } => {
+                Exp::new(1.0 / mean_service_time).unwrap().sample(rng)
+            }
+            QueueModel::Deterministic { service_time } => service_time,
+        }
+    }
+}
+
+/// Where the queues are located
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum QueueLocation {
+    /// One queue for the link to each destination
+    Destination,
+    /// A number of shared relays with one queue each. Each stream is routed
+    /// via a uniformly chosen relay.
+    Relays { count: u64 },
+}
+
+/// Format the location like it is given on the command line
+impl fmt::Display for QueueLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueLocation::Destination => write!(f, "destination"),
+            QueueLocation::Relays { count } => write!(f, "relays:{}", count),
+        }
+    }
+}
+
+/// Congestion by shared single-server FIFO queues
+pub struct Queueing {
+    pub model: QueueModel,
+    pub location: QueueLocation,
+}
+
+/// Read the latency profiles of the first `number_of_destinations`
+/// destinations from a file containing one distribution per line.
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn read_destination_latencies(
+    path: impl AsRef<Path>,
+    number_of_destinations: u64,
+) -> anyhow::Result<Vec<ParsedDistribution<u64>>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read destination latencies from {}", path.display()))?;
+
+    let profiles = content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .take(number_of_destinations as usize)
+        .map(|line| {
+            parse_distribution::<u64>(line)
+                .map_err(|e| anyhow!(e))
+                .with_context(|| {
+                    format!("Invalid latency profile \"{}\" in {}", line, path.display())
+                })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if (profiles.len() as u64) < number_of_destinations {
+        bail!(
+            "{} contains only {} latency profiles, but there are {} destinations.",
+            path.display(),
+            profiles.len(),
+            number_of_destinations
+        );
+    }
+    Ok(profiles)
+}
+
+/// Number of messages whose delays are sampled in parallel
+const DELAY_BATCH_SIZE: usize = 1 << 16;
+
+/// The most messages that may be in flight at once. Messages stay in flight
+/// for their delay, so this limits the longest delay to the time in which the
+/// sources send this many messages.
+const MAX_IN_FLIGHT: usize = 1 << 24;
+
+/// The most messages that the stages that need the whole trace at once may
+/// collect in memory. These are cover traffic, queues, mixes, responses and
+/// the Tor simulation; all other stages stream the messages.
+pub const MAX_COLLECTED_MESSAGES: usize = 1 << 26;
+
+/// Collect the messages for a stage that needs all of them at once, failing
+/// if there are more than [`MAX_COLLECTED_MESSAGES`]
+pub fn collect_messages<T>(
+    messages: impl Iterator<Item = T>,
+    stage: &str,
+) -> anyhow::Result<Vec<T>> {
+    let mut collected = Vec::new();
+    for message in messages {
+        collected.push(message);
+        check_collected(collected.len(), stage)?;
+    }
+    Ok(collected)
+}
+
+/// Fail if a stage that needs all messages at once collected more than
+/// [`MAX_COLLECTED_MESSAGES`]
+pub fn check_collected(num_messages: usize, stage: &str) -> anyhow::Result<()> {
+    if num_messages > MAX_COLLECTED_MESSAGES {
+        bail!(
+            "{} needs all messages in memory, but there are more than {}.",
+            stage,
+            MAX_COLLECTED_MESSAGES
+        );
+    }
+    Ok(())
+}
 
 // It is important that this is (to some extend) reproducable, so we can change/analyse the destination distribution!
-// Lets maybe only create the entries we need?
+/// Delay the messages, which must be ordered by their source timestamps, and
+/// write them to `output` in the order of their arrival.
+///
+/// Without queues, mixes and responses, this only keeps the messages in memory
+/// that are still in flight: as delays are never negative, a message can be
+/// written as soon as the following messages are sent after its arrival. At
+/// most [`MAX_IN_FLIGHT`] messages may be in flight at once. Otherwise, all
+/// messages are collected, up to [`MAX_COLLECTED_MESSAGES`].
+///
+/// The delays are sampled in parallel batches, with a separate random number
+/// stream per source, so the result does not depend on the number of threads.
 pub fn generate_network_delay(
-    delay_distribution: &ParsedDistribution<u64>,
-    pre_network_trace: Vec<trace::PreNetworkTraceEntry>,
-) -> Trace {
-    let mut m_id = 0;
-    let distr = delay_distribution.make_distr().unwrap();
-    let mut rng = rand::thread_rng();
-
-    let mut trace = TraceBuilder::new();
-    for entry in pre_network_trace {
-        let delay = distr.sample(&mut rng);
-
-        trace.add_entry(TraceEntry {
-            m_id: MessageId::new(m_id),
-            source_id: entry.source_id,
-            source_timestamp: entry.source_timestamp,
-            destination_id: entry.destination_id,
-            destination_timestamp: entry
-                .source_timestamp
-                .checked_add(time::Duration::from(time::Duration::milliseconds(
-                    delay as i64,
-                )))
-                .unwrap(),
+    delay_model: &DelayModel,
+    pre_network_trace: impl Iterator<Item = trace::PreNetworkTraceEntry>,
+    seed: u64,
+    output: &mut TraceWriter,
+) -> anyhow::Result<()> {
+    let sampler = DelaySampler::new(delay_model, seed)?;
+    let mut sources = HashMap::new();
+    let mut pre_network_trace = pre_network_trace.peekable();
+    let batches = std::iter::from_fn(|| {
+        pre_network_trace.peek()?;
+        Some(
+            pre_network_trace
+                .by_ref()
+                .take(DELAY_BATCH_SIZE)
+                .collect::<Vec<_>>(),
+        )
+    });
+
+    if delay_model.queue.is_none() && delay_model.mix.is_none() && delay_model.responses.is_none() {
+        let mut in_flight = ReorderBuffer::new(MAX_IN_FLIGHT);
+        for batch in batches {
+            for entry in sampler.delay_batch(batch, &mut sources)? {
+                // retransmissions are sent later than the following messages
+                if !entry.retransmission {
+                    in_flight.write_arrived_until(entry.source_timestamp, output)?;
+                }
+                in_flight.push(entry)?;
+            }
+        }
+        in_flight.write_all(output)?;
+        return Ok(());
+    }
+
+    // Queues, mixes and responses depend on the arrival order of all messages,
+    // so they are applied to the whole trace at once.
+    let mut entries = Vec::new();
+    for batch in batches {
+        entries.extend(sampler.delay_batch(batch, &mut sources)?);
+        check_collected(entries.len(), "The queues, mixes and responses")?;
+    }
+    let (dropped, mut entries): (Vec<_>, Vec<_>) =
+        entries.into_iter().partition(|entry| entry.dropped);
+
+    // the delays so far lead to the queues, which then add the congestion delay
+    let mut rng = rng::stage_rng(seed, rng::Stage::Delays);
+    if let Some(ref queueing) = delay_model.queue {
+        apply_queueing(&mut entries, queueing, &mut rng);
+    }
+    if let Some(ref mix) = delay_model.mix {
+        mix::apply_mix(&mut entries, mix, &mut rng)?;
+    }
+
+    // the responses only pass the network delay on their way back
+    if let Some(ref responses) = delay_model.responses {
+        let downstream =
+            response::responses(&entries, &responses.model, responses.message_size, seed)?;
+        entries.extend(sampler.delay_batch(downstream, &mut sources)?);
+    }
+
+    entries.extend(dropped);
+    entries.sort_by_key(|entry| entry.destination_timestamp);
+    for entry in entries {
+        output.write_entry(entry).map_err(|e| anyhow!(e))?;
+    }
+    Ok(())
+}
+
+/// Samples the delays of the messages
+struct DelaySampler<'a> {
+    delay_model: &'a DelayModel,
+    seed: u64,
+    distr: Box<dyn cli::ErasedDistribution<u64>>,
+    path_latency_distr: Option<Box<dyn cli::ErasedDistribution<u64>>>,
+    destination_latency_distrs: Option<Vec<Box<dyn cli::ErasedDistribution<u64>>>>,
+    retransmission_timeout_distr: Option<Box<dyn cli::ErasedDistribution<f64>>>,
+}
+
+/// The delay state of a source
+struct SourceDelays {
+    source_id: SourceId,
+    rng: StageRng,
+    /// base latencies, drawn when a path is used for the first time
+    path_latencies: HashMap<DestinationId, u64>,
+    /// latest arrival time per stream and direction, to keep streams in order
+    last_arrivals: HashMap<(u64, Direction), PrimitiveDateTime>,
+    /// number of messages sent per stream, to find their reused delays
+    num_sent: HashMap<u64, usize>,
+}
+
+impl<'a> DelaySampler<'a> {
+    fn new(delay_model: &'a DelayModel, seed: u64) -> anyhow::Result<DelaySampler<'a>> {
+        Ok(DelaySampler {
+            delay_model,
+            seed,
+            distr: delay_model
+                .message_delay
+                .make_distr()
+                .map_err(|e| anyhow!(e))?,
+            path_latency_distr: match delay_model.path_latency {
+                Some(ref distribution) => Some(distribution.make_distr().map_err(|e| anyhow!(e))?),
+                None => None,
+            },
+            destination_latency_distrs: match delay_model.destination_latencies {
+                Some(ref profiles) => Some(
+                    profiles
+                        .iter()
+                        .map(|profile| profile.make_distr().map_err(|e| anyhow!(e)))
+                        .collect::<anyhow::Result<Vec<_>>>()?,
+                ),
+                None => None,
+            },
+            retransmission_timeout_distr: match delay_model
+                .loss
+                .as_ref()
+                .and_then(|loss| loss.retransmission_timeout.as_ref())
+            {
+                Some(distribution) => Some(distribution.make_distr().map_err(|e| anyhow!(e))?),
+                None => None,
+            },
+        })
+    }
+
+    /// Delay a batch of messages, keeping their order. The messages of each
+    /// source are delayed in parallel to those of the other sources.
+    fn delay_batch(
+        &self,
+        batch: Vec<trace::PreNetworkTraceEntry>,
+        sources: &mut HashMap<SourceId, SourceDelays>,
+    ) -> anyhow::Result<Vec<TraceEntry>> {
+        let num_entries = batch.len();
+        let mut groups: HashMap<SourceId, Vec<(usize, trace::PreNetworkTraceEntry)>> =
+            HashMap::new();
+        for (i, entry) in batch.into_iter().enumerate() {
+            groups.entry(entry.source_id).or_default().push((i, entry));
+        }
+
+        let mut groups: Vec<_> = groups
+            .into_iter()
+            .map(|(source_id, entries)| {
+                let state = sources.remove(&source_id).unwrap_or_else(|| SourceDelays {
+                    source_id,
+                    rng: rng::source_rng(self.seed, rng::Stage::Delays, source_id),
+                    path_latencies: HashMap::new(),
+                    last_arrivals: HashMap::new(),
+                    num_sent: HashMap::new(),
+                });
+                (state, entries)
+            })
+            .collect();
+
+        let delayed: anyhow::Result<Vec<Vec<(usize, TraceEntry)>>> = groups
+            .par_iter_mut()
+            .map(|(state, entries)| {
+                let mut delayed = Vec::with_capacity(entries.len());
+                for (i, entry) in entries.drain(..) {
+                    self.transmit(i, entry, state, &mut delayed)?;
+                }
+                Ok(delayed)
+            })
+            .collect();
+        for (state, _) in groups {
+            sources.insert(state.source_id, state);
+        }
+
+        let mut result = Vec::with_capacity(num_entries);
+        result.extend(delayed?.into_iter().flatten());
+        // stable, so that lost messages stay before their retransmissions
+        result.sort_by_key(|(i, _)| *i);
+        Ok(result.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Send the `i`-th message of a batch over the network, where it may be
+    /// lost. A lost message is dropped and, after a timeout, sent again,
+    /// possibly several times until it arrives.
+    fn transmit(
+        &self,
+        i: usize,
+        mut entry: trace::PreNetworkTraceEntry,
+        source: &mut SourceDelays,
+        output: &mut Vec<(usize, TraceEntry)>,
+    ) -> anyhow::Result<()> {
+        let mut retransmission = false;
+        if let Some(ref loss) = self.delay_model.loss {
+            while !entry.dropped && source.rng.gen_bool(loss.probability) {
+                let mut lost = entry.clone();
+                lost.dropped = true;
+                let mut lost = self.delay(lost, source)?;
+                lost.retransmission = retransmission;
+                output.push((i, lost));
+
+                let Some(ref timeout_distr) = self.retransmission_timeout_distr else {
+                    return Ok(());
+                };
+                let timeout = timeout_distr.sample(&mut source.rng);
+                entry.source_timestamp += time::Duration::seconds_f64(timeout / 1000.0);
+                retransmission = true;
+            }
+        }
+
+        let mut delayed = self.delay(entry, source)?;
+        delayed.retransmission = retransmission;
+        output.push((i, delayed));
+        Ok(())
+    }
+
+    /// Delay a message of the given source
+    fn delay(
+        &self,
+        entry: trace::PreNetworkTraceEntry,
+        source: &mut SourceDelays,
+    ) -> anyhow::Result<TraceEntry> {
+        // dropped messages do not pass the network
+        if entry.dropped {
+            let source_timestamp = entry.source_timestamp;
+            return Ok(entry.into_trace_entry(source_timestamp));
+        }
+
+        let mut destination_timestamp = match self.delay_model.reused_delays {
+            Some(ref reused_delays) if !entry.dummy && entry.direction == Direction::Upstream => {
+                let index = source.num_sent.entry(entry.session).or_insert(0);
+                let delay = reused_delays
+                    .get(&(entry.source_id, entry.session))
+                    .and_then(|delays| delays.get(*index))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "There is no delay to reuse for message {} of session {} of source {}.",
+                            index,
+                            entry.session,
+                            entry.source_id.to_num()
+                        )
+                    })?;
+                *index += 1;
+                entry.source_timestamp + *delay
+            }
+            _ => {
+                let rng = &mut source.rng;
+                let mut delay = self.distr.sample(rng);
+                if let Some(ref path_latency_distr) = self.path_latency_distr {
+                    delay += *source
+                        .path_latencies
+                        .entry(entry.destination_id)
+                        .or_insert_with(|| path_latency_distr.sample(rng));
+                }
+                if let Some(ref destination_latency_distrs) = self.destination_latency_distrs {
+                    delay += destination_latency_distrs[entry.destination_id.to_num() as usize]
+                        .sample(rng);
+                }
+
+                entry
+                    .source_timestamp
+                    .checked_add(time::Duration::from(time::Duration::milliseconds(
+                        delay as i64,
+                    )))
+                    .unwrap()
+            }
+        };
+        if self.delay_model.fifo {
+            let last_arrival = source
+                .last_arrivals
+                .entry((entry.session, entry.direction))
+                .or_insert(destination_timestamp);
+            destination_timestamp = destination_timestamp.max(*last_arrival);
+            *last_arrival = destination_timestamp;
+        }
+
+        Ok(entry.into_trace_entry(destination_timestamp))
+    }
+}
+
+/// Messages that are in flight, to be written in the order of their arrival
+struct ReorderBuffer {
+    entries: BinaryHeap<InFlight>,
+    next_index: u64,
+    /// The most messages that may be in flight at once
+    capacity: usize,
+}
+
+/// A message in flight. Messages that arrive at the same time are ordered by
+/// the time they were sent.
+struct InFlight {
+    index: u64,
+    entry: TraceEntry,
+}
+
+impl InFlight {
+    fn key(&self) -> (PrimitiveDateTime, u64) {
+        (self.entry.destination_timestamp, self.index)
+    }
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    // reversed, so that the heap yields the earliest arrival first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+impl ReorderBuffer {
+    fn new(capacity: usize) -> ReorderBuffer {
+        ReorderBuffer {
+            entries: BinaryHeap::new(),
+            next_index: 0,
+            capacity,
+        }
+    }
+
+    /// Add a message, failing if there are too many in flight
+    fn push(&mut self, entry: TraceEntry) -> anyhow::Result<()> {
+        if self.entries.len() == self.capacity {
+            bail!(
+                "More than {} messages are in flight at once. Use shorter delays or fewer sources.",
+                self.capacity
+            );
+        }
+        self.entries.push(InFlight {
+            index: self.next_index,
+            entry,
+        });
+        self.next_index += 1;
+        Ok(())
+    }
+
+    /// Write the messages that arrived until the given time
+    fn write_arrived_until(
+        &mut self,
+        time: PrimitiveDateTime,
+        output: &mut TraceWriter,
+    ) -> anyhow::Result<()> {
+        while let Some(first) = self.entries.peek() {
+            if first.entry.destination_timestamp > time {
+                break;
+            }
+            let first = self.entries.pop().unwrap();
+            output.write_entry(first.entry).map_err(|e| anyhow!(e))?;
+        }
+        Ok(())
+    }
+
+    /// Write all remaining messages
+    fn write_all(mut self, output: &mut TraceWriter) -> anyhow::Result<()> {
+        while let Some(first) = self.entries.pop() {
+            output.write_entry(first.entry).map_err(|e| anyhow!(e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Pass the messages through shared FIFO queues, starting at their current
+/// destination timestamps, and delay them until they leave their queue.
+fn apply_queueing(entries: &mut [TraceEntry], queueing: &Queueing, rng: &mut impl Rng) {
+    // choose the queue of each message
+    let mut relays: HashMap<(SourceId, u64), u64> = HashMap::new();
+    let queues: Vec<u64> = entries
+        .iter()
+        .map(|entry| match queueing.location {
+            QueueLocation::Destination => entry.destination_id.to_num(),
+            QueueLocation::Relays { count } => *relays
+                .entry((entry.source_id, entry.session))
+                .or_insert_with(|| rng.gen_range(0..count)),
+        })
+        .collect();
+
+    // serve the messages in the order they arrive at the queues
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| entries[i].destination_timestamp);
+
+    let mut busy_until: HashMap<u64, PrimitiveDateTime> = HashMap::new();
+    for i in order {
+        let arrival = entries[i].destination_timestamp;
+        let start = match busy_until.get(&queues[i]) {
+            Some(&busy) => arrival.max(busy),
+            None => arrival,
+        };
+        let departure =
+            start + time::Duration::seconds_f64(queueing.model.service_time(rng) / 1000.0);
+        busy_until.insert(queues[i], departure);
+        entries[i].destination_timestamp = departure;
+    }
+}
+
+/// A stream of messages, ordered by their source timestamps
+pub type MessageStream<'a> = Box<dyn Iterator<Item = trace::PreNetworkTraceEntry> + 'a>;
+
+/// Merge streams of messages, each ordered by time, into a single stream
+/// ordered by source timestamps.
+///
+/// This is a k-way merge that only holds the next message of each stream.
+/// Messages sent at the same time keep the order of the streams.
+pub fn merge_traces<'a>(
+    mut inputs: Vec<MessageStream<'a>>,
+) -> impl Iterator<Item = trace::PreNetworkTraceEntry> + 'a {
+    let mut heads = Vec::with_capacity(inputs.len());
+    let mut queue = BinaryHeap::new();
+    for (i, input) in inputs.iter_mut().enumerate() {
+        let head = input.next();
+        if let Some(ref entry) = head {
+            queue.push(Reverse((entry.source_timestamp, i)));
+        }
+        heads.push(head);
+    }
+
+    std::iter::from_fn(move || {
+        let Reverse((_, i)) = queue.pop()?;
+        let next = inputs[i].next();
+        if let Some(ref entry) = next {
+            queue.push(Reverse((entry.source_timestamp, i)));
+        }
+        std::mem::replace(&mut heads[i], next)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{self, Stage};
+    use ppcalc_metric::MessageId;
+    use time::macros::datetime;
+
+    #[test]
+    fn deterministic_queue() {
+        // (destination, arrival [ms]) of each message
+        let arrivals = [(0, 1), (0, 0), (1, 1), (0, 1), (0, 10)];
+        let epoch = datetime!(1970-01-01 0:00);
+        let mut entries: Vec<TraceEntry> = arrivals
+            .iter()
+            .enumerate()
+            .map(|(i, (destination, arrival))| TraceEntry {
+                m_id: MessageId::new(i as u64),
+                source_id: SourceId::new(i as u64),
+                source_timestamp: epoch,
+                destination_id: DestinationId::new(*destination),
+                destination_timestamp: epoch + time::Duration::milliseconds(*arrival),
+                session: 0,
+                dummy: false,
+                dropped: false,
+                direction: Direction::Upstream,
+                retransmission: false,
+            })
+            .collect();
+        let queueing = Queueing {
+            model: QueueModel::Deterministic { service_time: 2.0 },
+            location: QueueLocation::Destination,
+        };
+        apply_queueing(
+            &mut entries,
+            &queueing,
+            &mut rng::stage_rng(1, Stage::Delays),
+        );
+
+        // each destination serves its messages in the order they arrive, and
+        // messages arriving at the same time in the order they were given
+        let departures: Vec<i64> = entries
+            .iter()
+            .map(|entry| (entry.destination_timestamp - epoch).whole_milliseconds() as i64)
+            .collect();
+        assert_eq!(departures, vec![4, 2, 3, 6, 12]);
+    }
+
+    /// Messages of 10 sources, where source i sends every i + 1 ms, so that
+    /// many are sent at the same time
+    fn streams() -> Vec<Vec<trace::PreNetworkTraceEntry>> {
+        let epoch = datetime!(1970-01-01 0:00);
+        (0..10)
+            .map(|i| {
+                (0..200)
+                    .map(|k| trace::PreNetworkTraceEntry {
+                        source_id: SourceId::new(i),
+                        session: k / 100,
+                        source_timestamp: epoch
+                            + time::Duration::milliseconds((k * (i + 1)) as i64),
+                        destination_id: DestinationId::new(i % 3),
+                        dummy: false,
+                        dropped: false,
+                        direction: Direction::Upstream,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn merge(streams: Vec<Vec<trace::PreNetworkTraceEntry>>) -> Vec<trace::PreNetworkTraceEntry> {
+        let streams: Vec<MessageStream> = streams
+            .into_iter()
+            .map(|stream| Box::new(stream.into_iter()) as MessageStream)
+            .collect();
+        merge_traces(streams).collect()
+    }
+
+    #[test]
+    fn merge_order() {
+        // the merge yields what a stable sort of all messages by time does
+        let mut sorted: Vec<_> = streams().into_iter().flatten().collect();
+        sorted.sort_by_key(|entry| entry.source_timestamp);
+        let key = |entry: &trace::PreNetworkTraceEntry| {
+            (entry.source_id, entry.session, entry.source_timestamp)
+        };
+        let merged: Vec<_> = merge(streams()).iter().map(key).collect();
+        assert_eq!(merged, sorted.iter().map(key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn streamed_like_sorted() {
+        let delay_model = DelayModel {
+            message_delay: parse_distribution("uniform:10:1000").unwrap(),
+            path_latency: None,
+            destination_latencies: None,
+            fifo: false,
+            queue: None,
+            mix: None,
+            reused_delays: None,
+            responses: None,
+            loss: Some(Loss {
+                probability: 0.2,
+                retransmission_timeout: Some(parse_distribution("uniform:100:300").unwrap()),
+            }),
+        };
+        let write = |name: &str, entries: &mut dyn FnMut(&mut TraceWriter)| {
+            let path = std::env::temp_dir().join(format!("ppcalc_test_{}.csv", name));
+            let mut output = TraceWriter::create(&path).unwrap();
+            entries(&mut output);
+            output.finish().unwrap();
+            let content = fs::read_to_string(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            content
+        };
+
+        // streamed through the reorder buffer
+        let streamed = write("streamed", &mut |output| {
+            generate_network_delay(&delay_model, merge(streams()).into_iter(), 3, output).unwrap()
         });
-        m_id += 1;
-    }
-    trace.fix();
-    trace.build().unwrap()
-}
-
-/* Todo we have sorted vectors of timestamps, this should be doable in something like timestamps * log(sources) */
-pub fn merge_traces(
-    source_traces: Vec<trace::SourceTrace>,
-    source_destination_map: &HashMap<SourceId, DestinationId>,
-) -> Vec<trace::PreNetworkTraceEntry> {
-    let mut pre_network_trace = vec![];
-    for trace in source_traces {
-        let destination_id = source_destination_map.get(&trace.source_id).unwrap();
-        for ts in trace.timestamps {
-            pre_network_trace.push(trace::PreNetworkTraceEntry {
-                source_id: trace.source_id,
-                source_timestamp: ts,
-                destination_id: *destination_id,
-            });
-        }
-    }
-    pre_network_trace.sort_by(|a, b| a.source_timestamp.cmp(&b.source_timestamp));
-    pre_network_trace
+        // delayed at once and sorted by arrival
+        let sorted = write("sorted", &mut |output| {
+            let sampler = DelaySampler::new(&delay_model, 3).unwrap();
+            let mut entries = sampler
+                .delay_batch(merge(streams()), &mut HashMap::new())
+                .unwrap();
+            entries.sort_by_key(|entry| entry.destination_timestamp);
+            for entry in entries {
+                output.write_entry(entry).unwrap();
+            }
+        });
+        assert!(streamed.lines().count() > 2000);
+        assert_eq!(streamed, sorted);
+    }
+
+    #[test]
+    fn in_flight_limit() {
+        let entry = |sent: i64, delay: i64| {
+            let epoch = datetime!(1970-01-01 0:00);
+            trace::PreNetworkTraceEntry {
+                source_id: SourceId::new(0),
+                session: 0,
+                source_timestamp: epoch + time::Duration::milliseconds(sent),
+                destination_id: DestinationId::new(0),
+                dummy: false,
+                dropped: false,
+                direction: Direction::Upstream,
+            }
+            .into_trace_entry(epoch + time::Duration::milliseconds(sent + delay))
+        };
+        let path = std::env::temp_dir().join("ppcalc_test_in_flight.csv");
+        let mut output = TraceWriter::create(&path).unwrap();
+
+        let mut in_flight = ReorderBuffer::new(2);
+        in_flight.push(entry(0, 10)).unwrap();
+        in_flight.push(entry(1, 10)).unwrap();
+        assert!(in_flight.push(entry(2, 10)).is_err());
+        // once a message has arrived, there is room again
+        in_flight
+            .write_arrived_until(entry(10, 0).source_timestamp, &mut output)
+            .unwrap();
+        in_flight.push(entry(10, 10)).unwrap();
+        assert!(in_flight.push(entry(10, 10)).is_err());
+        in_flight.write_all(&mut output).unwrap();
+        assert_eq!(output.finish().unwrap(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn lossy_model(probability: f64, retransmission_timeout: Option<&str>) -> DelayModel {
+        DelayModel {
+            message_delay: parse_distribution("uniform:10:100").unwrap(),
+            path_latency: None,
+            destination_latencies: None,
+            fifo: false,
+            queue: None,
+            mix: None,
+            reused_delays: None,
+            responses: None,
+            loss: Some(Loss {
+                probability,
+                retransmission_timeout: retransmission_timeout
+                    .map(|timeout| parse_distribution(timeout).unwrap()),
+            }),
+        }
+    }
+
+    #[test]
+    fn dropped() {
+        // without retransmissions, lost messages only appear at their source
+        let delay_model = lossy_model(1.0, None);
+        let sampler = DelaySampler::new(&delay_model, 5).unwrap();
+        let messages = merge(streams());
+        let entries = sampler
+            .delay_batch(messages.clone(), &mut HashMap::new())
+            .unwrap();
+        assert_eq!(entries.len(), messages.len());
+        for (entry, message) in entries.iter().zip(messages.iter()) {
+            assert!(entry.dropped && !entry.retransmission);
+            assert_eq!(entry.source_timestamp, message.source_timestamp);
+            assert_eq!(entry.destination_timestamp, entry.source_timestamp);
+        }
+    }
+
+    #[test]
+    fn retransmitted() {
+        let delay_model = lossy_model(0.3, Some("uniform:100:200"));
+        let sampler = DelaySampler::new(&delay_model, 5).unwrap();
+        let messages = merge(streams());
+        let entries = sampler
+            .delay_batch(messages.clone(), &mut HashMap::new())
+            .unwrap();
+
+        // The entries of each message are its lost copies, which only appear
+        // at the source, followed by the one that arrives. Each copy is sent
+        // after the timeout of the previous one.
+        let mut entries = entries.into_iter();
+        let mut num_retransmitted = 0;
+        for message in messages {
+            let mut sent = message.source_timestamp;
+            let mut lost = 0;
+            loop {
+                let entry = entries.next().unwrap();
+                assert_eq!(entry.source_id, message.source_id);
+                assert_eq!(entry.retransmission, lost > 0);
+                if lost == 0 {
+                    assert_eq!(entry.source_timestamp, sent);
+                } else {
+                    assert!(entry.source_timestamp - sent >= time::Duration::milliseconds(100));
+                }
+                sent = entry.source_timestamp;
+                if !entry.dropped {
+                    assert!(entry.destination_timestamp > entry.source_timestamp);
+                    break;
+                }
+                assert_eq!(entry.destination_timestamp, entry.source_timestamp);
+                lost += 1;
+            }
+            if lost > 0 {
+                num_retransmitted += 1;
+            }
+        }
+        assert!(entries.next().is_none());
+        assert!(num_retransmitted > 100);
+    }
 }
@@ -0,0 +1,456 @@
+//! Minimal reader for pcap and pcapng capture files.
+//!
+//! Only what is needed to turn captured IP packets into trace messages is
+//! supported: timestamps, the transport 5-tuple and the payload length.
+
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use time::macros::datetime;
+use time::PrimitiveDateTime;
+
+/// Transport protocol of a captured packet
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// The classic 5-tuple identifying a transport-layer flow
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FiveTuple {
+    pub protocol: Protocol,
+    pub src_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_ip: IpAddr,
+    pub dst_port: u16,
+}
+
+impl fmt::Display for FiveTuple {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let endpoint = |ip: &IpAddr, port: u16| match ip {
+            IpAddr::V4(ip) => format!("{}:{}", ip, port),
+            IpAddr::V6(ip) => format!("[{}]:{}", ip, port),
+        };
+        write!(
+            f,
+            "{}:{}->{}",
+            self.protocol,
+            endpoint(&self.src_ip, self.src_port),
+            endpoint(&self.dst_ip, self.dst_port)
+        )
+    }
+}
+
+/// A captured TCP or UDP packet
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub timestamp: PrimitiveDateTime,
+    pub five_tuple: FiveTuple,
+    pub payload_len: usize,
+}
+
+/// Read all TCP and UDP packets from a pcap or pcapng file.
+///
+/// Packets of other protocols, or packets that cannot be decoded, are skipped.
+pub fn read_packets(path: impl AsRef<Path>) -> anyhow::Result<Vec<Packet>> {
+    let path = path.as_ref();
+    let data = fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+
+    let frames = if data.len() >= 4 && data[0..4] == [0x0a, 0x0d, 0x0d, 0x0a] {
+        read_pcapng_frames(&data)
+    } else {
+        read_pcap_frames(&data)
+    }
+    .with_context(|| format!("Cannot parse capture file {}", path.display()))?;
+
+    Ok(frames
+        .into_iter()
+        .filter_map(|frame| {
+            let (five_tuple, payload_len) = decode_frame(frame.linktype, frame.data)?;
+            Some(Packet {
+                timestamp: datetime!(1970-01-01 0:00)
+                    + time::Duration::nanoseconds(frame.timestamp_ns),
+                five_tuple,
+                payload_len,
+            })
+        })
+        .collect())
+}
+
+/// A raw link-layer frame from a capture file
+struct Frame<'a> {
+    timestamp_ns: i64,
+    linktype: u32,
+    data: &'a [u8],
+}
+
+/// Byte order aware integer access to a capture file
+#[derive(Copy, Clone)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(&self, data: &[u8], offset: usize) -> anyhow::Result<u16> {
+        let bytes: [u8; 2] = data
+            .get(offset..offset + 2)
+            .ok_or_else(|| anyhow!("Unexpected end of file"))?
+            .try_into()
+            .unwrap();
+        Ok(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, data: &[u8], offset: usize) -> anyhow::Result<u32> {
+        let bytes: [u8; 4] = data
+            .get(offset..offset + 4)
+            .ok_or_else(|| anyhow!("Unexpected end of file"))?
+            .try_into()
+            .unwrap();
+        Ok(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+fn slice(data: &[u8], start: usize, len: usize) -> anyhow::Result<&[u8]> {
+    data.get(start..start + len)
+        .ok_or_else(|| anyhow!("Unexpected end of file"))
+}
+
+fn read_pcap_frames(data: &[u8]) -> anyhow::Result<Vec<Frame<'_>>> {
+    let magic = slice(data, 0, 4)?;
+    let (endian, nanoseconds) = match magic {
+        [0xa1, 0xb2, 0xc3, 0xd4] => (Endian { big: true }, false),
+        [0xd4, 0xc3, 0xb2, 0xa1] => (Endian { big: false }, false),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (Endian { big: true }, true),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (Endian { big: false }, true),
+        _ => bail!("Not a pcap or pcapng file"),
+    };
+    let linktype = endian.u32(data, 20)? & 0x0fff_ffff;
+
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let seconds = endian.u32(data, offset)? as i64;
+        let fraction = endian.u32(data, offset + 4)? as i64;
+        let captured_len = endian.u32(data, offset + 8)? as usize;
+        let timestamp_ns = seconds * 1_000_000_000
+            + if nanoseconds {
+                fraction
+            } else {
+                fraction * 1000
+            };
+
+        frames.push(Frame {
+            timestamp_ns,
+            linktype,
+            data: slice(data, offset + 16, captured_len)?,
+        });
+        offset += 16 + captured_len;
+    }
+
+    Ok(frames)
+}
+
+fn read_pcapng_frames(data: &[u8]) -> anyhow::Result<Vec<Frame<'_>>> {
+    const SECTION_HEADER: u32 = 0x0a0d0d0a;
+    const INTERFACE_DESCRIPTION: u32 = 0x00000001;
+    const ENHANCED_PACKET: u32 = 0x00000006;
+
+    // (linktype, timestamp units per second) per interface of the current section
+    let mut interfaces: Vec<(u32, u64)> = Vec::new();
+    let mut endian = Endian { big: false };
+    let mut frames = Vec::new();
+
+    let mut offset = 0;
+    while offset < data.len() {
+        // the block type of a section header is a palindrome, so it can be
+        // read before the byte order is known
+        let block_type = endian.u32(data, offset)?;
+        if block_type == SECTION_HEADER {
+            endian = match slice(data, offset + 8, 4)? {
+                [0x1a, 0x2b, 0x3c, 0x4d] => Endian { big: true },
+                [0x4d, 0x3c, 0x2b, 0x1a] => Endian { big: false },
+                _ => bail!("Invalid pcapng byte-order magic"),
+            };
+            interfaces.clear();
+        }
+
+        let block_len = endian.u32(data, offset + 4)? as usize;
+        if block_len < 12 {
+            bail!("Invalid pcapng block length {}", block_len);
+        }
+        let body = slice(data, offset + 8, block_len - 12)?;
+
+        match block_type {
+            INTERFACE_DESCRIPTION => {
+                let linktype = endian.u16(body, 0)? as u32;
+                interfaces.push((linktype, interface_resolution(body, endian)?));
+            }
+            ENHANCED_PACKET => {
+                let interface = endian.u32(body, 0)? as usize;
+                let (linktype, resolution) = *interfaces
+                    .get(interface)
+                    .ok_or_else(|| anyhow!("Packet refers to unknown interface {}", interface))?;
+                let timestamp = ((endian.u32(body, 4)? as u64) << 32) | endian.u32(body, 8)? as u64;
+                let captured_len = endian.u32(body, 12)? as usize;
+
+                let seconds = timestamp / resolution;
+                let fraction = timestamp % resolution;
+                let timestamp_ns = seconds as i64 * 1_000_000_000
+                    + (fraction as u128 * 1_000_000_000 / resolution as u128) as i64;
+
+                frames.push(Frame {
+                    timestamp_ns,
+                    linktype,
+                    data: slice(body, 20, captured_len)?,
+                });
+            }
+            _ => {
+                // other blocks carry no timestamped packets
+            }
+        }
+
+        offset += block_len;
+    }
+
+    Ok(frames)
+}
+
+/// Get the timestamp resolution (units per second) from the options of an
+/// interface description block body
+fn interface_resolution(body: &[u8], endian: Endian) -> anyhow::Result<u64> {
+    const OPTION_END: u16 = 0;
+    const OPTION_TSRESOL: u16 = 9;
+
+    let mut offset = 8;
+    while offset + 4 <= body.len() {
+        let code = endian.u16(body, offset)?;
+        let len = endian.u16(body, offset + 2)? as usize;
+        if code == OPTION_END {
+            break;
+        }
+        if code == OPTION_TSRESOL && len >= 1 {
+            let value = *body
+                .get(offset + 4)
+                .ok_or_else(|| anyhow!("Truncated timestamp resolution option"))?;
+            let resolution = if value & 0x80 != 0 {
+                1u64.checked_shl((value & 0x7f) as u32)
+            } else {
+                10u64.checked_pow(value as u32)
+            };
+            return resolution
+                .ok_or_else(|| anyhow!("Unsupported timestamp resolution {:#04x}", value));
+        }
+        // option values are padded to 32 bits
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+
+    // default: microseconds
+    Ok(1_000_000)
+}
+
+/// Decode a link-layer frame into its 5-tuple and transport payload length
+fn decode_frame(linktype: u32, data: &[u8]) -> Option<(FiveTuple, usize)> {
+    const LINKTYPE_NULL: u32 = 0;
+    const LINKTYPE_ETHERNET: u32 = 1;
+    const LINKTYPE_RAW: u32 = 101;
+    const LINKTYPE_LINUX_SLL: u32 = 113;
+    const LINKTYPE_IPV4: u32 = 228;
+    const LINKTYPE_IPV6: u32 = 229;
+    const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+    let ip_packet = match linktype {
+        LINKTYPE_NULL => data.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes([*data.get(12)?, *data.get(13)?]);
+            let mut offset = 14;
+            // skip (possibly stacked) VLAN tags
+            while ethertype == 0x8100 || ethertype == 0x88a8 {
+                ethertype = u16::from_be_bytes([*data.get(offset + 2)?, *data.get(offset + 3)?]);
+                offset += 4;
+            }
+            if ethertype != 0x0800 && ethertype != 0x86dd {
+                return None;
+            }
+            data.get(offset..)?
+        }
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => data,
+        LINKTYPE_LINUX_SLL => data.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => data.get(20..)?,
+        _ => return None,
+    };
+
+    decode_ip(ip_packet)
+}
+
+fn decode_ip(data: &[u8]) -> Option<(FiveTuple, usize)> {
+    let (protocol, src_ip, dst_ip, transport) = match data.first()? >> 4 {
+        4 => {
+            let header_len = ((data[0] & 0x0f) as usize) * 4;
+            let total_len = u16::from_be_bytes([*data.get(2)?, *data.get(3)?]) as usize;
+            let src: [u8; 4] = data.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = data.get(16..20)?.try_into().ok()?;
+            (
+                *data.get(9)?,
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                // the capture may be truncated, so keep the length from the header
+                (data.get(header_len..)?, total_len.checked_sub(header_len)?),
+            )
+        }
+        6 => {
+            let payload_len = u16::from_be_bytes([*data.get(4)?, *data.get(5)?]) as usize;
+            let src: [u8; 16] = data.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = data.get(24..40)?.try_into().ok()?;
+            (
+                *data.get(6)?,
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                (data.get(40..)?, payload_len),
+            )
+        }
+        _ => return None,
+    };
+    let (segment, segment_len) = transport;
+
+    let src_port = u16::from_be_bytes([*segment.first()?, *segment.get(1)?]);
+    let dst_port = u16::from_be_bytes([*segment.get(2)?, *segment.get(3)?]);
+    let (protocol, payload_len) = match protocol {
+        6 => {
+            let header_len = ((segment.get(12)? >> 4) as usize) * 4;
+            (Protocol::Tcp, segment_len.saturating_sub(header_len))
+        }
+        17 => (Protocol::Udp, segment_len.saturating_sub(8)),
+        _ => return None,
+    };
+
+    Some((
+        FiveTuple {
+            protocol,
+            src_ip,
+            src_port,
+            dst_ip,
+            dst_port,
+        },
+        payload_len,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A raw IPv4/UDP packet from 10.0.0.1:1000 to 10.0.0.2:2000 with 4 payload bytes
+    fn udp_packet() -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 32, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        packet.extend_from_slice(&[0x03, 0xe8, 0x07, 0xd0, 0, 12, 0, 0]);
+        packet.extend_from_slice(b"ping");
+        packet
+    }
+
+    fn read_from_bytes(file_name: &str, data: Vec<u8>) -> Vec<Packet> {
+        let path = std::env::temp_dir().join(file_name);
+        fs::write(&path, data).unwrap();
+        let packets = read_packets(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        packets
+    }
+
+    fn check_packets(packets: &[Packet]) {
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0].five_tuple.to_string(),
+            "udp:10.0.0.1:1000->10.0.0.2:2000"
+        );
+        assert_eq!(packets[0].payload_len, 4);
+        assert_eq!(packets[0].timestamp, datetime!(1970-01-01 0:00:01.5));
+    }
+
+    #[test]
+    fn read_pcap() {
+        let packet = udp_packet();
+        let mut data = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&65535u32.to_le_bytes());
+        data.extend_from_slice(&101u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&500_000u32.to_le_bytes());
+        data.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        data.extend_from_slice(&packet);
+
+        let packets = read_from_bytes("ppcalc_read_pcap_test.pcap", data);
+        check_packets(&packets);
+    }
+
+    #[test]
+    fn read_pcapng() {
+        let packet = udp_packet();
+        let block = |block_type: u32, body: Vec<u8>| {
+            let len = (body.len() + 12) as u32;
+            let mut block = block_type.to_le_bytes().to_vec();
+            block.extend_from_slice(&len.to_le_bytes());
+            block.extend_from_slice(&body);
+            block.extend_from_slice(&len.to_le_bytes());
+            block
+        };
+
+        let mut section = vec![0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0];
+        section.extend_from_slice(&[0xff; 8]);
+        // interface with millisecond resolution
+        let mut interface = vec![101, 0, 0, 0, 0, 0, 0, 0];
+        interface.extend_from_slice(&[9, 0, 1, 0, 3, 0, 0, 0, 0, 0, 0, 0]);
+        let mut enhanced = vec![0; 4];
+        enhanced.extend_from_slice(&0u32.to_le_bytes());
+        enhanced.extend_from_slice(&1500u32.to_le_bytes());
+        enhanced.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        enhanced.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        enhanced.extend_from_slice(&packet);
+
+        let mut data = block(0x0a0d0d0a, section);
+        data.extend(block(1, interface));
+        data.extend(block(6, enhanced));
+
+        let packets = read_from_bytes("ppcalc_read_pcapng_test.pcapng", data);
+        check_packets(&packets);
+    }
+
+    #[test]
+    fn timestamp_resolutions() {
+        // an interface description body with only the resolution option
+        let resolution = |value: u8| {
+            let mut body = vec![101, 0, 0, 0, 0, 0, 0, 0];
+            body.extend_from_slice(&[9, 0, 1, 0, value, 0, 0, 0]);
+            interface_resolution(&body, Endian { big: false })
+        };
+        assert_eq!(resolution(9).unwrap(), 1_000_000_000);
+        assert_eq!(resolution(0x80 | 10).unwrap(), 1024);
+        assert!(resolution(20).is_err());
+        assert!(resolution(0x80 | 64).is_err());
+        assert!(resolution(0xff).is_err());
+    }
+}
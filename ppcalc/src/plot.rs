@@ -21,9 +21,9 @@ pub fn deanonymized_users_over_time(
         let remaining_anonymity_set;
         let last_message = messages.last();
         let Some(last_message) = last_message else {
-                println!("skipped.");
-                continue;
-            };
+            println!("skipped.");
+            continue;
+        };
 
         remaining_anonymity_set = last_message.1.len();
         if let Some(destination_id) = net_trace.get_destination_mapping().get(&last_message.0) {
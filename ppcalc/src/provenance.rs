@@ -0,0 +1,169 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+/// Provenance of a generated trace, saved in a sidecar file next to it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TraceMetadata {
+    pub tool: Tool,
+    /// The parameters the trace was generated with
+    pub parameters: serde_json::Value,
+    /// Seed of the random number generator
+    pub seed: Option<u64>,
+    /// SHA-256 hash of the trace file
+    pub sha256: String,
+}
+
+/// Provenance of an analysis result, saved in a sidecar file next to it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnalysisMetadata {
+    pub tool: Tool,
+    /// The parameters of the analysis
+    pub parameters: serde_json::Value,
+    /// SHA-256 hash of the analyzed trace file
+    pub input_sha256: String,
+    /// Provenance of the analyzed trace, if it is known
+    pub trace: Option<TraceMetadata>,
+}
+
+/// The tool that produced a file, and when
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tool {
+    pub name: String,
+    pub version: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+}
+
+impl Tool {
+    /// Describe this program at the current time
+    pub fn current() -> Tool {
+        Tool {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: OffsetDateTime::now_utc(),
+        }
+    }
+}
+
+impl TraceMetadata {
+    /// Save the metadata next to the trace file it belongs to
+    pub fn write_for_trace(&self, trace_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        fs::write(
+            metadata_path(trace_path),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    /// Load the metadata of a trace file, if there is any
+    pub fn read_for_trace(trace_path: impl AsRef<Path>) -> anyhow::Result<Option<TraceMetadata>> {
+        let path = metadata_path(trace_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let metadata = serde_json::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("Invalid trace metadata in {}", path.display()))?;
+        Ok(Some(metadata))
+    }
+}
+
+impl AnalysisMetadata {
+    /// Save the metadata next to the output file it belongs to
+    pub fn write_for_output(&self, output_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        fs::write(
+            metadata_path(output_path),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Get the path of the metadata sidecar file of a trace, e.g. `trace.csv.meta.json`
+pub fn metadata_path(trace_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = trace_path.as_ref().as_os_str().to_owned();
+    path.push(".meta.json");
+    path.into()
+}
+
+/// Compute the SHA-256 hash of a file, as a hex string
+pub fn hash_file(path: impl AsRef<Path>) -> anyhow::Result<String> {
+    let path = path.as_ref();
+    let mut file =
+        fs::File::open(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_and_hashes() {
+        assert_eq!(
+            metadata_path("out/trace.csv"),
+            PathBuf::from("out/trace.csv.meta.json")
+        );
+
+        let path = std::env::temp_dir().join("ppcalc_test_hash.txt");
+        fs::write(&path, "abc").unwrap();
+        let hash = hash_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(hash_file(&path).is_err());
+    }
+
+    #[test]
+    fn metadata_roundtrip() {
+        let trace_path = std::env::temp_dir().join("ppcalc_test_provenance.csv");
+        assert!(TraceMetadata::read_for_trace(&trace_path)
+            .unwrap()
+            .is_none());
+
+        let metadata = TraceMetadata {
+            tool: Tool::current(),
+            parameters: serde_json::json!({ "sources": 10 }),
+            seed: Some(42),
+            sha256: "00ff".to_string(),
+        };
+        metadata.write_for_trace(&trace_path).unwrap();
+        let read = TraceMetadata::read_for_trace(&trace_path).unwrap().unwrap();
+        fs::remove_file(metadata_path(&trace_path)).unwrap();
+        assert_eq!(read.tool.name, metadata.tool.name);
+        assert_eq!(read.tool.version, metadata.tool.version);
+        assert_eq!(read.tool.timestamp, metadata.tool.timestamp);
+        assert_eq!(read.parameters, metadata.parameters);
+        assert_eq!(read.seed, Some(42));
+        assert_eq!(read.sha256, "00ff");
+
+        // the analysis of the trace embeds its provenance
+        let output_path = std::env::temp_dir().join("ppcalc_test_provenance.json");
+        AnalysisMetadata {
+            tool: Tool::current(),
+            parameters: serde_json::json!({}),
+            input_sha256: "00ff".to_string(),
+            trace: Some(read),
+        }
+        .write_for_output(&output_path)
+        .unwrap();
+        let path = metadata_path(&output_path);
+        let read: AnalysisMetadata =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.trace.unwrap().seed, Some(42));
+    }
+}
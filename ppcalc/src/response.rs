@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use time::{Duration, PrimitiveDateTime};
+
+use ppcalc_metric::{DestinationId, Direction, SourceId, TraceEntry};
+
+use crate::cli::ParsedDistribution;
+use crate::rng;
+use crate::trace::PreNetworkTraceEntry;
+
+/// How the destinations respond to the sessions of the sources
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseModel {
+    /// Size of the response to a session [B]
+    pub size: ParsedDistribution<u64>,
+    /// Time between the arrival of the last message of a session and the
+    /// start of the response [ms]
+    pub think_time: ParsedDistribution<f64>,
+    /// Bandwidth that the response is sent with [Mbit/s]
+    pub bandwidth: ParsedDistribution<f64>,
+}
+
+/// Generate the responses of the destinations to the sessions of the sources,
+/// given their delayed messages, ordered by the time they are sent.
+///
+/// Each destination responds once the last real message of a session arrived
+/// and its think time passed. The response is split into messages of the
+/// given size, that are sent at the response's bandwidth.
+pub fn responses(
+    entries: &[TraceEntry],
+    model: &ResponseModel,
+    message_size: u64,
+    seed: u64,
+) -> anyhow::Result<Vec<PreNetworkTraceEntry>> {
+    let size_distr = model.size.make_distr().map_err(|e| anyhow!(e))?;
+    let think_time_distr = model.think_time.make_distr().map_err(|e| anyhow!(e))?;
+    let bandwidth_distr = model.bandwidth.make_distr().map_err(|e| anyhow!(e))?;
+
+    // the destination and last arrival of each session
+    let mut sessions: BTreeMap<(SourceId, u64), (DestinationId, PrimitiveDateTime)> =
+        BTreeMap::new();
+    for entry in entries
+        .iter()
+        .filter(|x| x.direction == Direction::Upstream && !x.dummy && !x.dropped)
+    {
+        let last_arrival = sessions
+            .entry((entry.source_id, entry.session))
+            .or_insert((entry.destination_id, entry.destination_timestamp));
+        last_arrival.1 = last_arrival.1.max(entry.destination_timestamp);
+    }
+
+    // every source draws from its own random number stream, like when it is generated
+    let mut responses = Vec::new();
+    let mut source_rng = None;
+    for ((source_id, session), (destination_id, last_arrival)) in sessions {
+        let rng = match source_rng {
+            Some((id, ref mut rng)) if id == source_id => rng,
+            _ => {
+                &mut source_rng
+                    .insert((
+                        source_id,
+                        rng::source_rng(seed, rng::Stage::Responses, source_id),
+                    ))
+                    .1
+            }
+        };
+
+        let size = size_distr.sample(rng);
+        let think_time = think_time_distr.sample(rng);
+        let bandwidth = bandwidth_distr.sample(rng); // Mbit/s
+        if think_time.is_nan() || think_time < 0.0 {
+            bail!(
+                "The think time of a response must not be negative, but it is {} ms.",
+                think_time
+            );
+        }
+        if bandwidth.is_nan() || bandwidth <= 0.0 {
+            bail!(
+                "The bandwidth of a response must be positive, but it is {} Mbit/s.",
+                bandwidth
+            );
+        }
+        let bandwidth = (bandwidth * 1024.0 * 1024.0) / (8.0 * 1000.0 * 1000.0); // B/µs
+
+        let num_messages = size.div_ceil(message_size);
+        let imd = Duration::microseconds((message_size as f64 / bandwidth) as i64);
+        let mut time = last_arrival + Duration::seconds_f64(think_time / 1000.0);
+        for _ in 0..num_messages {
+            time += imd;
+            responses.push(PreNetworkTraceEntry {
+                source_id,
+                session,
+                source_timestamp: time,
+                destination_id,
+                dummy: false,
+                dropped: false,
+                direction: Direction::Downstream,
+            });
+        }
+    }
+    responses.sort_by_key(|entry| entry.source_timestamp);
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_distribution;
+    use ppcalc_metric::MessageId;
+    use time::macros::datetime;
+
+    fn arrival(source: u64, session: u64, destination: u64, ms: i64, dummy: bool) -> TraceEntry {
+        let timestamp = datetime!(1970-01-01 0:00) + Duration::milliseconds(ms);
+        TraceEntry {
+            m_id: MessageId::new(0),
+            source_id: SourceId::new(source),
+            source_timestamp: timestamp - Duration::milliseconds(10),
+            destination_id: DestinationId::new(destination),
+            destination_timestamp: timestamp,
+            session,
+            dummy,
+            dropped: false,
+            direction: Direction::Upstream,
+            retransmission: false,
+        }
+    }
+
+    fn model(size: &str, think_time: &str, bandwidth: &str) -> ResponseModel {
+        ResponseModel {
+            size: parse_distribution(size).unwrap(),
+            think_time: parse_distribution(think_time).unwrap(),
+            bandwidth: parse_distribution(bandwidth).unwrap(),
+        }
+    }
+
+    #[test]
+    fn timing() {
+        let entries = [
+            arrival(0, 0, 2, 100, false),
+            arrival(0, 0, 2, 300, false),
+            arrival(0, 0, 2, 1000, true),
+            arrival(1, 0, 1, 500, false),
+        ];
+        // 1 B/µs, so that the messages of 514 B are sent 514 µs apart
+        let model = model("constant:1000", "constant:50", "constant:7.62939453125");
+        let responses = responses(&entries, &model, 514, 42).unwrap();
+
+        let start = datetime!(1970-01-01 0:00);
+        let expected = [
+            (0, 2, 350_514),
+            (0, 2, 351_028),
+            (1, 1, 550_514),
+            (1, 1, 551_028),
+        ];
+        assert_eq!(responses.len(), expected.len());
+        for (response, (source, destination, us)) in responses.iter().zip(expected) {
+            assert_eq!(response.source_id, SourceId::new(source));
+            assert_eq!(response.destination_id, DestinationId::new(destination));
+            assert_eq!(response.session, 0);
+            assert_eq!(
+                response.source_timestamp,
+                start + Duration::microseconds(us)
+            );
+            assert_eq!(response.direction, Direction::Downstream);
+            assert!(!response.dummy);
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let entries: Vec<_> = (0..10)
+            .map(|source| arrival(source, 0, source % 3, 100, false))
+            .collect();
+        let model = model("uniform:1:5000", "uniform:0:100", "uniform:1:10");
+        let timestamps = |seed| {
+            responses(&entries, &model, 514, seed)
+                .unwrap()
+                .iter()
+                .map(|response| (response.source_id, response.source_timestamp))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps(1), timestamps(1));
+        assert_ne!(timestamps(1), timestamps(2));
+    }
+
+    #[test]
+    fn invalid_samples() {
+        let entries = [arrival(0, 0, 0, 100, false)];
+        for (think_time, bandwidth, error) in [
+            ("constant:-1", "constant:1", "think time"),
+            ("constant:0", "constant:0", "bandwidth"),
+        ] {
+            let model = model("constant:1000", think_time, bandwidth);
+            let Err(err) = responses(&entries, &model, 514, 42) else {
+                panic!("the {} is not checked", error);
+            };
+            assert!(err.to_string().contains(error), "{}", err);
+        }
+    }
+}
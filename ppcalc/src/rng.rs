@@ -0,0 +1,94 @@
+//! Seeded random number generation, so that traces can be reproduced.
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+
+use ppcalc_metric::SourceId;
+
+/// The random number generator used for generating traces
+pub type StageRng = ChaCha12Rng;
+
+/// A stage of the trace generation that draws from its own random number stream.
+///
+/// The stage takes up the lowest [`STAGE_BITS`] bits of the stream number of
+/// [`source_rng`], so there can be at most 256 stages.
+#[derive(Copy, Clone, Debug)]
+pub enum Stage {
+    Sources = 0,
+    Destinations = 1,
+    Delays = 2,
+    Circuits = 3,
+    Cover = 4,
+    Responses = 5,
+}
+
+/// The number of bits of a stream number that identify the stage
+pub const STAGE_BITS: u32 = 8;
+
+/// Get the random number generator of a generation stage, derived from the
+/// given seed.
+///
+/// Each stage uses a separate ChaCha stream. Changing the parameters of one
+/// stage, and thus the amount of random numbers it consumes, does not affect
+/// the random numbers of the other stages.
+pub fn stage_rng(seed: u64, stage: Stage) -> StageRng {
+    let mut rng = StageRng::seed_from_u64(seed);
+    rng.set_stream(stage as u64);
+    rng
+}
+
+/// Get the random number generator of a single source in a generation stage,
+/// derived from the given seed.
+///
+/// Sources draw from separate streams, so that they can be generated in
+/// parallel and still produce the same random numbers for any number of
+/// threads. The streams do not overlap with those of [`stage_rng`], as long as
+/// the source ID is below 2^56.
+pub fn source_rng(seed: u64, stage: Stage, source_id: SourceId) -> StageRng {
+    debug_assert!((stage as u64) < 1 << STAGE_BITS);
+    let mut rng = StageRng::seed_from_u64(seed);
+    rng.set_stream(((source_id.to_num() + 1) << STAGE_BITS) | stage as u64);
+    rng
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    const STAGES: [Stage; 6] = [
+        Stage::Sources,
+        Stage::Destinations,
+        Stage::Delays,
+        Stage::Circuits,
+        Stage::Cover,
+        Stage::Responses,
+    ];
+
+    #[test]
+    fn separate_streams() {
+        // the same seed gives the same numbers
+        assert_eq!(
+            stage_rng(1, Stage::Delays).next_u64(),
+            stage_rng(1, Stage::Delays).next_u64()
+        );
+        assert_eq!(
+            source_rng(1, Stage::Sources, SourceId::new(3)).next_u64(),
+            source_rng(1, Stage::Sources, SourceId::new(3)).next_u64()
+        );
+
+        // every stage and source draws different numbers
+        let mut first_numbers = Vec::new();
+        for stage in STAGES {
+            assert!((stage as u64) < 1 << STAGE_BITS);
+            first_numbers.push(stage_rng(1, stage).next_u64());
+            for source in 0..10 {
+                first_numbers.push(source_rng(1, stage, SourceId::new(source)).next_u64());
+            }
+        }
+        let num_streams = first_numbers.len();
+        first_numbers.sort_unstable();
+        first_numbers.dedup();
+        assert_eq!(first_numbers.len(), num_streams);
+    }
+}
@@ -0,0 +1,802 @@
+//! Scenarios describe everything that `generate` simulates: populations of
+//! sources, the destinations and the network. They are either given by the
+//! command line parameters or read from a TOML file like
+//!
+//! ```toml
+//! sources = 100
+//!
+//! [[population]]
+//! name = "web"
+//! proportion = 0.8
+//! bandwidth = "normal:10:2:1:20"
+//! stream_length = "uniform:10000:1000000"
+//! source_wait = "uniform:0:10000"
+//! traffic_model = "web:50000:2000"
+//!
+//! [[population]]
+//! name = "bulk"
+//! proportion = 0.2
+//! bandwidth = "constant:50"
+//! stream_length = "constant:10000000"
+//! source_wait = "uniform:0:10000"
+//! destination_selection = "uniform"
+//! load = "steps:0=1,60000=3"
+//!
+//! [destinations]
+//! count = 20
+//! selection = "zipf:1.2"
+//!
+//! [network]
+//! delay = "uniform:10:50"
+//! fifo = true
+//! ```
+//!
+//! The values use the same syntax as the respective command line parameters.
+//! Each population is a class of users, whose sources are labeled with its
+//! name in the classes file next to the generated trace.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+
+use ppcalc_metric::SourceId;
+
+use crate::cli::{self, GenerateArgs, ParsedDistribution, SampledValue};
+use crate::cover::CoverTraffic;
+use crate::destination::DestinationSelectionType;
+use crate::load::LoadProfile;
+use crate::mix::MixModel;
+use crate::network::{QueueLocation, QueueModel};
+use crate::response::ResponseModel;
+use crate::source::TrafficModel;
+
+/// Everything that is needed to generate a trace
+#[derive(Debug)]
+pub struct Scenario {
+    /// Size of each message [B]
+    pub message_size: u64,
+    pub seed: Option<u64>,
+    /// The sources, with consecutive IDs in the order of the populations
+    pub populations: Vec<Population>,
+    pub destinations: Destinations,
+    pub network: Network,
+}
+
+/// A group of sources that behave alike
+#[derive(Debug)]
+pub struct Population {
+    pub name: String,
+    pub num_sources: u64,
+    /// Bandwidth of each source [Mbit/s]
+    pub bandwidth: ParsedDistribution<f64>,
+    /// Length of a transfer [B]
+    pub stream_length: ParsedDistribution<u64>,
+    /// Time a source waits before sending [ms]
+    pub source_wait: ParsedDistribution<f64>,
+    pub traffic_model: TrafficModel,
+    pub sessions_per_source: ParsedDistribution<u64>,
+    /// Time between the end of a session and the start of the next one [ms]
+    pub session_interval: ParsedDistribution<f64>,
+    /// How the sessions of this population choose their destinations, if it
+    /// differs from the scenario's destination selection
+    pub destination_selection: Option<DestinationSelectionType>,
+    /// Relative rate over time at which the sources and sessions start
+    pub load: Option<LoadProfile>,
+}
+
+#[derive(Debug)]
+pub struct Destinations {
+    pub count: u64,
+    /// How the sessions choose their destinations, i.e. their popularity
+    pub selection: DestinationSelectionType,
+    /// Probability that a session goes to the destination of the previous one
+    pub stickiness: f64,
+    /// How the destinations respond to the sessions, if at all
+    pub responses: Option<ResponseModel>,
+}
+
+#[derive(Debug)]
+pub struct Network {
+    /// Network delay of each message [ms], or of each link in a Tor network
+    pub delay: ParsedDistribution<u64>,
+    pub path_latency: Option<ParsedDistribution<u64>>,
+    pub destination_latencies: Option<PathBuf>,
+    pub fifo: bool,
+    pub queue: Option<QueueModel>,
+    pub queue_location: QueueLocation,
+    pub cover: Vec<CoverTraffic>,
+    pub mix: Option<MixModel>,
+    /// Probability that a message is lost
+    pub loss: f64,
+    /// Time after which a lost message is sent again [ms], if at all
+    pub retransmission_timeout: Option<ParsedDistribution<f64>>,
+    /// Relays of a simulated Tor network
+    pub tor: Option<PathBuf>,
+}
+
+impl Scenario {
+    /// Get the scenario of a `generate` call, either from its scenario file or
+    /// from its command line parameters
+    pub fn from_args(args: &GenerateArgs) -> anyhow::Result<Scenario> {
+        let mut scenario = match args.scenario {
+            Some(ref path) => Scenario::read(path)?,
+            None => ScenarioFile::from(&Scenario {
+                message_size: args.message_size,
+                seed: None,
+                populations: vec![Population {
+                    name: "default".to_string(),
+                    num_sources: args.num_sources.unwrap(),
+                    bandwidth: args.bandwidth.clone().unwrap(),
+                    stream_length: args.stream_length.clone().unwrap(),
+                    source_wait: args.source_wait.clone().unwrap(),
+                    traffic_model: args.traffic_model.clone(),
+                    sessions_per_source: args.sessions_per_source.clone(),
+                    session_interval: args.session_interval.clone(),
+                    destination_selection: None,
+                    load: args.load.clone(),
+                }],
+                destinations: Destinations {
+                    count: args.num_destinations.unwrap(),
+                    selection: args.destination_selection.clone().unwrap(),
+                    stickiness: args.destination_stickiness,
+                    responses: args.response_size.clone().map(|size| ResponseModel {
+                        size,
+                        think_time: args.think_time.clone(),
+                        bandwidth: args.response_bandwidth.clone().unwrap(),
+                    }),
+                },
+                network: Network {
+                    delay: args.network_delay.clone().unwrap(),
+                    path_latency: args.path_latency.clone(),
+                    destination_latencies: args.destination_latencies.clone(),
+                    fifo: args.fifo,
+                    queue: args.queue.clone(),
+                    queue_location: args.queue_location.clone(),
+                    cover: args.cover.clone(),
+                    mix: args.mix.clone(),
+                    loss: args.loss,
+                    retransmission_timeout: args.retransmission_timeout.clone(),
+                    tor: args.tor.clone(),
+                },
+            })
+            // resolve the paths of the parameters in the working directory
+            .parse(&env::current_dir()?)?,
+        };
+        if args.seed.is_some() {
+            scenario.seed = args.seed;
+        }
+        Ok(scenario)
+    }
+
+    /// Read and validate a scenario file. Relative paths in the file are
+    /// relative to the file's directory, and become absolute paths, so that
+    /// the scenario can be written to another directory.
+    pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Scenario> {
+        let path = path.as_ref();
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        let file: ScenarioFile = toml::from_str(&content)
+            .with_context(|| format!("Invalid scenario file {}", path.display()))?;
+        let base = env::current_dir()?.join(path.parent().unwrap_or(Path::new("")));
+        file.parse(&base)
+            .with_context(|| format!("Invalid scenario in {}", path.display()))
+    }
+
+    /// Save the scenario in normalized form, i.e. with all default values and
+    /// the number of sources of each population
+    pub fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let file = ScenarioFile::from(self);
+        fs::write(path, toml::to_string(&file)?)?;
+        Ok(())
+    }
+
+    /// Get the path of the normalized scenario next to a trace, e.g. `trace.csv.scenario.toml`
+    pub fn path_for_trace(trace_path: impl AsRef<Path>) -> PathBuf {
+        let mut path = trace_path.as_ref().as_os_str().to_owned();
+        path.push(".scenario.toml");
+        path.into()
+    }
+
+    pub fn num_sources(&self) -> u64 {
+        self.populations.iter().map(|x| x.num_sources).sum()
+    }
+
+    /// Get the index of the population of a source. The populations have
+    /// consecutive source IDs in the order they are given.
+    pub fn population_index(&self, source_id: SourceId) -> Option<usize> {
+        let mut first_source_id = 0;
+        for (i, population) in self.populations.iter().enumerate() {
+            if source_id.to_num() < first_source_id + population.num_sources {
+                return Some(i);
+            }
+            first_source_id += population.num_sources;
+        }
+        None
+    }
+}
+
+/// A scenario as written in a file
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioFile {
+    /// Total number of sources, required if the populations are given by proportion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sources: Option<u64>,
+    #[serde(default = "default_message_size")]
+    message_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(rename = "population")]
+    populations: Vec<PopulationFile>,
+    destinations: DestinationsFile,
+    network: NetworkFile,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PopulationFile {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sources: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proportion: Option<f64>,
+    bandwidth: String,
+    stream_length: String,
+    source_wait: String,
+    #[serde(default = "default_traffic_model")]
+    traffic_model: String,
+    #[serde(default = "default_sessions_per_source")]
+    sessions_per_source: String,
+    #[serde(default = "default_session_interval")]
+    session_interval: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_selection: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    load: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DestinationsFile {
+    count: u64,
+    selection: String,
+    #[serde(default)]
+    stickiness: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    responses: Option<ResponsesFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResponsesFile {
+    size: String,
+    #[serde(default = "default_think_time")]
+    think_time: String,
+    bandwidth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetworkFile {
+    delay: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_latency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_latencies: Option<PathBuf>,
+    #[serde(default)]
+    fifo: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_location: Option<String>,
+    #[serde(default)]
+    cover: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mix: Option<String>,
+    #[serde(default)]
+    loss: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retransmission_timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tor: Option<PathBuf>,
+}
+
+fn default_message_size() -> u64 {
+    514
+}
+
+fn default_traffic_model() -> String {
+    "constant".to_string()
+}
+
+fn default_sessions_per_source() -> String {
+    "constant:1".to_string()
+}
+
+fn default_session_interval() -> String {
+    "constant:0".to_string()
+}
+
+fn default_think_time() -> String {
+    "constant:0".to_string()
+}
+
+/// Parse a value of the scenario, naming the field if it is invalid
+fn parse_field<T>(
+    field: &str,
+    value: &str,
+    parser: impl Fn(&str) -> Result<T, String>,
+) -> anyhow::Result<T> {
+    parser(value).map_err(|e| anyhow!("Invalid {} \"{}\": {}", field, value, e))
+}
+
+/// Parse a distribution of the scenario, with file paths relative to `base`,
+/// and check that it can be built
+fn parse_distribution_field<T: SampledValue + Copy>(
+    field: &str,
+    value: &str,
+    base: &Path,
+) -> anyhow::Result<ParsedDistribution<T>> {
+    let distribution = parse_field(field, value, cli::parse_distribution::<T>)?.relative_to(base);
+    distribution
+        .make_distr()
+        .map_err(|e| anyhow!("Invalid {} \"{}\": {}", field, value, e))?;
+    Ok(distribution)
+}
+
+/// Parse a destination selection of the scenario, with file paths relative to `base`
+fn parse_destination_selection_field(
+    value: &str,
+    base: &Path,
+) -> anyhow::Result<DestinationSelectionType> {
+    Ok(
+        match parse_field(
+            "destination selection",
+            value,
+            cli::parse_destination_selection_type,
+        )? {
+            DestinationSelectionType::Weights { file } => DestinationSelectionType::Weights {
+                file: base.join(file),
+            },
+            DestinationSelectionType::Distribution(distribution) => {
+                DestinationSelectionType::Distribution(distribution.relative_to(base))
+            }
+            selection => selection,
+        },
+    )
+}
+
+/// Parse a load profile of the scenario, with file paths relative to `base`,
+/// and check that its file can be read
+fn parse_load_field(value: &str, base: &Path) -> anyhow::Result<LoadProfile> {
+    let load = parse_field("load", value, cli::parse_load_profile)?.relative_to(base);
+    load.make_load()
+        .map_err(|e| anyhow!("Invalid load \"{}\": {}", value, e))?;
+    Ok(load)
+}
+
+impl ScenarioFile {
+    fn parse(self, base: &Path) -> anyhow::Result<Scenario> {
+        if self.message_size == 0 {
+            bail!("The message size must be positive.");
+        }
+
+        let counts = self.population_sizes()?;
+        let mut names = HashSet::new();
+        let mut populations = Vec::with_capacity(self.populations.len());
+        for (population, num_sources) in self.populations.into_iter().zip(counts) {
+            if !names.insert(population.name.clone()) {
+                bail!(
+                    "There are several populations named \"{}\".",
+                    population.name
+                );
+            }
+            populations.push(
+                population
+                    .parse(num_sources, base)
+                    .with_context(|| format!("In population \"{}\"", population.name))?,
+            );
+        }
+
+        let destinations = self.destinations;
+        if destinations.count == 0 {
+            bail!("There must be at least one destination.");
+        }
+        let selection = parse_destination_selection_field(&destinations.selection, base)?;
+        if !(0.0..=1.0).contains(&destinations.stickiness) {
+            bail!("The destination stickiness is not a probability between 0 and 1.");
+        }
+        let responses = match destinations.responses {
+            Some(ref responses) => Some(ResponseModel {
+                size: parse_distribution_field("response size", &responses.size, base)?,
+                think_time: parse_distribution_field("think time", &responses.think_time, base)?,
+                bandwidth: parse_distribution_field(
+                    "response bandwidth",
+                    &responses.bandwidth,
+                    base,
+                )?,
+            }),
+            None => None,
+        };
+
+        let network = self.network;
+        let tor = network.tor.map(|path| base.join(path));
+        if tor.is_some()
+            && (network.path_latency.is_some()
+                || network.destination_latencies.is_some()
+                || network.fifo
+                || network.queue.is_some()
+                || network.mix.is_some())
+        {
+            bail!("A Tor network cannot be combined with path latencies, destination latencies, FIFO ordering, queues or a mix.");
+        }
+        if tor.is_some() && (responses.is_some() || network.loss > 0.0) {
+            bail!("A Tor network cannot be combined with responses or loss.");
+        }
+        if !(0.0..=1.0).contains(&network.loss) {
+            bail!("The loss must be a probability between 0 and 1.");
+        }
+        if network.queue.is_none() && network.queue_location.is_some() {
+            bail!("A queue location requires a queue.");
+        }
+
+        Ok(Scenario {
+            message_size: self.message_size,
+            seed: self.seed,
+            populations,
+            destinations: Destinations {
+                count: destinations.count,
+                selection,
+                stickiness: destinations.stickiness,
+                responses,
+            },
+            network: Network {
+                delay: parse_distribution_field("network delay", &network.delay, base)?,
+                path_latency: match network.path_latency {
+                    Some(ref x) => Some(parse_distribution_field("path latency", x, base)?),
+                    None => None,
+                },
+                destination_latencies: network.destination_latencies.map(|path| base.join(path)),
+                fifo: network.fifo,
+                queue: match network.queue {
+                    Some(ref x) => Some(parse_field("queue", x, cli::parse_queue_model)?),
+                    None => None,
+                },
+                queue_location: match network.queue_location {
+                    Some(ref x) => parse_field("queue location", x, cli::parse_queue_location)?,
+                    None => QueueLocation::Destination,
+                },
+                cover: network
+                    .cover
+                    .iter()
+                    .map(|x| {
+                        Ok(
+                            match parse_field("cover traffic", x, cli::parse_cover_traffic)? {
+                                CoverTraffic::Adaptive(x) => {
+                                    CoverTraffic::Adaptive(x.relative_to(base))
+                                }
+                                CoverTraffic::Loop(x) => CoverTraffic::Loop(x.relative_to(base)),
+                                CoverTraffic::Drop(x) => CoverTraffic::Drop(x.relative_to(base)),
+                                cover => cover,
+                            },
+                        )
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+                mix: match network.mix {
+                    Some(ref x) => Some(match parse_field("mix", x, cli::parse_mix_model)? {
+                        MixModel::Continuous(x) => MixModel::Continuous(x.relative_to(base)),
+                        mix => mix,
+                    }),
+                    None => None,
+                },
+                loss: network.loss,
+                retransmission_timeout: match network.retransmission_timeout {
+                    Some(ref x) => {
+                        Some(parse_distribution_field("retransmission timeout", x, base)?)
+                    }
+                    None => None,
+                },
+                tor,
+            },
+        })
+    }
+
+    /// Determine the number of sources of each population, either given
+    /// directly or as proportions of the total number of sources.
+    ///
+    /// Proportions are rounded by the largest remainder method, so that the
+    /// populations add up to the total.
+    fn population_sizes(&self) -> anyhow::Result<Vec<u64>> {
+        if self.populations.is_empty() {
+            bail!("There must be at least one population.");
+        }
+
+        let by_proportion = self.populations.iter().any(|x| x.proportion.is_some());
+        for population in self.populations.iter() {
+            match (population.sources, population.proportion) {
+                (Some(_), Some(_)) | (None, None) => bail!(
+                    "Population \"{}\" needs either a number of sources or a proportion.",
+                    population.name
+                ),
+                (Some(_), None) if by_proportion => bail!(
+                    "Population \"{}\" is given by its number of sources, but others by proportion.",
+                    population.name
+                ),
+                (None, Some(proportion)) if !(0.0..=1.0).contains(&proportion) => bail!(
+                    "The proportion of population \"{}\" is not between 0 and 1.",
+                    population.name
+                ),
+                _ => {}
+            }
+        }
+
+        if !by_proportion {
+            let counts: Vec<u64> = self
+                .populations
+                .iter()
+                .map(|x| x.sources.unwrap())
+                .collect();
+            let total: u64 = counts.iter().sum();
+            match self.sources {
+                Some(sources) if sources != total => bail!(
+                    "The populations have {} sources in total, not {}.",
+                    total,
+                    sources
+                ),
+                _ if total == 0 => bail!("There must be at least one source."),
+                _ => return Ok(counts),
+            }
+        }
+
+        let Some(total) = self.sources else {
+            bail!("The total number of sources is required for populations given by proportion.");
+        };
+        let proportions: Vec<f64> = self
+            .populations
+            .iter()
+            .map(|x| x.proportion.unwrap())
+            .collect();
+        if (proportions.iter().sum::<f64>() - 1.0).abs() > 1e-6 {
+            bail!("The proportions of the populations do not add up to 1.");
+        }
+
+        let shares: Vec<f64> = proportions.iter().map(|x| x * total as f64).collect();
+        let mut counts: Vec<u64> = shares.iter().map(|x| x.floor() as u64).collect();
+        let mut by_remainder: Vec<usize> = (0..shares.len()).collect();
+        by_remainder.sort_by(|&a, &b| {
+            (shares[b] - shares[b].floor()).total_cmp(&(shares[a] - shares[a].floor()))
+        });
+        let missing = total - counts.iter().sum::<u64>();
+        for &i in by_remainder.iter().take(missing as usize) {
+            counts[i] += 1;
+        }
+        Ok(counts)
+    }
+}
+
+impl PopulationFile {
+    fn parse(&self, num_sources: u64, base: &Path) -> anyhow::Result<Population> {
+        Ok(Population {
+            name: self.name.clone(),
+            num_sources,
+            bandwidth: parse_distribution_field("bandwidth", &self.bandwidth, base)?,
+            stream_length: parse_distribution_field("stream length", &self.stream_length, base)?,
+            source_wait: parse_distribution_field("source wait", &self.source_wait, base)?,
+            traffic_model: parse_field(
+                "traffic model",
+                &self.traffic_model,
+                cli::parse_traffic_model,
+            )?,
+            sessions_per_source: parse_distribution_field(
+                "sessions per source",
+                &self.sessions_per_source,
+                base,
+            )?,
+            session_interval: parse_distribution_field(
+                "session interval",
+                &self.session_interval,
+                base,
+            )?,
+            destination_selection: match self.destination_selection {
+                Some(ref x) => Some(parse_destination_selection_field(x, base)?),
+                None => None,
+            },
+            load: match self.load {
+                Some(ref x) => Some(parse_load_field(x, base)?),
+                None => None,
+            },
+        })
+    }
+}
+
+impl From<&Scenario> for ScenarioFile {
+    fn from(scenario: &Scenario) -> ScenarioFile {
+        let network = &scenario.network;
+        ScenarioFile {
+            sources: Some(scenario.num_sources()),
+            message_size: scenario.message_size,
+            seed: scenario.seed,
+            populations: scenario
+                .populations
+                .iter()
+                .map(|population| PopulationFile {
+                    name: population.name.clone(),
+                    sources: Some(population.num_sources),
+                    proportion: None,
+                    bandwidth: population.bandwidth.to_string(),
+                    stream_length: population.stream_length.to_string(),
+                    source_wait: population.source_wait.to_string(),
+                    traffic_model: population.traffic_model.to_string(),
+                    sessions_per_source: population.sessions_per_source.to_string(),
+                    session_interval: population.session_interval.to_string(),
+                    destination_selection: population
+                        .destination_selection
+                        .as_ref()
+                        .map(|x| x.to_string()),
+                    load: population.load.as_ref().map(|x| x.to_string()),
+                })
+                .collect(),
+            destinations: DestinationsFile {
+                count: scenario.destinations.count,
+                selection: scenario.destinations.selection.to_string(),
+                stickiness: scenario.destinations.stickiness,
+                responses: scenario.destinations.responses.as_ref().map(|responses| {
+                    ResponsesFile {
+                        size: responses.size.to_string(),
+                        think_time: responses.think_time.to_string(),
+                        bandwidth: responses.bandwidth.to_string(),
+                    }
+                }),
+            },
+            network: NetworkFile {
+                delay: network.delay.to_string(),
+                path_latency: network.path_latency.as_ref().map(|x| x.to_string()),
+                destination_latencies: network.destination_latencies.clone(),
+                fifo: network.fifo,
+                queue: network.queue.as_ref().map(|x| x.to_string()),
+                queue_location: network
+                    .queue
+                    .as_ref()
+                    .map(|_| network.queue_location.to_string()),
+                cover: network.cover.iter().map(|x| x.to_string()).collect(),
+                mix: network.mix.as_ref().map(|x| x.to_string()),
+                loss: network.loss,
+                retransmission_timeout: network
+                    .retransmission_timeout
+                    .as_ref()
+                    .map(|x| x.to_string()),
+                tor: network.tor.clone(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenario_file(populations: &str) -> ScenarioFile {
+        toml::from_str(&format!(
+            "sources = 10
+            {}
+            [destinations]
+            count = 2
+            selection = \"uniform\"
+            [network]
+            delay = \"constant:10\"",
+            populations
+        ))
+        .unwrap()
+    }
+
+    fn population(name: &str, size: &str) -> String {
+        format!(
+            "[[population]]
+            name = \"{}\"
+            {}
+            bandwidth = \"constant:1\"
+            stream_length = \"constant:1000\"
+            source_wait = \"constant:0\"
+            ",
+            name, size
+        )
+    }
+
+    #[test]
+    fn population_sizes() {
+        // proportions are rounded so that they add up to the total
+        let file = scenario_file(&format!(
+            "{}{}{}",
+            population("a", "proportion = 0.45"),
+            population("b", "proportion = 0.35"),
+            population("c", "proportion = 0.2")
+        ));
+        assert_eq!(file.population_sizes().unwrap(), vec![5, 3, 2]);
+
+        let file = scenario_file(&format!(
+            "{}{}",
+            population("a", "sources = 4"),
+            population("b", "sources = 6")
+        ));
+        assert_eq!(file.population_sizes().unwrap(), vec![4, 6]);
+
+        // the counts must match the total
+        let file = scenario_file(&format!(
+            "{}{}",
+            population("a", "sources = 4"),
+            population("b", "sources = 5")
+        ));
+        assert!(file.population_sizes().is_err());
+
+        // counts and proportions cannot be mixed
+        let file = scenario_file(&format!(
+            "{}{}",
+            population("a", "sources = 4"),
+            population("b", "proportion = 0.6")
+        ));
+        assert!(file.population_sizes().is_err());
+    }
+
+    #[test]
+    fn population_classes() {
+        let file = scenario_file(&format!(
+            "{}{}destination_selection = \"zipf:1.5\"\n",
+            population("a", "proportion = 0.7"),
+            population("b", "proportion = 0.3")
+        ));
+        let scenario = file.parse(Path::new("")).unwrap();
+
+        // the populations have consecutive source IDs
+        let indices: Vec<_> = (0..11)
+            .map(|i| scenario.population_index(SourceId::new(i)))
+            .collect();
+        assert_eq!(indices[..7], [Some(0); 7]);
+        assert_eq!(indices[7..10], [Some(1); 3]);
+        assert_eq!(indices[10], None);
+
+        assert!(scenario.populations[0].destination_selection.is_none());
+        assert_eq!(
+            scenario.populations[1]
+                .destination_selection
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "zipf:1.5"
+        );
+    }
+
+    #[test]
+    fn write_elsewhere() {
+        // a scenario in a relative directory with a relative path to an
+        // empirical distribution
+        let input_dir = Path::new("ppcalc_test_scenario_in");
+        let output_dir = env::temp_dir().join("ppcalc_test_scenario_out");
+        fs::create_dir_all(input_dir).unwrap();
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(input_dir.join("lengths.txt"), "1000\n2000\n").unwrap();
+        let mut file = scenario_file(&population("a", "sources = 10"));
+        file.populations[0].stream_length = "empirical:lengths.txt".to_string();
+        let content = toml::to_string(&file).unwrap();
+        fs::write(input_dir.join("scenario.toml"), content).unwrap();
+
+        // the written scenario still refers to the same file
+        let scenario = Scenario::read(input_dir.join("scenario.toml")).unwrap();
+        let output = output_dir.join("trace.csv.scenario.toml");
+        scenario.write(&output).unwrap();
+        let scenario = Scenario::read(&output).unwrap();
+        let ParsedDistribution::Empirical { ref file } = scenario.populations[0].stream_length
+        else {
+            panic!("The stream length is not empirical.");
+        };
+        assert!(file.is_absolute());
+        assert_eq!(
+            fs::canonicalize(file).unwrap(),
+            fs::canonicalize(input_dir.join("lengths.txt")).unwrap()
+        );
+        fs::remove_dir_all(input_dir).unwrap();
+    }
+}
@@ -1,12 +1,106 @@
-use crate::trace;
+use std::fmt;
+
+use rand::Rng;
+use rand_distr::{Distribution, Exp, Pareto};
+use serde::{Deserialize, Serialize};
 use time::macros::datetime;
+use time::PrimitiveDateTime;
+
+/// The traffic model of a source, i.e. how it spreads its messages over time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TrafficModel {
+    /// Messages at a constant rate given by the bandwidth, like a bulk
+    /// transfer or constant-rate streaming
+    Constant,
+    /// Poisson arrivals, with the bandwidth determining the average rate
+    Poisson,
+    /// Alternating on and off periods with Pareto-distributed durations.
+    /// During on periods, messages are sent at the source's bandwidth.
+    OnOff {
+        /// Shape parameter of the Pareto distributions (must be > 1)
+        shape: f64,
+        /// Mean duration of an on period [ms]
+        mean_on: f64,
+        /// Mean duration of an off period [ms]
+        mean_off: f64,
+    },
+    /// Web page loads: the data is requested in pages, each sent as a burst
+    /// at the source's bandwidth, separated by exponentially distributed
+    /// think times
+    Web {
+        /// Size of a page [B]
+        page_size: u64,
+        /// Mean think time between two pages [ms]
+        mean_think_time: f64,
+    },
+}
 
-use ppcalc_metric::SourceId;
+/// Format the model like it is given on the command line
+impl fmt::Display for TrafficModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrafficModel::Constant => write!(f, "constant"),
+            TrafficModel::Poisson => write!(f, "poisson"),
+            TrafficModel::OnOff {
+                shape,
+                mean_on,
+                mean_off,
+            } => write!(f, "onoff:{}:{}:{}", shape, mean_on, mean_off),
+            TrafficModel::Web {
+                page_size,
+                mean_think_time,
+            } => write!(f, "web:{}:{}", page_size, mean_think_time),
+        }
+    }
+}
 
+impl TrafficModel {
+    /// Check that the model's parameters are valid
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            TrafficModel::Constant | TrafficModel::Poisson => Ok(()),
+            TrafficModel::OnOff {
+                shape,
+                mean_on,
+                mean_off,
+            } => {
+                if *shape <= 1.0 {
+                    return Err("The Pareto shape of the on/off model must be > 1.".to_string());
+                }
+                if *mean_on <= 0.0 || *mean_off <= 0.0 {
+                    return Err("The on and off periods must be positive.".to_string());
+                }
+                Ok(())
+            }
+            TrafficModel::Web {
+                page_size,
+                mean_think_time,
+            } => {
+                if *page_size == 0 || *mean_think_time <= 0.0 {
+                    return Err(
+                        "The page size and think time of the web model must be positive."
+                            .to_string(),
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A session of a source, which draws the send times of its messages one by
+/// one as they are needed
 pub struct Source {
     number_of_messages: u64,
     inter_message_delay: time::Duration,
-    start_offset: time::Duration,
+    traffic_model: TrafficModel,
+    message_size: u64,
+    /// The number of messages sent so far
+    sent: u64,
+    /// The send time of the last message, or the start of the session
+    time: PrimitiveDateTime,
+    /// The end of the current on period of the on/off model
+    period_end: Option<PrimitiveDateTime>,
 }
 
 impl Source {
@@ -14,23 +108,149 @@ impl Source {
         number_of_messages: u64,
         inter_message_delay: time::Duration,
         start_offset: time::Duration,
+        traffic_model: TrafficModel,
+        message_size: u64,
     ) -> Source {
         Source {
             number_of_messages,
             inter_message_delay,
-            start_offset,
+            traffic_model,
+            message_size,
+            sent: 0,
+            time: datetime!(1970-01-01 0:00) + start_offset,
+            period_end: None,
         }
     }
-    pub fn gen_source_trace(&mut self, source_id: SourceId) -> trace::SourceTrace {
-        let mut timestamps = vec![];
-        let mut time = datetime!(1970-01-01 0:00) + self.start_offset;
-        for _ in 0..self.number_of_messages {
-            time = time.checked_add(self.inter_message_delay).unwrap();
-            timestamps.push(time);
+
+    /// Draw the send time of the next message, or None once all are sent
+    pub fn next_timestamp(&mut self, rng: &mut impl Rng) -> Option<PrimitiveDateTime> {
+        // the first on period starts with the session, even if it sends nothing
+        if let TrafficModel::OnOff { shape, mean_on, .. } = self.traffic_model {
+            if self.period_end.is_none() {
+                self.period_end =
+                    Some(self.time + milliseconds(pareto(shape, mean_on).sample(rng)));
+            }
+        }
+        if self.sent == self.number_of_messages {
+            return None;
         }
-        trace::SourceTrace {
-            source_id: source_id,
-            timestamps,
+
+        let imd = self.inter_message_delay;
+        match self.traffic_model {
+            TrafficModel::Constant => {
+                self.time = self.time.checked_add(imd).unwrap();
+            }
+            TrafficModel::Poisson => {
+                // a zero inter-message delay degenerates to sending everything at once
+                let gaps = Exp::new(1.0 / imd.as_seconds_f64().max(f64::MIN_POSITIVE)).unwrap();
+                self.time = self
+                    .time
+                    .checked_add(time::Duration::seconds_f64(gaps.sample(rng)))
+                    .unwrap();
+            }
+            TrafficModel::OnOff {
+                shape,
+                mean_on,
+                mean_off,
+            } => {
+                self.time = self.time.checked_add(imd).unwrap();
+                // continue at the start of the next on period if this one is over
+                let period_end = self.period_end.unwrap();
+                if self.time > period_end {
+                    self.time = period_end + milliseconds(pareto(shape, mean_off).sample(rng));
+                    self.period_end =
+                        Some(self.time + milliseconds(pareto(shape, mean_on).sample(rng)));
+                }
+            }
+            TrafficModel::Web {
+                page_size,
+                mean_think_time,
+            } => {
+                let messages_per_page = page_size.div_ceil(self.message_size);
+                if self.sent > 0 && self.sent.is_multiple_of(messages_per_page) {
+                    let think_times = Exp::new(1.0 / mean_think_time).unwrap();
+                    self.time = self
+                        .time
+                        .checked_add(milliseconds(think_times.sample(rng)))
+                        .unwrap();
+                }
+                self.time = self.time.checked_add(imd).unwrap();
+            }
+        }
+        self.sent += 1;
+        Some(self.time)
+    }
+}
+
+/// A Pareto distribution with the given shape and mean
+fn pareto(shape: f64, mean: f64) -> Pareto<f64> {
+    Pareto::new(mean * (shape - 1.0) / shape, shape).unwrap()
+}
+
+fn milliseconds(value: f64) -> time::Duration {
+    time::Duration::seconds_f64(value / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{self, Stage};
+    use ppcalc_metric::SourceId;
+
+    /// Generate the send times of 1000 messages of 500 B every 10 ms
+    fn send_times(traffic_model: TrafficModel) -> Vec<time::PrimitiveDateTime> {
+        let mut rng = rng::source_rng(7, Stage::Sources, SourceId::new(0));
+        let mut source = Source::new(
+            1000,
+            time::Duration::milliseconds(10),
+            time::Duration::ZERO,
+            traffic_model,
+            500,
+        );
+        std::iter::from_fn(|| source.next_timestamp(&mut rng)).collect()
+    }
+
+    fn gaps(timestamps: &[time::PrimitiveDateTime]) -> Vec<time::Duration> {
+        timestamps.windows(2).map(|x| x[1] - x[0]).collect()
+    }
+
+    #[test]
+    fn on_off() {
+        let timestamps = send_times(TrafficModel::OnOff {
+            shape: 1.5,
+            mean_on: 100.0,
+            mean_off: 500.0,
+        });
+        assert_eq!(timestamps.len(), 1000);
+
+        // messages are sent at the bandwidth during on periods, and never faster
+        let imd = time::Duration::milliseconds(10);
+        let gaps = gaps(&timestamps);
+        assert!(gaps.iter().all(|x| *x >= imd));
+        let num_on = gaps.iter().filter(|x| **x == imd).count();
+        let num_off = gaps.len() - num_on;
+        assert!(num_on > 0 && num_off > 0);
+        // the off periods are much longer than the on periods
+        let off_time: time::Duration = gaps.iter().filter(|x| **x > imd).sum();
+        assert!(off_time > imd * num_on as u32);
+    }
+
+    #[test]
+    fn web() {
+        let timestamps = send_times(TrafficModel::Web {
+            page_size: 5000,
+            mean_think_time: 1000.0,
+        });
+        assert_eq!(timestamps.len(), 1000);
+
+        // pages of 10 messages at the bandwidth, separated by think times
+        let imd = time::Duration::milliseconds(10);
+        for (i, gap) in gaps(&timestamps).into_iter().enumerate() {
+            if (i + 1) % 10 == 0 {
+                assert!(gap > imd);
+            } else {
+                assert_eq!(gap, imd);
+            }
         }
     }
 }
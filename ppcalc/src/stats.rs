@@ -0,0 +1,354 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use anyhow::anyhow;
+use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
+use serde::Serialize;
+use time::PrimitiveDateTime;
+
+use ppcalc_metric::{DestinationId, Direction, SourceId, Trace, TraceBuilder};
+
+use crate::cli::StatsArgs;
+
+/// Percentiles reported for every distribution
+const PERCENTILES: [f64; 7] = [1.0, 5.0, 25.0, 50.0, 75.0, 95.0, 99.0];
+
+pub fn run(args: StatsArgs) -> anyhow::Result<()> {
+    let trace = TraceBuilder::from_csv(&args.input)
+        .map_err(|e| anyhow!(e))?
+        .build()?;
+
+    let stats = TraceStats::compute(&trace, args.interval);
+    stats.print();
+
+    if let Some(path) = args.output {
+        fs::write(path, serde_json::to_string_pretty(&stats)?)?;
+    }
+
+    Ok(())
+}
+
+/// Summary of an empirical distribution of values
+#[derive(Serialize)]
+pub struct Summary {
+    count: usize,
+    min: Option<f64>,
+    mean: Option<f64>,
+    max: Option<f64>,
+    /// (percentile, value) pairs, using the nearest-rank method
+    percentiles: Vec<(f64, f64)>,
+}
+
+impl Summary {
+    fn new(mut values: Vec<f64>) -> Summary {
+        values.sort_unstable_by(|a, b| a.total_cmp(b));
+
+        let percentiles = if values.is_empty() {
+            Vec::new()
+        } else {
+            PERCENTILES
+                .iter()
+                .map(|p| {
+                    let rank = ((p / 100.0) * values.len() as f64).ceil() as usize;
+                    (*p, values[rank.clamp(1, values.len()) - 1])
+                })
+                .collect()
+        };
+
+        Summary {
+            count: values.len(),
+            min: values.first().copied(),
+            mean: if values.is_empty() {
+                None
+            } else {
+                Some(values.iter().sum::<f64>() / values.len() as f64)
+            },
+            max: values.last().copied(),
+            percentiles,
+        }
+    }
+
+    fn print(&self, name: &str) {
+        let fmt = |x: Option<f64>| x.map_or("-".to_string(), |x| format!("{:.3}", x));
+        println!(
+            "{:<32} n={:<10} min={:<12} mean={:<12} max={}",
+            name,
+            self.count,
+            fmt(self.min),
+            fmt(self.mean),
+            fmt(self.max)
+        );
+        if !self.percentiles.is_empty() {
+            let percentiles: Vec<_> = self
+                .percentiles
+                .iter()
+                .map(|(p, v)| format!("p{}={:.3}", p, v))
+                .collect();
+            println!("{:<32} {}", "", percentiles.join(" "));
+        }
+    }
+}
+
+/// Descriptive statistics of a network trace
+#[derive(Serialize)]
+pub struct TraceStats {
+    num_messages: usize,
+    num_sources: usize,
+    num_destinations: usize,
+    /// Number of dummy messages, including dropped ones
+    num_dummies: usize,
+    /// Number of messages that never arrived at their destination
+    num_dropped: usize,
+    /// Number of messages that were sent again after being lost
+    num_retransmissions: usize,
+    /// Number of downstream messages (responses), which the other statistics
+    /// leave out
+    num_responses: usize,
+
+    /// Number of messages sent per source
+    messages_per_source: BTreeMap<SourceId, u64>,
+    /// Number of messages received per destination
+    messages_per_destination: BTreeMap<DestinationId, u64>,
+    /// Number of distinct sources that sent to each destination
+    fan_in_per_destination: BTreeMap<DestinationId, u64>,
+
+    messages_per_source_summary: Summary,
+    messages_per_destination_summary: Summary,
+    fan_in_summary: Summary,
+
+    /// Average send rate of each source over its active period [messages/s]
+    send_rate_summary: Summary,
+    /// Time between two consecutive messages of the same source [ms]
+    inter_message_delay_summary: Summary,
+    /// Observed network delay, i.e. destination minus source timestamp [ms]
+    network_delay_summary: Summary,
+
+    /// Width of the buckets in `active_sources` [ms]
+    concurrency_interval: u64,
+    /// Number of active sources (between their first and last message) at
+    /// the start of each interval, as (seconds since trace start, count)
+    active_sources: Vec<(f64, u64)>,
+    active_sources_summary: Summary,
+}
+
+impl TraceStats {
+    pub fn compute(trace: &Trace, interval: u64) -> TraceStats {
+        let mut messages_per_source: BTreeMap<SourceId, u64> = BTreeMap::new();
+        let mut messages_per_destination: BTreeMap<DestinationId, u64> = BTreeMap::new();
+        let mut sources_per_destination: HashMap<DestinationId, HashSet<SourceId>> =
+            HashMap::default();
+        let mut send_times: HashMap<SourceId, Vec<PrimitiveDateTime>> = HashMap::default();
+        let mut network_delays = Vec::new();
+        let mut num_dummies = 0;
+        let mut num_dropped = 0;
+        let mut num_retransmissions = 0;
+        let mut num_responses = 0;
+
+        for entry in trace.entries() {
+            if entry.direction == Direction::Downstream {
+                num_responses += 1;
+                continue;
+            }
+            *messages_per_source.entry(entry.source_id).or_default() += 1;
+            send_times
+                .entry(entry.source_id)
+                .or_default()
+                .push(entry.source_timestamp);
+            if entry.dummy {
+                num_dummies += 1;
+            }
+            if entry.retransmission {
+                num_retransmissions += 1;
+            }
+            if entry.dropped {
+                num_dropped += 1;
+                continue;
+            }
+            *messages_per_destination
+                .entry(entry.destination_id)
+                .or_default() += 1;
+            sources_per_destination
+                .entry(entry.destination_id)
+                .or_default()
+                .insert(entry.source_id);
+            network_delays.push(
+                (entry.destination_timestamp - entry.source_timestamp).as_seconds_f64() * 1000.0,
+            );
+        }
+
+        let mut send_rates = Vec::new();
+        let mut inter_message_delays = Vec::new();
+        let mut active_periods = Vec::new();
+        for timestamps in send_times.values_mut() {
+            timestamps.sort_unstable();
+            let first = *timestamps.first().unwrap();
+            let last = *timestamps.last().unwrap();
+            active_periods.push((first, last));
+
+            let active = (last - first).as_seconds_f64();
+            if active > 0.0 {
+                send_rates.push((timestamps.len() - 1) as f64 / active);
+            }
+            for pair in timestamps.windows(2) {
+                inter_message_delays.push((pair[1] - pair[0]).as_seconds_f64() * 1000.0);
+            }
+        }
+
+        let active_sources = active_sources_over_time(&active_periods, interval);
+
+        let fan_in_per_destination: BTreeMap<DestinationId, u64> = sources_per_destination
+            .iter()
+            .map(|(dest, sources)| (*dest, sources.len() as u64))
+            .collect();
+
+        TraceStats {
+            num_messages: trace.entries().count(),
+            num_sources: messages_per_source.len(),
+            num_destinations: messages_per_destination.len(),
+            num_dummies,
+            num_dropped,
+            num_retransmissions,
+            num_responses,
+            messages_per_source_summary: Summary::new(
+                messages_per_source.values().map(|x| *x as f64).collect(),
+            ),
+            messages_per_destination_summary: Summary::new(
+                messages_per_destination
+                    .values()
+                    .map(|x| *x as f64)
+                    .collect(),
+            ),
+            fan_in_summary: Summary::new(
+                fan_in_per_destination.values().map(|x| *x as f64).collect(),
+            ),
+            messages_per_source,
+            messages_per_destination,
+            fan_in_per_destination,
+            send_rate_summary: Summary::new(send_rates),
+            inter_message_delay_summary: Summary::new(inter_message_delays),
+            network_delay_summary: Summary::new(network_delays),
+            concurrency_interval: interval,
+            active_sources_summary: Summary::new(
+                active_sources.iter().map(|(_, x)| *x as f64).collect(),
+            ),
+            active_sources,
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Messages:     {}", self.num_messages);
+        println!("Sources:      {}", self.num_sources);
+        println!("Destinations: {}", self.num_destinations);
+        println!("Dummies:      {}", self.num_dummies);
+        println!("Dropped:      {}", self.num_dropped);
+        println!("Retransmits:  {}", self.num_retransmissions);
+        println!("Responses:    {}", self.num_responses);
+        println!();
+        self.messages_per_source_summary
+            .print("Messages per source");
+        self.messages_per_destination_summary
+            .print("Messages per destination");
+        self.fan_in_summary.print("Sources per destination");
+        self.send_rate_summary.print("Send rate [msg/s]");
+        self.inter_message_delay_summary
+            .print("Inter-message delay [ms]");
+        self.network_delay_summary.print("Network delay [ms]");
+        self.active_sources_summary.print(&format!(
+            "Active sources (per {} ms)",
+            self.concurrency_interval
+        ));
+    }
+}
+
+/// Count the sources that are active at the start of each interval.
+///
+/// A source counts as active between its first and its last message, inclusive.
+fn active_sources_over_time(
+    active_periods: &[(PrimitiveDateTime, PrimitiveDateTime)],
+    interval: u64,
+) -> Vec<(f64, u64)> {
+    let Some(start) = active_periods.iter().map(|(first, _)| *first).min() else {
+        return Vec::new();
+    };
+    let end = active_periods.iter().map(|(_, last)| *last).max().unwrap();
+
+    // sweep over start (+1) and end (-1) events
+    let mut events: Vec<(PrimitiveDateTime, i64)> = active_periods
+        .iter()
+        .flat_map(|(first, last)| [(*first, 1), (*last, -1)])
+        .collect();
+    // process starts before ends at the same time so that sources are counted inclusively
+    events.sort_unstable_by_key(|(time, delta)| (*time, -delta));
+
+    let step = time::Duration::milliseconds(interval.max(1) as i64);
+    let mut result = Vec::new();
+    let mut events = events.into_iter().peekable();
+    let mut active: i64 = 0;
+    let mut bucket = start;
+    while bucket <= end {
+        while let Some((time, delta)) = events.peek() {
+            // sources ending exactly at the bucket start are still active
+            if *time < bucket || (*time == bucket && *delta > 0) {
+                active += delta;
+                events.next();
+            } else {
+                break;
+            }
+        }
+        result.push(((bucket - start).as_seconds_f64(), active as u64));
+        bucket += step;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn summary_percentiles() {
+        let empty = Summary::new(Vec::new());
+        assert_eq!(empty.count, 0);
+        assert!(empty.min.is_none() && empty.mean.is_none() && empty.max.is_none());
+        assert!(empty.percentiles.is_empty());
+
+        let single = Summary::new(vec![4.0]);
+        assert_eq!(
+            (single.min, single.mean, single.max),
+            (Some(4.0), Some(4.0), Some(4.0))
+        );
+        assert!(single.percentiles.iter().all(|(_, x)| *x == 4.0));
+
+        // nearest rank: the p-th percentile is the ceil(p/100 * n)-th smallest value
+        let summary = Summary::new((1..=20).rev().map(|x| x as f64).collect());
+        assert_eq!(summary.mean, Some(10.5));
+        assert_eq!(
+            summary.percentiles,
+            vec![
+                (1.0, 1.0),
+                (5.0, 1.0),
+                (25.0, 5.0),
+                (50.0, 10.0),
+                (75.0, 15.0),
+                (95.0, 19.0),
+                (99.0, 20.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn active_sources() {
+        assert!(active_sources_over_time(&[], 1000).is_empty());
+
+        let start = datetime!(1970-01-01 0:00);
+        let at = |ms: i64| start + time::Duration::milliseconds(ms);
+        let periods = [(at(0), at(1000)), (at(500), at(2500)), (at(2000), at(2000))];
+        // a source that ends exactly at the start of a bucket still counts
+        assert_eq!(
+            active_sources_over_time(&periods, 1000),
+            vec![(0.0, 1), (1.0, 2), (2.0, 2)]
+        );
+    }
+}
@@ -0,0 +1,522 @@
+//! A simplified simulation of Tor: streams are sent via three-hop circuits
+//! through a network of relays with limited bandwidth.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use rand::distributions::WeightedIndex;
+use rand::prelude::Distribution;
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use time::PrimitiveDateTime;
+
+use ppcalc_metric::{
+    MessageId, RelayId, RelayObservation, RelayObservations, SourceId, TraceEntry,
+};
+
+use crate::cli::ParsedDistribution;
+use crate::trace;
+
+/// Number of relays on each circuit
+const CIRCUIT_LENGTH: usize = 3;
+
+/// The relays of a circuit, from the guard to the exit
+type Circuit = [RelayId; CIRCUIT_LENGTH];
+
+/// Ports of which an exit policy must allow at least two, like for Tor's Exit flag
+const EXIT_PORTS: [u16; 3] = [80, 443, 6667];
+
+/// The relays of the simulated network
+#[derive(Debug, Default)]
+pub struct Network {
+    pub relays: Vec<Relay>,
+    /// The bandwidth weights of a consensus (e.g. `Wgg`), scaled by 10000,
+    /// which balance the bandwidth of the relays between the positions of a
+    /// circuit. Missing weights are 10000.
+    pub weights: HashMap<String, u64>,
+}
+
+/// A position on a circuit
+#[derive(Copy, Clone, Debug)]
+enum Position {
+    Guard,
+    Middle,
+    Exit,
+}
+
+impl Network {
+    /// The bandwidth weight of a relay at a position, as defined in Tor's
+    /// directory specification
+    fn weight(&self, relay: &Relay, position: Position) -> u64 {
+        let name = match (position, relay.has_flag("Guard"), relay.has_flag("Exit")) {
+            (Position::Guard, true, true) => "Wgd",
+            (Position::Guard, true, false) => "Wgg",
+            (Position::Guard, false, _) => "Wgm",
+            (Position::Middle, true, true) => "Wmd",
+            (Position::Middle, true, false) => "Wmg",
+            (Position::Middle, false, true) => "Wme",
+            (Position::Middle, false, false) => "Wmm",
+            (Position::Exit, true, true) => "Wed",
+            (Position::Exit, false, true) => "Wee",
+            (Position::Exit, true, false) => "Weg",
+            (Position::Exit, false, false) => "Wem",
+        };
+        self.weights.get(name).copied().unwrap_or(10000)
+    }
+}
+
+/// A relay of the simulated network
+#[derive(Deserialize, Debug)]
+pub struct Relay {
+    /// Bandwidth [kB/s], as given in the consensus weights
+    pub bandwidth: u64,
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+impl Relay {
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|x| x == flag)
+    }
+}
+
+/// Read the relays from a Tor consensus, a file of server descriptors, or
+/// a JSON list of relays like
+/// `[{"nickname": "relay1", "bandwidth": 1000, "flags": ["Guard"]}, ...]`.
+///
+/// The relay IDs are the positions of the relays in the file, counting from 0.
+pub fn read_relays(path: impl AsRef<Path>) -> anyhow::Result<Network> {
+    let path = path.as_ref();
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+
+    let network = if content.trim_start().starts_with('[') {
+        Network {
+            relays: serde_json::from_str(&content)
+                .with_context(|| format!("Invalid relay list in {}", path.display()))?,
+            weights: HashMap::new(),
+        }
+    } else {
+        parse_relay_documents(&content)
+    };
+    if network.relays.is_empty() {
+        bail!("{} does not contain any relays", path.display());
+    }
+    Ok(network)
+}
+
+/// The decisions of an exit policy for the [`EXIT_PORTS`] on any address
+#[derive(Default)]
+struct ExitPolicy([Option<bool>; EXIT_PORTS.len()]);
+
+impl ExitPolicy {
+    /// Apply a rule like `accept *:80-443`. The first matching rule decides,
+    /// and rules for specific addresses do not apply to any address.
+    fn add_rule(&mut self, accept: bool, pattern: &str) {
+        let Some((address, ports)) = pattern.rsplit_once(':') else {
+            return;
+        };
+        if !matches!(address, "*" | "*4" | "0.0.0.0/0") {
+            return;
+        }
+        let range = match ports.split_once('-') {
+            _ if ports == "*" => Some((u16::MIN, u16::MAX)),
+            Some((low, high)) => low.parse().ok().zip(high.parse().ok()),
+            None => ports.parse().ok().map(|port| (port, port)),
+        };
+        let Some((low, high)) = range else {
+            return;
+        };
+        for (decision, port) in self.0.iter_mut().zip(EXIT_PORTS) {
+            if decision.is_none() && (low..=high).contains(&port) {
+                *decision = Some(accept);
+            }
+        }
+    }
+
+    /// Whether the policy allows exiting, like Tor does for the Exit flag.
+    /// Ports without a matching rule are accepted.
+    fn allows_exit(&self) -> bool {
+        self.0
+            .iter()
+            .filter(|decision| decision.unwrap_or(true))
+            .count()
+            >= 2
+    }
+}
+
+/// Parse the relays of a consensus (`r`, `s`, `w` and `bandwidth-weights`
+/// lines) or of server descriptors (`router`, `bandwidth` and `accept` or
+/// `reject` lines)
+fn parse_relay_documents(content: &str) -> Network {
+    let mut network = Network::default();
+    let relays = &mut network.relays;
+    // descriptors have no flags, so their exit policies tell the exits
+    let mut exit_policies: Vec<Option<ExitPolicy>> = Vec::new();
+    for line in content.lines() {
+        let mut words = line.split_whitespace();
+        let keyword = words.next();
+        let relay = relays.last_mut();
+        match (keyword, relay) {
+            (Some(keyword @ ("r" | "router")), _) => {
+                relays.push(Relay {
+                    bandwidth: 0,
+                    flags: Vec::new(),
+                });
+                exit_policies.push((keyword == "router").then(ExitPolicy::default));
+            }
+            (Some("s"), Some(relay)) => {
+                relay.flags = words.map(|x| x.to_string()).collect();
+            }
+            (Some("w"), Some(relay)) => {
+                if let Some(bandwidth) = words.find_map(|x| x.strip_prefix("Bandwidth=")) {
+                    relay.bandwidth = bandwidth.parse().unwrap_or(0);
+                }
+            }
+            // average and observed bandwidth of a descriptor [B/s]
+            (Some("bandwidth"), Some(relay)) => {
+                let values: Vec<u64> = words.filter_map(|x| x.parse().ok()).collect();
+                if let [average, _, observed] = values[..] {
+                    relay.bandwidth = average.min(observed) / 1000;
+                }
+            }
+            (Some(rule @ ("accept" | "reject")), Some(_)) => {
+                if let (Some(Some(policy)), Some(pattern)) =
+                    (exit_policies.last_mut(), words.next())
+                {
+                    policy.add_rule(rule == "accept", pattern);
+                }
+            }
+            (Some("bandwidth-weights"), _) => {
+                for (name, weight) in words.filter_map(|x| x.split_once('=')) {
+                    if let Ok(weight) = weight.parse() {
+                        network.weights.insert(name.to_string(), weight);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for (relay, policy) in network.relays.iter_mut().zip(exit_policies) {
+        if policy.is_some_and(|policy| policy.allows_exit()) {
+            relay.flags.push("Exit".to_string());
+        }
+    }
+    network
+}
+
+/// Choose a bandwidth-weighted circuit: a guard, middle relays and an exit
+/// relay, all different. The bandwidth of each relay is weighted by the
+/// bandwidth weight of its position. If no relays have the Guard or Exit
+/// flag, any relay can take that position.
+///
+/// Like Tor, the scarce exit is chosen first, then the guard and the middle relays.
+fn choose_circuit(network: &Network, rng: &mut impl Rng) -> anyhow::Result<Circuit> {
+    let mut positions = vec![Position::Exit, Position::Guard];
+    positions.resize(CIRCUIT_LENGTH, Position::Middle);
+    let mut chosen = Vec::with_capacity(CIRCUIT_LENGTH);
+    for position in positions {
+        let flag = match position {
+            Position::Guard => Some("Guard"),
+            Position::Middle => None,
+            Position::Exit => Some("Exit"),
+        };
+        let any_flagged = flag.is_some_and(|flag| network.relays.iter().any(|x| x.has_flag(flag)));
+        let weights = network.relays.iter().enumerate().map(|(i, relay)| {
+            let eligible = (!any_flagged || flag.is_some_and(|flag| relay.has_flag(flag)))
+                && !chosen.contains(&i);
+            if eligible {
+                relay.bandwidth * network.weight(relay, position)
+            } else {
+                0
+            }
+        });
+        let distr = WeightedIndex::new(weights)
+            .map_err(|_| anyhow!("Not enough relays with bandwidth to build a circuit"))?;
+        chosen.push(distr.sample(rng));
+    }
+
+    // order the relays from the guard to the exit
+    Ok(std::array::from_fn(|hop| {
+        let position = match hop {
+            0 => 1,
+            hop if hop == CIRCUIT_LENGTH - 1 => 0,
+            hop => hop + 1,
+        };
+        RelayId::new(chosen[position] as u64)
+    }))
+}
+
+/// Send the messages via circuits through the relays. Returns the messages
+/// in the order of their arrival, numbered accordingly.
+///
+/// Each stream uses its own circuit. Each relay forwards the messages in the
+/// order they arrive, taking `message_size` divided by its bandwidth for each
+/// of them. Every link of a circuit adds a delay drawn from `link_delay` [ms],
+/// but the messages of a circuit stay in order.
+pub fn simulate(
+    network: &Network,
+    pre_network_trace: Vec<trace::PreNetworkTraceEntry>,
+    message_size: u64,
+    link_delay: &ParsedDistribution<u64>,
+    circuit_rng: &mut impl Rng,
+    delay_rng: &mut impl Rng,
+) -> anyhow::Result<(Vec<TraceEntry>, RelayObservations)> {
+    let link_delay = link_delay.make_distr().map_err(|e| anyhow!(e))?;
+    let link = |rng: &mut dyn RngCore| time::Duration::milliseconds(link_delay.sample(rng) as i64);
+    let service_times: Vec<time::Duration> = network
+        .relays
+        .iter()
+        .map(|relay| {
+            // relays without bandwidth are never part of a circuit
+            let bandwidth = relay.bandwidth.max(1) as f64 * 1000.0;
+            time::Duration::seconds_f64(message_size as f64 / bandwidth)
+        })
+        .collect();
+
+    // dropped messages do not pass the network
+    let (dropped, pre_network_trace): (Vec<_>, Vec<_>) = pre_network_trace
+        .into_iter()
+        .partition(|entry| entry.dropped);
+
+    // build a circuit per stream, in order of their first message
+    let mut circuits: HashMap<(SourceId, u64), Circuit> = HashMap::new();
+    let mut message_circuits = Vec::with_capacity(pre_network_trace.len());
+    for entry in pre_network_trace.iter() {
+        let circuit = match circuits.get(&(entry.source_id, entry.session)) {
+            Some(circuit) => *circuit,
+            None => {
+                let circuit = choose_circuit(network, circuit_rng)?;
+                circuits.insert((entry.source_id, entry.session), circuit);
+                circuit
+            }
+        };
+        message_circuits.push(circuit);
+    }
+
+    // Discrete event simulation: each event is the arrival of a message at
+    // a hop, with the destination being the hop after the last relay.
+    // The messages of a circuit stay in order on each link, so they arrive at
+    // a hop no earlier than the previous message of their stream.
+    let mut last_arrivals: HashMap<(usize, SourceId, u64), PrimitiveDateTime> = HashMap::new();
+    let mut keep_order = |time: PrimitiveDateTime, entry: &trace::PreNetworkTraceEntry, hop| {
+        let last_arrival = last_arrivals
+            .entry((hop, entry.source_id, entry.session))
+            .or_insert(time);
+        *last_arrival = time.max(*last_arrival);
+        *last_arrival
+    };
+
+    let mut events = BinaryHeap::new();
+    for (i, entry) in pre_network_trace.iter().enumerate() {
+        let arrival = keep_order(entry.source_timestamp + link(delay_rng), entry, 0);
+        events.push(Reverse((arrival, i, 0)));
+    }
+    let mut busy_until: HashMap<RelayId, PrimitiveDateTime> = HashMap::new();
+    let mut observations = Vec::new();
+    let mut arrivals = vec![None; pre_network_trace.len()];
+    while let Some(Reverse((time, i, hop))) = events.pop() {
+        if hop == CIRCUIT_LENGTH {
+            arrivals[i] = Some(time);
+            continue;
+        }
+        let relay = message_circuits[i][hop];
+        observations.push(RelayObservation {
+            m_id: MessageId::new(i as u64),
+            relay_id: relay,
+            hop: hop as u32,
+            timestamp: time,
+        });
+
+        let start = match busy_until.get(&relay) {
+            Some(&busy) => time.max(busy),
+            None => time,
+        };
+        let departure = start + service_times[relay.to_num() as usize];
+        busy_until.insert(relay, departure);
+
+        let next = keep_order(departure + link(delay_rng), &pre_network_trace[i], hop + 1);
+        events.push(Reverse((next, i, hop + 1)));
+    }
+
+    let mut entries: Vec<TraceEntry> = pre_network_trace
+        .into_iter()
+        .zip(arrivals)
+        .map(|(entry, arrival)| entry.into_trace_entry(arrival.unwrap()))
+        .chain(dropped.into_iter().map(|entry| {
+            let source_timestamp = entry.source_timestamp;
+            entry.into_trace_entry(source_timestamp)
+        }))
+        .collect();
+
+    // number the messages by their arrival, like TraceBuilder::fix()
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| entries[i].destination_timestamp);
+    let mut new_ids = vec![0; order.len()];
+    for (m_id, &i) in order.iter().enumerate() {
+        new_ids[i] = m_id as u64;
+    }
+
+    for (i, entry) in entries.iter_mut().enumerate() {
+        entry.m_id = MessageId::new(new_ids[i]);
+    }
+    entries.sort_by_key(|entry| entry.m_id);
+    let mut relay_observations = RelayObservations::new();
+    observations.sort_by_key(|x: &RelayObservation| (x.timestamp, x.hop));
+    for mut observation in observations {
+        observation.m_id = MessageId::new(new_ids[observation.m_id.to_num() as usize]);
+        relay_observations.add_observation(observation);
+    }
+
+    Ok((entries, relay_observations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_distribution;
+    use crate::rng::{self, Stage};
+    use ppcalc_metric::{DestinationId, Direction};
+    use time::macros::datetime;
+
+    fn relay(bandwidth: u64, flags: &[&str]) -> Relay {
+        Relay {
+            bandwidth,
+            flags: flags.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_consensus() {
+        let network = parse_relay_documents(
+            "r relay1 AAAA BBBB 2024-01-01 00:00:00 192.0.2.1 9001 0
+s Fast Guard Running Valid
+w Bandwidth=1000
+r relay2 CCCC DDDD 2024-01-01 00:00:00 192.0.2.2 9001 0
+s Exit Fast Running Valid
+w Bandwidth=2000 Measured=1900
+p accept 80,443
+bandwidth-weights Wgd=0 Wgg=5904 Wmg=4096 Wee=10000",
+        );
+        assert_eq!(network.relays.len(), 2);
+        assert_eq!(network.relays[0].bandwidth, 1000);
+        assert!(network.relays[0].has_flag("Guard") && !network.relays[0].has_flag("Exit"));
+        assert_eq!(network.relays[1].bandwidth, 2000);
+        assert!(network.relays[1].has_flag("Exit"));
+        assert_eq!(network.weight(&network.relays[0], Position::Guard), 5904);
+        assert_eq!(network.weight(&network.relays[0], Position::Middle), 4096);
+        assert_eq!(network.weight(&network.relays[1], Position::Middle), 10000);
+    }
+
+    #[test]
+    fn parse_descriptors() {
+        let network = parse_relay_documents(
+            "router web 192.0.2.1 9001 0 0
+bandwidth 2000000 3000000 1000000
+reject 10.0.0.0/8:*
+accept *:80
+accept *:443
+reject *:*
+router private 192.0.2.2 9001 0 0
+bandwidth 1000000 1000000 1000000
+accept 10.0.0.1:*
+accept *:22
+reject *:*
+router open 192.0.2.3 9001 0 0
+bandwidth 1000000 1000000 1000000
+reject *:25
+accept *:*",
+        );
+        let exits: Vec<bool> = network
+            .relays
+            .iter()
+            .map(|relay| relay.has_flag("Exit"))
+            .collect();
+        assert_eq!(exits, vec![true, false, true]);
+        assert_eq!(network.relays[0].bandwidth, 1000);
+    }
+
+    #[test]
+    fn weighted_circuits() {
+        // the guards do not serve as middle relays
+        let network = Network {
+            relays: vec![
+                relay(100, &["Exit"]),
+                relay(100, &["Guard"]),
+                relay(300, &["Guard"]),
+                relay(100, &[]),
+            ],
+            weights: [("Wmg".to_string(), 0)].into_iter().collect(),
+        };
+        let mut rng = rng::stage_rng(1, Stage::Circuits);
+        let mut guards = [0; 4];
+        for _ in 0..4000 {
+            let [guard, middle, exit] = choose_circuit(&network, &mut rng).unwrap();
+            assert_eq!(exit, RelayId::new(0));
+            assert_eq!(middle, RelayId::new(3));
+            guards[guard.to_num() as usize] += 1;
+        }
+        let ratio = guards[2] as f64 / guards[1] as f64;
+        assert!((2.6..3.4).contains(&ratio));
+    }
+
+    #[test]
+    fn relay_queueing() {
+        // two streams on the same circuit, each relay taking 1ms per message
+        let network = Network {
+            relays: vec![
+                relay(1000, &["Guard"]),
+                relay(1000, &[]),
+                relay(1000, &["Exit"]),
+            ],
+            weights: HashMap::new(),
+        };
+        let epoch = datetime!(1970-01-01 0:00);
+        let messages = (0..2)
+            .map(|source| trace::PreNetworkTraceEntry {
+                source_id: SourceId::new(source),
+                session: 0,
+                source_timestamp: epoch,
+                destination_id: DestinationId::new(0),
+                dummy: false,
+                dropped: false,
+                direction: Direction::Upstream,
+            })
+            .collect();
+        let (entries, observations) = simulate(
+            &network,
+            messages,
+            1000,
+            &parse_distribution("constant:0").unwrap(),
+            &mut rng::stage_rng(1, Stage::Circuits),
+            &mut rng::stage_rng(1, Stage::Delays),
+        )
+        .unwrap();
+
+        // the second message waits for the first one at every relay
+        let ms = |time: PrimitiveDateTime| (time - epoch).whole_milliseconds();
+        let arrivals: Vec<(u64, i128)> = entries
+            .iter()
+            .map(|entry| (entry.source_id.to_num(), ms(entry.destination_timestamp)))
+            .collect();
+        assert_eq!(arrivals, vec![(0, 3), (1, 4)]);
+        let hops: Vec<(u64, u64, i128)> = observations
+            .observations()
+            .map(|x| (x.m_id.to_num(), x.relay_id.to_num(), ms(x.timestamp)))
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                (0, 0, 0),
+                (1, 0, 0),
+                (0, 1, 1),
+                (1, 1, 2),
+                (0, 2, 2),
+                (1, 2, 3)
+            ]
+        );
+    }
+}
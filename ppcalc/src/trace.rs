@@ -1,27 +1,71 @@
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 use time::PrimitiveDateTime;
 
-use ppcalc_metric::{DestinationId, SourceId, TraceBuilder};
+use ppcalc_metric::{DestinationId, Direction, MessageId, SourceId, TraceBuilder, TraceEntry};
 
 #[derive(Serialize, Deserialize)]
 pub struct SourceTrace {
     pub source_id: SourceId,
+    pub session: u64,
     pub timestamps: Vec<PrimitiveDateTime>,
 }
 
+/// A message in a sources file, which holds the send times of the sources
+#[derive(Serialize, Deserialize)]
+struct SourceTraceEntry {
+    source_id: SourceId,
+    session: u64,
+    source_timestamp: PrimitiveDateTime,
+}
+
+/// An entry in a destinations file, which holds the destination of each
+/// session of the sources
 #[derive(Serialize, Deserialize)]
 pub struct SourceDestinationMapEntry {
-    source: SourceId,
-    destination: DestinationId,
+    source_id: SourceId,
+    session: u64,
+    destination_id: DestinationId,
 }
 
+/// An entry in a classes file, which holds the class of each source
 #[derive(Serialize, Deserialize)]
+struct SourceClassEntry {
+    source_id: SourceId,
+    class: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PreNetworkTraceEntry {
     pub source_id: SourceId,
+    pub session: u64,
     pub source_timestamp: PrimitiveDateTime,
     pub destination_id: DestinationId,
+    pub dummy: bool,
+    pub dropped: bool,
+    pub direction: Direction,
+}
+
+impl PreNetworkTraceEntry {
+    /// Turn into a trace entry that arrives, or is dropped, at the given time.
+    /// Its message ID still needs to be assigned.
+    pub fn into_trace_entry(self, destination_timestamp: PrimitiveDateTime) -> TraceEntry {
+        TraceEntry {
+            m_id: MessageId::new(0),
+            source_id: self.source_id,
+            source_timestamp: self.source_timestamp,
+            destination_id: self.destination_id,
+            destination_timestamp,
+            session: self.session,
+            dummy: self.dummy,
+            dropped: self.dropped,
+            direction: self.direction,
+            retransmission: false,
+        }
+    }
 }
 
 /// Reconstruct sources and their behavior from a network trace file
@@ -33,20 +77,307 @@ pub fn read_sources_from_trace(
     // load the trace
     let trace = TraceBuilder::from_csv(path)?.build()?;
 
-    // create a SourceTrace per source
-    let mut result: Vec<SourceTrace> = (0..=trace.max_source_id().to_num())
-        .map(|source_id| SourceTrace {
-            source_id: SourceId::new(source_id),
-            timestamps: Vec::new(),
+    // Collect send times per session of each source. Dummies, responses and
+    // retransmissions are not part of the sources' behavior, but generated
+    // separately.
+    let mut sessions: BTreeMap<(SourceId, u64), Vec<PrimitiveDateTime>> = BTreeMap::new();
+    for entry in trace
+        .entries()
+        .filter(|x| !x.dummy && x.direction == Direction::Upstream && !x.retransmission)
+    {
+        sessions
+            .entry((entry.source_id, entry.session))
+            .or_default()
+            .push(entry.source_timestamp);
+    }
+
+    Ok(sessions_to_source_traces(sessions))
+}
+
+/// Create a SourceTrace per session
+fn sessions_to_source_traces(
+    sessions: BTreeMap<(SourceId, u64), Vec<PrimitiveDateTime>>,
+) -> Vec<SourceTrace> {
+    sessions
+        .into_iter()
+        .map(|((source_id, session), mut timestamps)| {
+            timestamps.sort();
+            SourceTrace {
+                source_id,
+                session,
+                timestamps,
+            }
         })
-        .collect();
+        .collect()
+}
+
+/// Whether a CSV file is a network trace, as opposed to a sources or
+/// destinations file
+fn is_network_trace(path: &Path) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut rdr = csv::ReaderBuilder::new().from_path(path)?;
+    Ok(rdr.headers()?.iter().any(|x| x == "destination_timestamp"))
+}
 
-    // Collect send times per source
-    for entry in trace.entries() {
-        result[entry.source_id.to_num() as usize]
-            .timestamps
+/// Saves the send times of the sources to a sources file while the messages
+/// are generated, in the order they are sent
+pub struct SourcesWriter {
+    wtr: csv::Writer<std::fs::File>,
+}
+
+impl SourcesWriter {
+    pub fn create(path: impl AsRef<Path>) -> Result<SourcesWriter, Box<dyn Error + Send + Sync>> {
+        Ok(SourcesWriter {
+            wtr: csv::WriterBuilder::new().from_path(path.as_ref())?,
+        })
+    }
+
+    /// Write a message, unless it is a dummy
+    pub fn write(
+        &mut self,
+        entry: &PreNetworkTraceEntry,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !entry.dummy {
+            self.wtr.serialize(SourceTraceEntry {
+                source_id: entry.source_id,
+                session: entry.session,
+                source_timestamp: entry.source_timestamp,
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// Read the sources from a sources file, or reconstruct them from a network
+/// trace file
+pub fn read_sources(
+    path: impl AsRef<Path>,
+) -> Result<Vec<SourceTrace>, Box<dyn Error + Send + Sync>> {
+    let path = path.as_ref();
+    if is_network_trace(path)? {
+        return read_sources_from_trace(path);
+    }
+
+    let mut sessions: BTreeMap<(SourceId, u64), Vec<PrimitiveDateTime>> = BTreeMap::new();
+    let mut rdr = csv::ReaderBuilder::new().from_path(path)?;
+    for result in rdr.deserialize() {
+        let entry: SourceTraceEntry = result?;
+        sessions
+            .entry((entry.source_id, entry.session))
+            .or_default()
             .push(entry.source_timestamp);
     }
+    Ok(sessions_to_source_traces(sessions))
+}
+
+/// Save the destination of each session of the sources to a destinations file
+pub fn write_destinations(
+    path: impl AsRef<Path>,
+    source_destination_map: &HashMap<(SourceId, u64), DestinationId>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut sessions: Vec<_> = source_destination_map.iter().collect();
+    sessions.sort_unstable_by_key(|(key, _)| **key);
+
+    let mut wtr = csv::WriterBuilder::new().from_path(path.as_ref())?;
+    for (&(source_id, session), &destination_id) in sessions {
+        wtr.serialize(SourceDestinationMapEntry {
+            source_id,
+            session,
+            destination_id,
+        })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Read the destination of each session of the sources from a destinations
+/// file or a network trace file
+pub fn read_destinations(
+    path: impl AsRef<Path>,
+) -> Result<HashMap<(SourceId, u64), DestinationId>, Box<dyn Error + Send + Sync>> {
+    let path = path.as_ref();
+    let mut source_destination_map = HashMap::new();
+    if is_network_trace(path)? {
+        let trace = TraceBuilder::from_csv(path)?.build()?;
+        for entry in trace
+            .entries()
+            .filter(|x| !x.dummy && x.direction == Direction::Upstream)
+        {
+            source_destination_map.insert((entry.source_id, entry.session), entry.destination_id);
+        }
+    } else {
+        let mut rdr = csv::ReaderBuilder::new().from_path(path)?;
+        for result in rdr.deserialize() {
+            let entry: SourceDestinationMapEntry = result?;
+            source_destination_map.insert((entry.source_id, entry.session), entry.destination_id);
+        }
+    }
+    Ok(source_destination_map)
+}
+
+/// The network delays of the messages of each session, in the order they were sent
+pub type SessionDelays = HashMap<(SourceId, u64), Vec<time::Duration>>;
+
+/// Read the network delays of the messages of each session from a network
+/// trace file, in the order the messages were sent. Dummies, responses,
+/// dropped messages and retransmissions are skipped.
+pub fn read_delays_from_trace(
+    path: impl AsRef<Path>,
+) -> Result<SessionDelays, Box<dyn Error + Send + Sync>> {
+    let trace = TraceBuilder::from_csv(path)?.build()?;
+
+    let mut messages: Vec<&TraceEntry> = trace
+        .entries()
+        .filter(|x| {
+            !x.dummy && x.direction == Direction::Upstream && !x.retransmission && !x.dropped
+        })
+        .collect();
+    messages.sort_by_key(|x| x.source_timestamp);
+
+    let mut delays = SessionDelays::new();
+    for entry in messages {
+        delays
+            .entry((entry.source_id, entry.session))
+            .or_default()
+            .push(entry.destination_timestamp - entry.source_timestamp);
+    }
+    Ok(delays)
+}
+
+/// Get the path of the classes file next to a trace, e.g. `trace.csv.classes.csv`
+pub fn classes_path_for_trace(trace_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = trace_path.as_ref().as_os_str().to_owned();
+    path.push(".classes.csv");
+    path.into()
+}
 
-    Ok(result)
+/// Save the class of each source to a classes file
+pub fn write_classes(
+    path: impl AsRef<Path>,
+    classes: &BTreeMap<SourceId, String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut wtr = csv::WriterBuilder::new().from_path(path.as_ref())?;
+    for (&source_id, class) in classes.iter() {
+        wtr.serialize(SourceClassEntry {
+            source_id,
+            class: class.clone(),
+        })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Read the class of each source from a classes file
+pub fn read_classes(
+    path: impl AsRef<Path>,
+) -> Result<HashMap<SourceId, String>, Box<dyn Error + Send + Sync>> {
+    let mut rdr = csv::ReaderBuilder::new().from_path(path.as_ref())?;
+    let mut classes = HashMap::new();
+    for result in rdr.deserialize() {
+        let entry: SourceClassEntry = result?;
+        classes.insert(entry.source_id, entry.class);
+    }
+    Ok(classes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn sources_and_destinations_roundtrip() {
+        let source_traces = [
+            SourceTrace {
+                source_id: SourceId::new(0),
+                session: 0,
+                timestamps: vec![datetime!(1970-01-01 0:00:01), datetime!(1970-01-01 0:00:02)],
+            },
+            SourceTrace {
+                source_id: SourceId::new(0),
+                session: 1,
+                timestamps: vec![datetime!(1970-01-01 0:00:05.5)],
+            },
+            SourceTrace {
+                source_id: SourceId::new(1),
+                session: 0,
+                timestamps: vec![datetime!(1970-01-01 0:00:00.25)],
+            },
+        ];
+        let path = std::env::temp_dir().join("ppcalc_test_sources.csv");
+        let mut writer = SourcesWriter::create(&path).unwrap();
+        for trace in source_traces.iter() {
+            for &source_timestamp in trace.timestamps.iter() {
+                writer
+                    .write(&PreNetworkTraceEntry {
+                        source_id: trace.source_id,
+                        session: trace.session,
+                        source_timestamp,
+                        destination_id: DestinationId::new(0),
+                        dummy: false,
+                        dropped: false,
+                        direction: Direction::Upstream,
+                    })
+                    .unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        let read = read_sources(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.len(), source_traces.len());
+        for (read, written) in read.iter().zip(source_traces.iter()) {
+            assert_eq!(read.source_id, written.source_id);
+            assert_eq!(read.session, written.session);
+            assert_eq!(read.timestamps, written.timestamps);
+        }
+
+        let source_destination_map = HashMap::from([
+            ((SourceId::new(0), 0), DestinationId::new(3)),
+            ((SourceId::new(0), 1), DestinationId::new(1)),
+            ((SourceId::new(1), 0), DestinationId::new(3)),
+        ]);
+        let path = std::env::temp_dir().join("ppcalc_test_destinations.csv");
+        write_destinations(&path, &source_destination_map).unwrap();
+        let read = read_destinations(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, source_destination_map);
+    }
+
+    #[test]
+    fn delays_skip_dropped() {
+        let epoch = datetime!(1970-01-01 0:00);
+        let ms = time::Duration::milliseconds;
+        // (sent, arrived [ms], dropped) of the messages of a session
+        let messages = [(0, 0, true), (10, 30, false), (20, 25, false)];
+        let mut entries: Vec<TraceEntry> = messages
+            .iter()
+            .map(|&(sent, arrived, dropped)| TraceEntry {
+                m_id: MessageId::new(0),
+                source_id: SourceId::new(0),
+                source_timestamp: epoch + ms(sent),
+                destination_id: DestinationId::new(0),
+                destination_timestamp: epoch + ms(arrived),
+                session: 0,
+                dummy: false,
+                dropped,
+                direction: Direction::Upstream,
+                retransmission: false,
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.destination_timestamp);
+
+        let path = std::env::temp_dir().join("ppcalc_test_delays.csv");
+        let mut writer = ppcalc_metric::TraceWriter::create(&path).unwrap();
+        for entry in entries {
+            writer.write_entry(entry).unwrap();
+        }
+        writer.finish().unwrap();
+        let delays = read_delays_from_trace(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(delays[&(SourceId::new(0), 0)], vec![ms(20), ms(5)]);
+    }
 }
@@ -1,14 +1,20 @@
 //! A crate for analyzing anonymity properties of traces from anonymous communication networks (ACNs).
 
 mod trace;
-pub use trace::{DestinationId, MessageId, SourceId};
-pub use trace::{Trace, TraceBuilder, TraceEntry};
+pub use trace::{DestinationId, Direction, MessageId, RelayId, SourceId};
+pub use trace::{
+    RelayObservation, RelayObservations, Trace, TraceBuilder, TraceEntry, TraceWriter,
+};
 
 mod containers;
 
 mod metric;
 pub use metric::{
-    compute_relationship_anonymity, compute_relationship_anonymity_sizes, simple_example_generator,
+    compute_bidirectional_relationship_anonymity,
+    compute_bidirectional_relationship_anonymity_sizes, compute_relationship_anonymity,
+    compute_relationship_anonymity_sizes, compute_relationship_anonymity_sizes_with_dummies,
+    compute_relationship_anonymity_with_dummies, compute_relay_relationship_anonymity,
+    compute_relay_relationship_anonymity_sizes, simple_example_generator, DummyVisibility,
 };
 
 mod bench;
@@ -5,15 +5,16 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::{fs::File, io::BufReader};
 
-use fxhash::FxHashMap as HashMap;
+use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use time::Duration;
+use time::{Duration, PrimitiveDateTime};
 
 use crate::bench;
 use crate::containers::MessageSet;
 use crate::trace::{
-    DestinationId, DestinationMapping, MessageId, SourceId, Trace, TraceBuilder, TraceEntry,
+    DestinationId, DestinationMapping, Direction, MessageId, RelayId, RelayObservations, SourceId,
+    Trace, TraceBuilder, TraceEntry,
 };
 
 /// Compute the relative difference between two message anonymity sets.
@@ -49,7 +50,12 @@ pub fn compute_relationship_anonymity(
     ),
     Box<dyn std::error::Error + Send + Sync>,
 > {
-    compute_relationship_anonymity_inner::<OutputFull>(trace, min_delay, max_delay)
+    compute_relationship_anonymity_with_dummies(
+        trace,
+        DummyVisibility::Indistinguishable,
+        min_delay,
+        max_delay,
+    )
 }
 
 pub fn compute_relationship_anonymity_sizes(
@@ -63,11 +69,152 @@ pub fn compute_relationship_anonymity_sizes(
     ),
     Box<dyn std::error::Error + Send + Sync>,
 > {
-    compute_relationship_anonymity_inner::<OutputSizes>(trace, min_delay, max_delay)
+    compute_relationship_anonymity_sizes_with_dummies(
+        trace,
+        DummyVisibility::Indistinguishable,
+        min_delay,
+        max_delay,
+    )
+}
+
+/// Like [compute_relationship_anonymity], but for an adversary that may
+/// recognize the dummy messages
+pub fn compute_relationship_anonymity_with_dummies(
+    trace: &Trace,
+    dummies: DummyVisibility,
+    min_delay: Duration,
+    max_delay: Duration,
+) -> RelationshipAnonymityResult<Vec<DestinationId>> {
+    compute_relationship_anonymity_inner::<OutputFull>(
+        trace,
+        &AdversaryView::edges(trace, dummies),
+        min_delay,
+        max_delay,
+    )
+}
+
+/// Like [compute_relationship_anonymity_sizes], but for an adversary that may
+/// recognize the dummy messages
+pub fn compute_relationship_anonymity_sizes_with_dummies(
+    trace: &Trace,
+    dummies: DummyVisibility,
+    min_delay: Duration,
+    max_delay: Duration,
+) -> RelationshipAnonymityResult<usize> {
+    compute_relationship_anonymity_inner::<OutputSizes>(
+        trace,
+        &AdversaryView::edges(trace, dummies),
+        min_delay,
+        max_delay,
+    )
+}
+
+/// Compute the relationship anonymity sets, combining the evidence of the
+/// upstream messages with that of the downstream messages (responses).
+///
+/// Each response that a source receives can only have been sent by a
+/// destination that sent a response within the delay window before, so the
+/// candidate destinations are restricted to those. The resulting anonymity
+/// sets include the responses received by each source.
+pub fn compute_bidirectional_relationship_anonymity(
+    trace: &Trace,
+    dummies: DummyVisibility,
+    min_delay: Duration,
+    max_delay: Duration,
+) -> RelationshipAnonymityResult<Vec<DestinationId>> {
+    compute_relationship_anonymity_inner::<OutputFull>(
+        trace,
+        &AdversaryView::edges_bidirectional(trace, dummies),
+        min_delay,
+        max_delay,
+    )
+}
+
+/// Like [compute_bidirectional_relationship_anonymity], but only computes the
+/// sizes of the anonymity sets.
+pub fn compute_bidirectional_relationship_anonymity_sizes(
+    trace: &Trace,
+    dummies: DummyVisibility,
+    min_delay: Duration,
+    max_delay: Duration,
+) -> RelationshipAnonymityResult<usize> {
+    compute_relationship_anonymity_inner::<OutputSizes>(
+        trace,
+        &AdversaryView::edges_bidirectional(trace, dummies),
+        min_delay,
+        max_delay,
+    )
+}
+
+/// Whether the adversary can tell dummy messages apart from real ones
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DummyVisibility {
+    /// Dummies look like real messages to the adversary
+    Indistinguishable,
+    /// The adversary recognizes and ignores dummies
+    Distinguishable,
+}
+
+impl DummyVisibility {
+    /// Whether the adversary takes the message into account
+    fn considers(&self, entry: &TraceEntry) -> bool {
+        *self == DummyVisibility::Indistinguishable || !entry.dummy
+    }
+
+    /// Whether the adversary observes dummies without recognizing them
+    fn hides_dummies(&self, trace: &Trace) -> bool {
+        *self == DummyVisibility::Indistinguishable && trace.entries().any(|msg| msg.dummy)
+    }
+}
+
+/// Source and destination relationship anonymity sets, per message
+type RelationshipAnonymityResult<T> = Result<
+    (
+        HashMap<SourceId, Vec<(MessageId, T)>>,
+        HashMap<SourceId, Vec<(MessageId, T)>>,
+    ),
+    Box<dyn std::error::Error + Send + Sync>,
+>;
+
+/// Compute the relationship anonymity sets for an adversary that observes a
+/// set of relays instead of the sources and destinations.
+///
+/// The adversary links the observations of a message at consecutive relays it
+/// controls, as they forward the message on the same circuit. A message is
+/// observed on the source side if its first relay (hop 0) is controlled by
+/// the adversary, and on the destination side if its last relay is. The delay
+/// windows are given per hop and scaled to the number of hops between the
+/// observations that are matched, so relays in the middle of the path narrow
+/// down the windows.
+pub fn compute_relay_relationship_anonymity(
+    trace: &Trace,
+    observations: &RelayObservations,
+    adversary_relays: &HashSet<RelayId>,
+    dummies: DummyVisibility,
+    min_hop_delay: Duration,
+    max_hop_delay: Duration,
+) -> RelationshipAnonymityResult<Vec<DestinationId>> {
+    let view = AdversaryView::relays(trace, observations, adversary_relays, dummies);
+    compute_relationship_anonymity_inner::<OutputFull>(trace, &view, min_hop_delay, max_hop_delay)
+}
+
+/// Like [compute_relay_relationship_anonymity], but only computes the sizes
+/// of the anonymity sets.
+pub fn compute_relay_relationship_anonymity_sizes(
+    trace: &Trace,
+    observations: &RelayObservations,
+    adversary_relays: &HashSet<RelayId>,
+    dummies: DummyVisibility,
+    min_hop_delay: Duration,
+    max_hop_delay: Duration,
+) -> RelationshipAnonymityResult<usize> {
+    let view = AdversaryView::relays(trace, observations, adversary_relays, dummies);
+    compute_relationship_anonymity_inner::<OutputSizes>(trace, &view, min_hop_delay, max_hop_delay)
 }
 
 fn compute_relationship_anonymity_inner<T: OutputMapper>(
     trace: &Trace,
+    view: &AdversaryView,
     min_delay: Duration,
     max_delay: Duration,
 ) -> Result<
@@ -82,7 +229,7 @@ fn compute_relationship_anonymity_inner<T: OutputMapper>(
 
     bench.measure("compute anonymity sets", BENCH_ENABLED);
     let source_relationship_anonymity_sets =
-        compute_message_anonymity_sets::<T>(&trace, min_delay, max_delay);
+        compute_message_anonymity_sets::<T>(trace, view, min_delay, max_delay);
 
     /* Be wary that this yields only useful results if there is just one source per destination */
     let destination_relationship_anonymity_sets = HashMap::default(); // TODO
@@ -97,20 +244,27 @@ fn compute_relationship_anonymity_inner<T: OutputMapper>(
 struct AnonymitySetMerger {
     // number of candidate messages per destination after the previous message
     prev_destination_candidates: Option<HashMap<DestinationId, usize>>,
+    // whether messages that no destination can have received are dummies
+    skip_impossible: bool,
 }
 
 impl AnonymitySetMerger {
-    fn new() -> AnonymitySetMerger {
+    fn new(skip_impossible: bool) -> AnonymitySetMerger {
         AnonymitySetMerger {
             prev_destination_candidates: None,
+            skip_impossible,
         }
     }
 
+    /// Merge the next message of the source into its anonymity set. There is
+    /// none yet if all messages so far were skipped as dummies.
     fn next_anonymity_set(
         &mut self,
         source_message: MessageId,
         destination_anon_sets: &HashMap<DestinationId, (usize, usize)>,
-    ) -> Vec<DestinationId> {
+    ) -> Option<Vec<DestinationId>> {
+        let is_first = self.prev_destination_candidates.is_none();
+
         // Access the previous message's candidates
         let prev_destination_candidates = match self.prev_destination_candidates {
             Some(ref mut x) => x,
@@ -158,13 +312,44 @@ impl AnonymitySetMerger {
             destination_candidates.insert(destination.clone(), candidates - 1);
         }
 
+        // A message that cannot have reached any candidate must have been a
+        // dummy, so it does not change the candidates.
+        if destination_candidates.is_empty() && self.skip_impossible {
+            if is_first {
+                self.prev_destination_candidates = None;
+                return None;
+            }
+            return Some(prev_destination_candidates.keys().cloned().collect());
+        }
+
         // The destination anonymity set after this message is now ready.
         let result = destination_candidates.keys().cloned().collect();
 
         // remember the remaining number of message candidates for each destination
         *prev_destination_candidates = destination_candidates;
 
-        result
+        Some(result)
+    }
+}
+
+impl AnonymitySetMerger {
+    /// Restrict the candidates to the given destinations, e.g. those that can
+    /// have sent a response that the source received
+    fn restrict_to(&mut self, destinations: &HashSet<DestinationId>) -> Option<Vec<DestinationId>> {
+        // like for upstream messages, an impossible response must be a dummy
+        if destinations.is_empty() && self.skip_impossible {
+            return self
+                .prev_destination_candidates
+                .as_ref()
+                .map(|candidates| candidates.keys().cloned().collect());
+        }
+
+        let candidates = self.prev_destination_candidates.get_or_insert_with(|| {
+            // no messages have been assigned to the destinations yet
+            destinations.iter().map(|dest| (*dest, 0)).collect()
+        });
+        candidates.retain(|dest, _| destinations.contains(dest));
+        Some(candidates.keys().cloned().collect())
     }
 }
 
@@ -198,24 +383,230 @@ impl OutputMapper for OutputSizes {
     }
 }
 
+/// What the adversary observes of a trace: when the sources sent their
+/// messages, and when messages arrived at the destinations.
+///
+/// Observations are made at hops along the path of a message, where the
+/// source is at hop 0. The delay windows are scaled to the number of hops
+/// between the two observations that are matched.
+struct AdversaryView {
+    /// The observed messages of each source (indexed by source ID)
+    sent: Vec<Vec<SentObservation>>,
+    /// The observed arrivals at the destinations, grouped by the hop at
+    /// which they were observed
+    received: Vec<ReceivedObservations>,
+    /// Whether some of the observed messages are dummies that the adversary
+    /// cannot recognize
+    hidden_dummies: bool,
+    /// The observed responses, if the adversary takes them into account
+    downstream: Option<DownstreamView>,
+}
+
+/// A message observed on the source side
+#[derive(Clone)]
+struct SentObservation {
+    m_id: MessageId,
+    timestamp: PrimitiveDateTime,
+    /// The hop at which the message was observed last on the source side
+    hop: u32,
+    /// Whether the adversary followed the message all the way to its destination
+    linked: bool,
+}
+
+/// Messages observed on the destination side at the same hop
+struct ReceivedObservations {
+    hop: u32,
+    /// The observed messages, sorted by time
+    arrivals: Vec<(PrimitiveDateTime, MessageId)>,
+}
+
+/// What the adversary observes of the downstream messages (responses)
+struct DownstreamView {
+    /// The responses received by each source (indexed by source ID) together
+    /// with their arrival times, ordered by time
+    received: Vec<Vec<(MessageId, PrimitiveDateTime)>>,
+    /// The responses sent by the destinations, sorted by time
+    sent: Vec<(PrimitiveDateTime, MessageId)>,
+}
+
+impl DownstreamView {
+    /// The destinations that can have sent a response that arrived at the
+    /// given time
+    fn responders(
+        &self,
+        received_at: PrimitiveDateTime,
+        destination_mapping: &DestinationMapping,
+        min_delay: Duration,
+        max_delay: Duration,
+    ) -> HashSet<DestinationId> {
+        let from_time = received_at - max_delay;
+        let to_time = received_at - min_delay;
+        let start_index = self.sent.partition_point(|(time, _)| *time < from_time);
+        self.sent[start_index..]
+            .iter()
+            .take_while(|(sent_at, _)| *sent_at <= to_time)
+            .map(|(_, message)| *destination_mapping.get(message).unwrap())
+            .collect()
+    }
+}
+
+impl AdversaryView {
+    /// The view of an adversary observing all sources and destinations.
+    ///
+    /// Dropped messages are only observed at their source. Only upstream
+    /// messages are taken into account.
+    fn edges(trace: &Trace, dummies: DummyVisibility) -> AdversaryView {
+        let upstream = |msg: &&TraceEntry| msg.direction == Direction::Upstream;
+        let mut sent = vec![Vec::new(); trace.max_source_id().to_num() as usize + 1];
+        for msg in trace
+            .entries()
+            .filter(upstream)
+            .filter(|msg| dummies.considers(msg))
+        {
+            sent.get_mut(msg.source_id.to_num() as usize)
+                .unwrap()
+                .push(SentObservation {
+                    m_id: msg.m_id,
+                    timestamp: msg.source_timestamp,
+                    hop: 0,
+                    linked: false,
+                });
+        }
+
+        // trace entries are sorted by their arrival already
+        let arrivals = trace
+            .entries()
+            .filter(upstream)
+            .filter(|msg| dummies.considers(msg) && !msg.dropped)
+            .map(|msg| (msg.destination_timestamp, msg.m_id))
+            .collect();
+
+        AdversaryView {
+            sent,
+            // the destinations are one hop away from the sources
+            received: vec![ReceivedObservations { hop: 1, arrivals }],
+            hidden_dummies: dummies.hides_dummies(trace),
+            downstream: None,
+        }
+    }
+
+    /// The view of an adversary observing all sources and destinations, in
+    /// both directions
+    fn edges_bidirectional(trace: &Trace, dummies: DummyVisibility) -> AdversaryView {
+        let responses = || {
+            trace
+                .entries()
+                .filter(|msg| msg.direction == Direction::Downstream && dummies.considers(msg))
+        };
+
+        // trace entries are sorted by their arrival already
+        let mut received = vec![Vec::new(); trace.max_source_id().to_num() as usize + 1];
+        for msg in responses().filter(|msg| !msg.dropped) {
+            received[msg.source_id.to_num() as usize].push((msg.m_id, msg.destination_timestamp));
+        }
+        let mut sent: Vec<_> = responses()
+            .map(|msg| (msg.source_timestamp, msg.m_id))
+            .collect();
+        sent.sort_unstable();
+
+        AdversaryView {
+            downstream: Some(DownstreamView { received, sent }),
+            ..AdversaryView::edges(trace, dummies)
+        }
+    }
+
+    /// The view of an adversary observing the given relays.
+    ///
+    /// The observations of a message at consecutive relays of the adversary
+    /// are linked. The message is observed on the source side at the end of
+    /// the run of relays that starts at its first hop, and on the destination
+    /// side at the start of the run that ends at its last hop.
+    fn relays(
+        trace: &Trace,
+        observations: &RelayObservations,
+        adversary_relays: &HashSet<RelayId>,
+        dummies: DummyVisibility,
+    ) -> AdversaryView {
+        let num_messages = trace.max_message_id().to_num() as usize + 1;
+        let mut considered = vec![false; num_messages];
+        for msg in trace.entries() {
+            considered[msg.m_id.to_num() as usize] =
+                msg.direction == Direction::Upstream && dummies.considers(msg);
+        }
+
+        // the observations of each message by the adversary, and its last hop
+        let mut paths: Vec<Vec<(u32, PrimitiveDateTime)>> = vec![Vec::new(); num_messages];
+        let mut last_hop = vec![0; num_messages];
+        for observation in observations.observations() {
+            let index = observation.m_id.to_num() as usize;
+            if index >= num_messages || !considered[index] {
+                continue;
+            }
+            last_hop[index] = last_hop[index].max(observation.hop);
+            if adversary_relays.contains(&observation.relay_id) {
+                paths[index].push((observation.hop, observation.timestamp));
+            }
+        }
+
+        let mut sent = vec![Vec::new(); trace.max_source_id().to_num() as usize + 1];
+        let mut received: BTreeMap<u32, Vec<(PrimitiveDateTime, MessageId)>> = BTreeMap::new();
+        for msg in trace.entries() {
+            let index = msg.m_id.to_num() as usize;
+            let path = &mut paths[index];
+            path.sort_unstable();
+
+            // the runs of consecutive hops from the first and to the last hop
+            let source_side = path
+                .iter()
+                .zip(0..)
+                .take_while(|((hop, _), expected)| hop == expected)
+                .last();
+            let destination_side = path
+                .iter()
+                .rev()
+                .zip((0..=last_hop[index]).rev())
+                .take_while(|((hop, _), expected)| hop == expected)
+                .last();
+
+            if let Some(((hop, timestamp), _)) = source_side {
+                sent[msg.source_id.to_num() as usize].push(SentObservation {
+                    m_id: msg.m_id,
+                    timestamp: *timestamp,
+                    hop: *hop,
+                    linked: *hop == last_hop[index],
+                });
+            }
+            if let Some(((hop, timestamp), _)) = destination_side {
+                received
+                    .entry(*hop)
+                    .or_default()
+                    .push((*timestamp, msg.m_id));
+            }
+        }
+
+        AdversaryView {
+            sent,
+            received: received
+                .into_iter()
+                .map(|(hop, mut arrivals)| {
+                    arrivals.sort_unstable();
+                    ReceivedObservations { hop, arrivals }
+                })
+                .collect(),
+            hidden_dummies: dummies.hides_dummies(trace),
+            downstream: None,
+        }
+    }
+}
+
 fn compute_message_anonymity_sets<T: OutputMapper>(
     trace: &Trace,
+    view: &AdversaryView,
     min_delay: Duration,
     max_delay: Duration,
 ) -> HashMap<SourceId, Vec<(MessageId, T::Item)>> {
     let destination_mapping = trace.get_destination_mapping();
 
-    // split messages per source
-    let messages_per_source: Vec<Vec<&TraceEntry>> = {
-        let mut v = vec![Vec::new(); trace.max_source_id().to_num() as usize + 1];
-        for msg in trace.entries() {
-            v.get_mut(msg.source_id.to_num() as usize)
-                .unwrap()
-                .push(msg);
-        }
-        v
-    };
-
     // Progress printer. Takes progress info via a channel from the processing
     // threads and prints status info to stdout. This thread finishes as soon
     // a false value is sent to the channel, or the channel is closed.
@@ -234,41 +625,83 @@ fn compute_message_anonymity_sets<T: OutputMapper>(
         }
     });
 
-    let result: HashMap<SourceId, Vec<(MessageId, T::Item)>> = messages_per_source
-        .into_par_iter()
+    let result: HashMap<SourceId, Vec<(MessageId, T::Item)>> = view
+        .sent
+        .par_iter()
         .enumerate()
         .map(|(source, messages)| {
             let source = SourceId::new(source as u64);
-            let entries = trace.entries_vec();
 
             let mut source_result = Vec::new();
             let mut last_msg_anonset: Option<HashMap<DestinationId, MessageSet>> = None;
 
             // helper struct to merge/intersect the anonymity sets over time
             // (this was previously the "second phase")
-            let mut anonset_intersector = AnonymitySetMerger::new();
+            let mut anonset_intersector = AnonymitySetMerger::new(view.hidden_dummies);
+
+            // responses received by the source, taken into account in the
+            // order of time together with the sent messages
+            let mut responses = match view.downstream {
+                Some(ref downstream) => downstream.received[source.to_num() as usize].iter(),
+                None => [].iter(),
+            }
+            .peekable();
+            let restrict_to_responders =
+                |received_at: PrimitiveDateTime, intersector: &mut AnonymitySetMerger| {
+                    let downstream = view.downstream.as_ref().unwrap();
+                    let responders = downstream.responders(
+                        received_at,
+                        destination_mapping,
+                        min_delay,
+                        max_delay,
+                    );
+                    intersector.restrict_to(&responders).map(T::map)
+                };
+
+            for observation in messages {
+                let (message_id, sent_at) = (&observation.m_id, &observation.timestamp);
+                while let Some((response_id, received_at)) =
+                    responses.next_if(|(_, received_at)| received_at <= sent_at)
+                {
+                    if let Some(anonymity_set) =
+                        restrict_to_responders(*received_at, &mut anonset_intersector)
+                    {
+                        source_result.push((*response_id, anonymity_set));
+                    }
+                }
 
-            for message in messages {
                 // Find the relevant destination messages.
                 // This exploits the fact that the trace entries are sorted by
                 // time of arrival at the destination, so we can carry out fast
                 // range queries.
                 let mut this_msg_anonset = MessageSet::new();
-                let from_time = message.source_timestamp + min_delay;
-                let to_time = message.source_timestamp + max_delay;
-
-                // Find the first relevant index (whose timestamp is _not_ less
-                // than from_time). We use partition_point(...) here instead of
-                // binary_search(...), because the latter would give us only
-                // _some_ matching entry, not necessarily the first one.
-                let start_index = entries.partition_point(|e| e.destination_timestamp < from_time);
-
-                for dest_msg in &entries[start_index..] {
-                    if dest_msg.destination_timestamp > to_time {
-                        break;
+                if observation.linked {
+                    // the adversary followed the message to its destination
+                    this_msg_anonset.insert(*message_id);
+                }
+                for group in view.received.iter() {
+                    if observation.linked || group.hop <= observation.hop {
+                        continue;
                     }
+                    let hops = (group.hop - observation.hop) as i32;
+                    let from_time = *sent_at + min_delay * hops;
+                    let to_time = *sent_at + max_delay * hops;
+
+                    // Find the first relevant index (whose timestamp is _not_ less
+                    // than from_time). We use partition_point(...) here instead of
+                    // binary_search(...), because the latter would give us only
+                    // _some_ matching entry, not necessarily the first one.
+                    let start_index = group
+                        .arrivals
+                        .partition_point(|(time, _)| *time < from_time);
+
+                    for (received_at, dest_msg) in &group.arrivals[start_index..] {
+                        if *received_at > to_time {
+                            break;
+                        }
 
-                    this_msg_anonset.insert(dest_msg.m_id);
+                        this_msg_anonset.insert(*dest_msg);
+                    }
                 }
 
                 let this_msg_anonset = split_by_destination(this_msg_anonset, destination_mapping);
@@ -305,18 +738,26 @@ fn compute_message_anonymity_sets<T: OutputMapper>(
                     };
 
                 // use the aggregated anonymity set delta for computing the next anonymity set (possible destinations)
+                // (skipped dummies before the first real message have none)
                 let anonymity_set =
-                    anonset_intersector.next_anonymity_set(message.m_id, &relative_difference);
+                    anonset_intersector.next_anonymity_set(*message_id, &relative_difference);
 
-                // map the anonymity set to what we want to output
-                let anonymity_set = T::map(anonymity_set);
-
-                // save it as the next result
-                source_result.push((message.m_id, anonymity_set));
+                // map the anonymity set to what we want to output, and save
+                // it as the next result
+                if let Some(anonymity_set) = anonymity_set {
+                    source_result.push((*message_id, T::map(anonymity_set)));
+                }
 
                 // remember the original (but split by destination) anonymity set for next iteration
                 last_msg_anonset = Some(this_msg_anonset);
             }
+            for (response_id, received_at) in responses {
+                if let Some(anonymity_set) =
+                    restrict_to_responders(*received_at, &mut anonset_intersector)
+                {
+                    source_result.push((*response_id, anonymity_set));
+                }
+            }
             progress_s.send(true).unwrap();
             (source, source_result)
         })
@@ -510,4 +951,294 @@ mod tests {
     fn simple_test_7() {
         execute_test("./test/simple_test_7/");
     }
+
+    #[test]
+    fn relay_adversary() {
+        use crate::trace::{RelayObservation, TraceEntry};
+        use time::macros::datetime;
+
+        // Two sources with disjoint three-hop paths: source 0 via relays 0-1-2
+        // to destination 0, and source 1 via relays 3-4-5 to destination 1.
+        let mut builder = TraceBuilder::new();
+        let mut observations = RelayObservations::new();
+        for k in 0..10 {
+            for source in 0..2u64 {
+                let sent =
+                    datetime!(1970-01-01 0:00) + Duration::milliseconds(10 * k + 3 * source as i64);
+                let m_id = MessageId::new(2 * k as u64 + source);
+                builder.add_entry(TraceEntry {
+                    m_id,
+                    source_id: SourceId::new(source),
+                    source_timestamp: sent,
+                    destination_id: DestinationId::new(source),
+                    destination_timestamp: sent + Duration::milliseconds(20),
+                    session: 0,
+                    dummy: false,
+                    dropped: false,
+                    direction: Direction::Upstream,
+                    retransmission: false,
+                });
+                for hop in 0..3 {
+                    observations.add_observation(RelayObservation {
+                        m_id,
+                        relay_id: RelayId::new(3 * source + hop as u64),
+                        hop,
+                        timestamp: sent + Duration::milliseconds(5 * (hop as i64 + 1)),
+                    });
+                }
+            }
+        }
+        let trace = builder.build().unwrap();
+
+        // The adversary controls the first and the last relay of source 0 only
+        let adversary: HashSet<RelayId> = [RelayId::new(0), RelayId::new(2)].into_iter().collect();
+        let (sras, _) = compute_relay_relationship_anonymity(
+            &trace,
+            &observations,
+            &adversary,
+            DummyVisibility::Indistinguishable,
+            Duration::milliseconds(1),
+            Duration::milliseconds(10),
+        )
+        .unwrap();
+
+        let source_0 = &sras[&SourceId::new(0)];
+        assert_eq!(source_0.len(), 10);
+        for (_, anonymity_set) in source_0 {
+            assert_eq!(anonymity_set, &vec![DestinationId::new(0)]);
+        }
+        assert!(sras[&SourceId::new(1)].is_empty());
+    }
+
+    #[test]
+    fn relay_adversary_middle() {
+        use crate::trace::{RelayObservation, TraceEntry};
+        use time::macros::datetime;
+
+        // Two sources with four-hop paths that share the exit: source 0 via
+        // relays 0-1-2-3 to destination 0, and source 1 via relays 4-5-6-3 to
+        // destination 1, 5ms later.
+        let mut builder = TraceBuilder::new();
+        let mut observations = RelayObservations::new();
+        for k in 0..10 {
+            for source in 0..2u64 {
+                let sent =
+                    datetime!(1970-01-01 0:00) + Duration::milliseconds(10 * k + 5 * source as i64);
+                let m_id = MessageId::new(2 * k as u64 + source);
+                builder.add_entry(TraceEntry {
+                    m_id,
+                    source_id: SourceId::new(source),
+                    source_timestamp: sent,
+                    destination_id: DestinationId::new(source),
+                    destination_timestamp: sent + Duration::milliseconds(25),
+                    session: 0,
+                    dummy: false,
+                    dropped: false,
+                    direction: Direction::Upstream,
+                    retransmission: false,
+                });
+                for hop in 0..4 {
+                    let relay_id = match hop {
+                        3 => 3,
+                        _ => 4 * source + hop as u64,
+                    };
+                    observations.add_observation(RelayObservation {
+                        m_id,
+                        relay_id: RelayId::new(relay_id),
+                        hop,
+                        timestamp: sent + Duration::milliseconds(5 * (hop as i64 + 1)),
+                    });
+                }
+            }
+        }
+        let trace = builder.build().unwrap();
+
+        let anonymity_sets = |relays: &[u64]| {
+            let adversary: HashSet<RelayId> = relays.iter().map(|r| RelayId::new(*r)).collect();
+            let (sras, _) = compute_relay_relationship_anonymity(
+                &trace,
+                &observations,
+                &adversary,
+                DummyVisibility::Indistinguishable,
+                Duration::milliseconds(4),
+                Duration::milliseconds(7),
+            )
+            .unwrap();
+            sras[&SourceId::new(0)].clone()
+        };
+
+        // With the entry and the exit, the window over three hops contains
+        // the message of source 1 that leaves the exit 5ms later.
+        let entry_exit = anonymity_sets(&[0, 3]);
+        assert_eq!(entry_exit.len(), 10);
+        assert!(entry_exit
+            .iter()
+            .any(|(_, set)| set == &vec![DestinationId::new(0), DestinationId::new(1)]));
+
+        // The second relay is linked to the entry, which leaves a narrower
+        // window over two hops.
+        let with_middle = anonymity_sets(&[0, 1, 3]);
+        assert_eq!(with_middle.len(), 10);
+        for (_, set) in &with_middle {
+            assert_eq!(set, &vec![DestinationId::new(0)]);
+        }
+    }
+
+    #[test]
+    fn dummy_visibility() {
+        use crate::trace::TraceEntry;
+        use time::macros::datetime;
+
+        // Source 0 alternates between real messages to destination 0 and
+        // dummies, which are either drop cover messages to destination 1 or
+        // padding that never arrives.
+        let mut builder = TraceBuilder::new();
+        for k in 0..20u64 {
+            let sent = datetime!(1970-01-01 0:00) + Duration::milliseconds(10 * k as i64);
+            builder.add_entry(TraceEntry {
+                m_id: MessageId::new(k),
+                source_id: SourceId::new(0),
+                source_timestamp: sent,
+                destination_id: DestinationId::new(k % 2),
+                destination_timestamp: sent + Duration::milliseconds(5),
+                session: 0,
+                dummy: k % 2 == 1,
+                dropped: k % 4 == 3,
+                direction: Direction::Upstream,
+                retransmission: false,
+            });
+        }
+        let trace = builder.build().unwrap();
+
+        let compute = |dummies| {
+            compute_relationship_anonymity_with_dummies(
+                &trace,
+                dummies,
+                Duration::milliseconds(1),
+                Duration::milliseconds(6),
+            )
+            .unwrap()
+            .0
+            .remove(&SourceId::new(0))
+            .unwrap()
+        };
+
+        // Recognizing the dummies, the adversary finds the destination right away
+        let sras = compute(DummyVisibility::Distinguishable);
+        assert_eq!(sras.len(), 10);
+        for (_, anonymity_set) in sras {
+            assert_eq!(anonymity_set, vec![DestinationId::new(0)]);
+        }
+
+        // Otherwise, the adversary observes the dummies as well, but ignores
+        // them as they cannot have reached the remaining destination
+        let sras = compute(DummyVisibility::Indistinguishable);
+        assert_eq!(sras.len(), 20);
+        for (_, anonymity_set) in sras {
+            assert_eq!(anonymity_set, vec![DestinationId::new(0)]);
+        }
+    }
+
+    #[test]
+    fn impossible_first_message() {
+        use crate::trace::TraceEntry;
+        use time::macros::datetime;
+
+        // Source 0 starts with padding that never arrives, followed by real
+        // messages to destination 0. Source 1 makes the trace contain a
+        // second destination.
+        let start = datetime!(1970-01-01 0:00);
+        let mut builder = TraceBuilder::new();
+        for (source, sent, dummy) in [(0, 0, true), (0, 10, false), (0, 20, false), (1, 9, false)] {
+            builder.add_entry(TraceEntry {
+                m_id: MessageId::new(0),
+                source_id: SourceId::new(source),
+                source_timestamp: start + Duration::milliseconds(sent),
+                destination_id: DestinationId::new(source),
+                destination_timestamp: start + Duration::milliseconds(sent + 5),
+                session: 0,
+                dummy,
+                dropped: dummy,
+                direction: Direction::Upstream,
+                retransmission: false,
+            });
+        }
+        builder.fix();
+        let trace = builder.build().unwrap();
+
+        // the padding has no anonymity set, rather than an empty one
+        let (sras, _) = compute_relationship_anonymity_sizes(
+            &trace,
+            Duration::milliseconds(1),
+            Duration::milliseconds(6),
+        )
+        .unwrap();
+        let sizes: Vec<usize> = sras[&SourceId::new(0)].iter().map(|x| x.1).collect();
+        assert_eq!(sizes, [2, 1]);
+    }
+
+    #[test]
+    fn bidirectional() {
+        use crate::trace::TraceEntry;
+        use time::macros::datetime;
+
+        // Sources 0 and 1 send at the same times to destinations 0 and 1, so
+        // they cannot be told apart upstream. The destinations respond at
+        // different times, though.
+        let start = datetime!(1970-01-01 0:00);
+        let mut builder = TraceBuilder::new();
+        let mut add = |source: u64, sent: i64, delay: i64, direction| {
+            builder.add_entry(TraceEntry {
+                m_id: MessageId::new(0),
+                source_id: SourceId::new(source),
+                source_timestamp: start + Duration::milliseconds(sent),
+                destination_id: DestinationId::new(source),
+                destination_timestamp: start + Duration::milliseconds(sent + delay),
+                session: 0,
+                dummy: false,
+                dropped: false,
+                direction,
+                retransmission: false,
+            });
+        };
+        for k in 0..5 {
+            add(0, 10 * k, 5, Direction::Upstream);
+            add(1, 10 * k, 5, Direction::Upstream);
+        }
+        add(0, 100, 5, Direction::Downstream);
+        add(1, 200, 5, Direction::Downstream);
+        builder.fix();
+        let trace = builder.build().unwrap();
+
+        let compute = |bidirectional| {
+            let compute = match bidirectional {
+                true => compute_bidirectional_relationship_anonymity,
+                false => compute_relationship_anonymity_with_dummies,
+            };
+            compute(
+                &trace,
+                DummyVisibility::Indistinguishable,
+                Duration::milliseconds(1),
+                Duration::milliseconds(6),
+            )
+            .unwrap()
+            .0
+        };
+
+        // upstream, both destinations remain candidates
+        let sras = compute(false);
+        for source in 0..2 {
+            let sras = &sras[&SourceId::new(source)];
+            assert_eq!(sras.len(), 5);
+            assert_eq!(sras.last().unwrap().1.len(), 2);
+        }
+
+        // the responses reveal the destinations
+        let sras = compute(true);
+        for source in 0..2 {
+            let sras = &sras[&SourceId::new(source)];
+            assert_eq!(sras.len(), 6);
+            assert_eq!(sras.last().unwrap().1, vec![DestinationId::new(source)]);
+        }
+    }
 }
@@ -16,6 +16,38 @@ pub struct TraceEntry {
     pub source_timestamp: PrimitiveDateTime,
     pub destination_id: DestinationId,
     pub destination_timestamp: PrimitiveDateTime,
+    /// The session of the source that the message belongs to, counting from 0
+    #[serde(default)]
+    pub session: u64,
+    /// Whether the message is a dummy, i.e. cover traffic instead of real data
+    #[serde(default)]
+    pub dummy: bool,
+    /// Whether the message was dropped on its way, like padding that is
+    /// discarded by the network. Dropped messages never arrive at their
+    /// destination, their destination timestamp is the time they were dropped.
+    #[serde(default)]
+    pub dropped: bool,
+    /// The direction of the message. Downstream messages are responses that
+    /// the destination sends to the source: they are sent at the source
+    /// timestamp and arrive at the source at the destination timestamp.
+    #[serde(default)]
+    pub direction: Direction,
+    /// Whether the message is a retransmission of a message that was lost,
+    /// i.e. dropped by the network. The lost message only appears at the
+    /// source, the retransmission at both ends.
+    #[serde(default)]
+    pub retransmission: bool,
+}
+
+/// The direction of a message between a source and its destination
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the source to the destination
+    #[default]
+    Upstream,
+    /// From the destination back to the source
+    Downstream,
 }
 
 /// A builder for a network trace.
@@ -152,6 +184,72 @@ impl TraceBuilder {
     }
 }
 
+/// An observation of a message at a relay on its path through the ACN.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RelayObservation {
+    pub m_id: MessageId,
+    pub relay_id: RelayId,
+    /// Position of the relay on the message's path, starting at 0 for the
+    /// relay next to the source
+    pub hop: u32,
+    pub timestamp: PrimitiveDateTime,
+}
+
+/// The observations of messages at the relays of an ACN, complementing a [Trace]
+/// that only contains what happens at the sources and destinations.
+pub struct RelayObservations {
+    observations: Vec<RelayObservation>,
+}
+
+impl RelayObservations {
+    /// Construct an empty set of relay observations
+    pub fn new() -> RelayObservations {
+        RelayObservations {
+            observations: Vec::new(),
+        }
+    }
+
+    /// Add a new observation
+    pub fn add_observation(&mut self, observation: RelayObservation) {
+        self.observations.push(observation);
+    }
+
+    /// Load relay observations from a CSV file, given its file path
+    pub fn from_csv(
+        path: impl AsRef<Path>,
+    ) -> Result<RelayObservations, Box<dyn std::error::Error + Send + Sync>> {
+        let mut rdr = csv::ReaderBuilder::new().from_path(path.as_ref())?;
+        let mut observations = RelayObservations::new();
+        for result in rdr.deserialize() {
+            observations.add_observation(result?);
+        }
+        Ok(observations)
+    }
+
+    /// Serialize to a CSV file
+    pub fn write_to_file(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut wtr = csv::WriterBuilder::new().from_path(path.as_ref())?;
+        for observation in self.observations.iter() {
+            wtr.serialize(observation)?;
+        }
+        Ok(())
+    }
+
+    /// Get an iterator over the observations
+    pub fn observations(&self) -> impl Iterator<Item = &RelayObservation> {
+        self.observations.iter()
+    }
+}
+
+impl Default for RelayObservations {
+    fn default() -> Self {
+        RelayObservations::new()
+    }
+}
+
 /// A network trace containing the ground truth of an ACN run.
 ///
 /// [Trace]s are meant as the "ground truth" in the way that they contain the
@@ -218,6 +316,56 @@ impl Trace {
     }
 }
 
+/// A writer that saves a trace to a CSV file entry by entry, without keeping
+/// it in memory.
+///
+/// The entries must be written in the order of their arrival. They are
+/// numbered in this order, like [TraceBuilder::fix] does.
+pub struct TraceWriter {
+    writer: csv::Writer<std::fs::File>,
+    num_entries: u64,
+    previous_time: Option<PrimitiveDateTime>,
+}
+
+impl TraceWriter {
+    /// Create the CSV file to write to
+    pub fn create(
+        path: impl AsRef<Path>,
+    ) -> Result<TraceWriter, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(TraceWriter {
+            writer: csv::WriterBuilder::new().from_path(path.as_ref())?,
+            num_entries: 0,
+            previous_time: None,
+        })
+    }
+
+    /// Write the next entry, assigning its message ID
+    pub fn write_entry(
+        &mut self,
+        mut entry: TraceEntry,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        entry.m_id = MessageId::new(self.num_entries);
+        if let Some(previous_time) = self.previous_time {
+            if previous_time > entry.destination_timestamp {
+                return Err(TraceBuildError::NotSortedByArrival(entry.m_id).into());
+            }
+        }
+        self.previous_time = Some(entry.destination_timestamp);
+        self.writer.serialize(&entry)?;
+        self.num_entries += 1;
+        Ok(())
+    }
+
+    /// Finish writing the trace, returning the number of its entries
+    pub fn finish(mut self) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        if self.num_entries == 0 {
+            return Err(TraceBuildError::EmptyTrace.into());
+        }
+        self.writer.flush()?;
+        Ok(self.num_entries)
+    }
+}
+
 /// An error that can occur when building a trace
 #[derive(Debug, thiserror::Error)]
 pub enum TraceBuildError {
@@ -302,3 +450,9 @@ implement_conversions!(SourceId, u64);
 pub struct DestinationId(u64);
 implement_display!(DestinationId);
 implement_conversions!(DestinationId, u64);
+
+/// The ID of a relay within the ACN.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct RelayId(u64);
+implement_display!(RelayId);
+implement_conversions!(RelayId, u64);